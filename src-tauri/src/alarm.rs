@@ -0,0 +1,366 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration as StdDuration;
+
+use chrono::{DateTime, Duration, Local, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Manager, Runtime};
+use uuid::Uuid;
+
+use crate::command::send_capability;
+use crate::events::{self, AutomationKind};
+use crate::profiles;
+use crate::scheduler::{runs_on, Trigger, EVERY_DAY};
+use crate::settings;
+use crate::solar::{self, Location};
+use crate::tray;
+
+const TICK_INTERVAL: StdDuration = StdDuration::from_secs(15);
+
+const START_KELVIN: u32 = 2000;
+const END_KELVIN: u32 = 3000;
+
+// Each step sends two requests (colour temperature and brightness). Capping the number of
+// steps keeps a ramp well inside the per-device limit of 10 requests a minute and costs at
+// most a few dozen requests from the daily quota.
+const MAX_STEPS: i32 = 30;
+const MIN_STEP_SECS: i64 = 30;
+const QUOTA_RESERVE: u32 = 100;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Alarm {
+    #[serde(default)]
+    pub id: String,
+    pub name: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    pub device: String,
+    pub sku: String,
+    /// When the light reaches full brightness.
+    pub at: Trigger,
+    #[serde(default = "default_weekdays")]
+    pub weekdays: u8,
+    #[serde(default = "default_window_minutes")]
+    pub window_minutes: u32,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_weekdays() -> u8 {
+    EVERY_DAY
+}
+
+fn default_window_minutes() -> u32 {
+    30
+}
+
+impl Alarm {
+    fn window(&self) -> Duration {
+        Duration::minutes(self.window_minutes.into())
+    }
+
+    /// The end of the ramp that is in progress at `now`, or of the next one to start.
+    fn upcoming_end<Tz: TimeZone>(
+        &self,
+        tz: &Tz,
+        location: Option<Location>,
+        now: DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        let mut date = now.with_timezone(tz).date_naive();
+
+        for _ in 0..8 {
+            if runs_on(self.weekdays, date) {
+                if let Some(end) = self.at.resolve_on(tz, location, date) {
+                    if end > now {
+                        return Some(end);
+                    }
+                }
+            }
+            date = date.succ_opt()?;
+        }
+
+        None
+    }
+}
+
+/// Colour temperature and brightness `progress` of the way through a ramp.
+fn ramp_values(progress: f64) -> (u32, u32) {
+    let progress = progress.clamp(0.0, 1.0);
+    let kelvin = START_KELVIN as f64 + (END_KELVIN - START_KELVIN) as f64 * progress;
+    let brightness = (100.0 * progress).round().max(1.0);
+
+    (kelvin.round() as u32, brightness as u32)
+}
+
+struct Ramp {
+    end: DateTime<Utc>,
+    last_step: Option<DateTime<Utc>>,
+}
+
+#[derive(Default)]
+pub struct AlarmState {
+    ramps: Mutex<HashMap<String, Ramp>>,
+    cancelled: Mutex<HashMap<String, DateTime<Utc>>>,
+    ramping: AtomicBool,
+}
+
+impl AlarmState {
+    fn is_cancelled(&self, id: &str, end: DateTime<Utc>) -> bool {
+        self.cancelled.lock().unwrap().get(id) == Some(&end)
+    }
+}
+
+pub fn is_ramping<R: Runtime>(app: &AppHandle<R>) -> bool {
+    app.try_state::<AlarmState>()
+        .is_some_and(|state| state.ramping.load(Ordering::Relaxed))
+}
+
+/// Stops every ramp in progress, leaving the lights where they are. The alarms stay
+/// enabled and run again at their next occurrence.
+pub fn cancel_active<R: Runtime>(app: &AppHandle<R>) {
+    let state = app.state::<AlarmState>();
+
+    let ramps = std::mem::take(&mut *state.ramps.lock().unwrap());
+    let mut cancelled = state.cancelled.lock().unwrap();
+    for (id, ramp) in ramps {
+        cancelled.insert(id, ramp.end);
+    }
+    drop(cancelled);
+
+    set_ramping(app, false);
+}
+
+fn set_ramping<R: Runtime>(app: &AppHandle<R>, ramping: bool) {
+    let state = app.state::<AlarmState>();
+
+    if state.ramping.swap(ramping, Ordering::Relaxed) != ramping {
+        if let Err(e) = tray::refresh_menu(app) {
+            eprintln!("Failed to update tray menu: {}", e);
+        }
+    }
+}
+
+pub fn load_alarms<R: Runtime>(app: &AppHandle<R>) -> Vec<Alarm> {
+    settings::get(app).alarms
+}
+
+fn save_alarms<R: Runtime>(app: &AppHandle<R>, alarms: Vec<Alarm>) -> Result<(), String> {
+    settings::update(app, |s| {
+        s.alarms = alarms;
+        Ok(())
+    })
+}
+
+async fn step<R: Runtime>(
+    app: &AppHandle<R>,
+    alarm: &Alarm,
+    progress: f64,
+    first: bool,
+) -> Result<(), String> {
+    let (kelvin, brightness) = ramp_values(progress);
+
+    send_capability(
+        app,
+        alarm.device.clone(),
+        alarm.sku.clone(),
+        "devices.capabilities.color_setting".to_string(),
+        "colorTemperatureK".to_string(),
+        serde_json::json!(kelvin),
+    )
+    .await?;
+
+    send_capability(
+        app,
+        alarm.device.clone(),
+        alarm.sku.clone(),
+        "devices.capabilities.brightness".to_string(),
+        "brightness".to_string(),
+        serde_json::json!(brightness),
+    )
+    .await?;
+
+    // Power on only after dimming so the light doesn't flash at its previous brightness.
+    if first {
+        send_capability(
+            app,
+            alarm.device.clone(),
+            alarm.sku.clone(),
+            "devices.capabilities.on_off".to_string(),
+            "powerSwitch".to_string(),
+            serde_json::json!(1),
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+async fn tick<R: Runtime>(app: &AppHandle<R>) -> Result<(), String> {
+    let now = Utc::now();
+    let location = solar::load_location(app);
+    let alarms = load_alarms(app);
+    let state = app.state::<AlarmState>();
+
+    let mut ramping = false;
+
+    for alarm in alarms.iter().filter(|a| a.enabled) {
+        // Finish a ramp whose end passed between ticks so the light reaches full brightness.
+        let finished = {
+            let mut ramps = state.ramps.lock().unwrap();
+            match ramps.get(&alarm.id) {
+                Some(ramp) if ramp.end <= now => ramps.remove(&alarm.id),
+                _ => None,
+            }
+        };
+        if finished.is_some() {
+            if let Err(e) = step(app, alarm, 1.0, false).await {
+                eprintln!("Wake-up light \"{}\" failed: {}", alarm.name, e);
+            }
+        }
+
+        let Some(end) = alarm.upcoming_end(&Local, location, now) else {
+            continue;
+        };
+        let start = end - alarm.window();
+
+        if now < start || state.is_cancelled(&alarm.id, end) {
+            continue;
+        }
+
+        ramping = true;
+
+        let step_interval = (alarm.window() / MAX_STEPS).max(Duration::seconds(MIN_STEP_SECS));
+        let first = {
+            let ramps = state.ramps.lock().unwrap();
+            match ramps.get(&alarm.id) {
+                Some(ramp) => {
+                    if ramp
+                        .last_step
+                        .is_some_and(|last| now - last < step_interval)
+                    {
+                        continue;
+                    }
+                    false
+                }
+                None => true,
+            }
+        };
+
+        if profiles::remaining_for(app, &alarm.device) < QUOTA_RESERVE {
+            continue;
+        }
+
+        let progress = (now - start).num_seconds() as f64 / alarm.window().num_seconds() as f64;
+
+        state.ramps.lock().unwrap().insert(
+            alarm.id.clone(),
+            Ramp {
+                end,
+                last_step: Some(now),
+            },
+        );
+
+        let result = step(app, alarm, progress, first).await;
+        if let Err(e) = &result {
+            eprintln!("Wake-up light \"{}\" failed: {}", alarm.name, e);
+        }
+        if first {
+            events::publish_automation(
+                app,
+                AutomationKind::Alarm,
+                &alarm.name,
+                Some(&alarm.device),
+                result.err(),
+            );
+        }
+    }
+
+    // A ramp cancelled from the tray must not be picked up again by a tick that was
+    // already running.
+    state
+        .ramps
+        .lock()
+        .unwrap()
+        .retain(|id, ramp| !state.is_cancelled(id, ramp.end));
+
+    set_ramping(app, ramping);
+
+    Ok(())
+}
+
+pub fn start<R: Runtime>(app_handle: &AppHandle<R>) {
+    app_handle.manage(AlarmState::default());
+
+    let app = app_handle.clone();
+
+    tauri::async_runtime::spawn(async move {
+        loop {
+            if let Err(e) = tick(&app).await {
+                eprintln!("Alarm tick failed: {}", e);
+            }
+
+            tokio::time::sleep(TICK_INTERVAL).await;
+        }
+    });
+}
+
+#[tauri::command]
+pub fn get_alarms<R: Runtime>(app: AppHandle<R>) -> Vec<Alarm> {
+    load_alarms(&app)
+}
+
+#[tauri::command]
+pub fn save_alarm<R: Runtime>(app: AppHandle<R>, mut alarm: Alarm) -> Result<Alarm, String> {
+    alarm.at.validate()?;
+
+    if !(1..=120).contains(&alarm.window_minutes) {
+        return Err("Sunrise window must be between 1 and 120 minutes".to_string());
+    }
+
+    if alarm.weekdays & EVERY_DAY == 0 {
+        return Err("Alarm must run on at least one weekday".to_string());
+    }
+
+    if matches!(alarm.at, Trigger::Solar { .. }) && solar::load_location(&app).is_none() {
+        return Err("Location not set. Please set your location first.".to_string());
+    }
+
+    let mut alarms = load_alarms(&app);
+
+    if alarm.id.is_empty() {
+        alarm.id = Uuid::new_v4().to_string();
+    }
+
+    match alarms.iter_mut().find(|a| a.id == alarm.id) {
+        Some(existing) => *existing = alarm.clone(),
+        None => alarms.push(alarm.clone()),
+    }
+
+    save_alarms(&app, alarms)?;
+
+    Ok(alarm)
+}
+
+#[tauri::command]
+pub fn delete_alarm<R: Runtime>(app: AppHandle<R>, id: String) -> Result<(), String> {
+    let mut alarms = load_alarms(&app);
+    let count = alarms.len();
+
+    alarms.retain(|a| a.id != id);
+
+    if alarms.len() == count {
+        return Err("Alarm not found".to_string());
+    }
+
+    app.state::<AlarmState>().ramps.lock().unwrap().remove(&id);
+
+    save_alarms(&app, alarms)
+}
+
+#[tauri::command]
+pub fn cancel_alarm<R: Runtime>(app: AppHandle<R>) {
+    cancel_active(&app);
+}
@@ -0,0 +1,437 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Duration, Local, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Manager, Runtime};
+use tauri_plugin_notification::NotificationExt;
+use tauri_plugin_store::StoreExt;
+use uuid::Uuid;
+
+use crate::cache;
+use crate::command::DeviceState;
+use crate::events::{self, AutomationKind};
+use crate::history::RECORDED_INSTANCES;
+use crate::poller;
+use crate::sensors::{self, TemperatureUnit};
+use crate::settings::{self, SETTINGS_FILE};
+
+const ALERT_LOG_KEY: &str = "alert_log";
+const MAX_LOG_ENTRIES: usize = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Comparison {
+    Above,
+    Below,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AlertRule {
+    #[serde(default)]
+    pub id: String,
+    pub name: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    pub device: String,
+    pub sku: String,
+    /// `sensorTemperature` (in °C) or `sensorHumidity` (in %).
+    pub instance: String,
+    pub comparison: Comparison,
+    pub threshold: f64,
+    /// How far back past the threshold the value must go before the alert clears, so a
+    /// reading hovering around the threshold doesn't alert over and over.
+    #[serde(default = "default_hysteresis")]
+    pub hysteresis: f64,
+    /// How long the threshold must be crossed before alerting.
+    #[serde(default)]
+    pub for_minutes: u32,
+    /// Minimum time between two notifications for this rule.
+    #[serde(default = "default_cooldown_minutes")]
+    pub cooldown_minutes: u32,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_hysteresis() -> f64 {
+    1.0
+}
+
+fn default_cooldown_minutes() -> u32 {
+    60
+}
+
+impl AlertRule {
+    fn crossed(&self, value: f64) -> bool {
+        match self.comparison {
+            Comparison::Above => value > self.threshold,
+            Comparison::Below => value < self.threshold,
+        }
+    }
+
+    fn cleared(&self, value: f64) -> bool {
+        match self.comparison {
+            Comparison::Above => value <= self.threshold - self.hysteresis,
+            Comparison::Below => value >= self.threshold + self.hysteresis,
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !RECORDED_INSTANCES.contains(&self.instance.as_str()) {
+            return Err("Alerts are only available for temperature and humidity".to_string());
+        }
+
+        if !self.threshold.is_finite() || !(0.0..=20.0).contains(&self.hysteresis) {
+            return Err("Invalid threshold".to_string());
+        }
+
+        if self.for_minutes > 24 * 60 || self.cooldown_minutes > 7 * 24 * 60 {
+            return Err("Durations must be at most a day, cooldowns at most a week".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TimeOfDay {
+    pub hour: u32,
+    pub minute: u32,
+}
+
+impl TimeOfDay {
+    fn to_time(self) -> Option<NaiveTime> {
+        NaiveTime::from_hms_opt(self.hour, self.minute, 0)
+    }
+}
+
+/// Time of night when alerts are logged but not shown.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuietHours {
+    pub enabled: bool,
+    pub from: TimeOfDay,
+    pub until: TimeOfDay,
+}
+
+impl Default for QuietHours {
+    fn default() -> Self {
+        QuietHours {
+            enabled: false,
+            from: TimeOfDay {
+                hour: 22,
+                minute: 0,
+            },
+            until: TimeOfDay { hour: 7, minute: 0 },
+        }
+    }
+}
+
+impl QuietHours {
+    fn contains(&self, time: NaiveTime) -> bool {
+        let (Some(from), Some(until)) = (self.from.to_time(), self.until.to_time()) else {
+            return false;
+        };
+
+        if !self.enabled {
+            false
+        } else if from <= until {
+            from <= time && time < until
+        } else {
+            // Spans midnight.
+            time >= from || time < until
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AlertEvent {
+    pub rule_id: String,
+    pub rule_name: String,
+    pub device: String,
+    pub instance: String,
+    pub value: f64,
+    pub at: DateTime<Utc>,
+    /// Logged during quiet hours without a notification.
+    pub suppressed: bool,
+}
+
+#[derive(Debug, Default)]
+struct RuleState {
+    crossed_since: Option<DateTime<Utc>>,
+    firing: bool,
+    last_notified: Option<DateTime<Utc>>,
+}
+
+impl RuleState {
+    /// Feeds one reading through the rule. Returns true when it should alert.
+    fn update(&mut self, rule: &AlertRule, value: f64, now: DateTime<Utc>) -> bool {
+        if rule.crossed(value) {
+            let since = *self.crossed_since.get_or_insert(now);
+
+            if self.firing || now - since < Duration::minutes(rule.for_minutes.into()) {
+                return false;
+            }
+
+            self.firing = true;
+
+            let cooldown = Duration::minutes(rule.cooldown_minutes.into());
+            if self.last_notified.is_some_and(|last| now - last < cooldown) {
+                return false;
+            }
+
+            self.last_notified = Some(now);
+            true
+        } else {
+            // Between the threshold and the hysteresis band a firing alert stays up, while
+            // one that hasn't fired yet starts its wait again.
+            if !self.firing || rule.cleared(value) {
+                self.crossed_since = None;
+                self.firing = false;
+            }
+            false
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct AlertState {
+    rules: Mutex<HashMap<String, RuleState>>,
+}
+
+pub fn load_rules<R: Runtime>(app: &AppHandle<R>) -> Vec<AlertRule> {
+    settings::get(app).alert_rules
+}
+
+fn save_rules<R: Runtime>(app: &AppHandle<R>, rules: Vec<AlertRule>) -> Result<(), String> {
+    settings::update(app, |s| {
+        s.alert_rules = rules;
+        Ok(())
+    })
+}
+
+fn load_quiet_hours<R: Runtime>(app: &AppHandle<R>) -> QuietHours {
+    settings::get(app).alert_quiet_hours
+}
+
+fn load_log<R: Runtime>(app: &AppHandle<R>) -> Vec<AlertEvent> {
+    app.store(SETTINGS_FILE)
+        .ok()
+        .and_then(|store| store.get(ALERT_LOG_KEY))
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default()
+}
+
+fn save_log<R: Runtime>(app: &AppHandle<R>, log: &[AlertEvent]) -> Result<(), String> {
+    let store = app.store(SETTINGS_FILE).map_err(|e| e.to_string())?;
+    let value = serde_json::to_value(log).map_err(|e| e.to_string())?;
+
+    store.set(ALERT_LOG_KEY, value);
+    store
+        .save()
+        .map_err(|_| "Failed to save settings".to_string())
+}
+
+fn describe(rule: &AlertRule, value: f64, unit: TemperatureUnit) -> String {
+    let format = |v: f64| match rule.instance.as_str() {
+        "sensorTemperature" => unit.format(v),
+        _ => format!("{:.0}%", v),
+    };
+    let what = match rule.instance.as_str() {
+        "sensorTemperature" => "Temperature",
+        _ => "Humidity",
+    };
+    let direction = match rule.comparison {
+        Comparison::Above => "above",
+        Comparison::Below => "below",
+    };
+
+    format!(
+        "{} is {} ({} {})",
+        what,
+        format(value),
+        direction,
+        format(rule.threshold)
+    )
+}
+
+fn notify<R: Runtime>(app: &AppHandle<R>, rule: &AlertRule, value: f64, unit: TemperatureUnit) {
+    let device = cache::devices(app)
+        .into_iter()
+        .find(|d| d.device == rule.device)
+        .map(|d| d.display_name().to_string())
+        .unwrap_or_else(|| rule.device.clone());
+
+    if let Err(e) = app
+        .notification()
+        .builder()
+        .title(format!("{}: {}", rule.name, device))
+        .body(describe(rule, value, unit))
+        .show()
+    {
+        eprintln!("Failed to show alert \"{}\": {}", rule.name, e);
+    }
+}
+
+/// Checks a freshly fetched state against every rule for that device.
+pub fn evaluate<R: Runtime>(app: &AppHandle<R>, state: &DeviceState) {
+    let Some(alerts) = app.try_state::<AlertState>() else {
+        return;
+    };
+    let rules = load_rules(app);
+
+    let now = Utc::now();
+    let mut fired = Vec::new();
+
+    {
+        let mut states = alerts.rules.lock().unwrap();
+
+        for rule in rules
+            .iter()
+            .filter(|r| r.enabled && r.device == state.device)
+        {
+            let Some(value) = state.reading(&rule.instance) else {
+                continue;
+            };
+
+            if states
+                .entry(rule.id.clone())
+                .or_default()
+                .update(rule, value, now)
+            {
+                fired.push((rule, value));
+            }
+        }
+    }
+
+    if fired.is_empty() {
+        return;
+    }
+
+    let quiet = load_quiet_hours(app).contains(Local::now().time());
+    let mut log = load_log(app);
+
+    let unit = sensors::load_tray_sensors(app).unit;
+
+    for (rule, value) in fired {
+        if !quiet {
+            notify(app, rule, value, unit);
+        }
+
+        events::publish_automation(
+            app,
+            AutomationKind::Alert,
+            &rule.name,
+            Some(&rule.device),
+            Some(describe(rule, value, unit)),
+        );
+
+        log.push(AlertEvent {
+            rule_id: rule.id.clone(),
+            rule_name: rule.name.clone(),
+            device: rule.device.clone(),
+            instance: rule.instance.clone(),
+            value,
+            at: now,
+            suppressed: quiet,
+        });
+    }
+
+    let excess = log.len().saturating_sub(MAX_LOG_ENTRIES);
+    log.drain(..excess);
+
+    if let Err(e) = save_log(app, &log) {
+        eprintln!("Failed to save alert log: {}", e);
+    }
+}
+
+pub fn start<R: Runtime>(app_handle: &AppHandle<R>) {
+    app_handle.manage(AlertState::default());
+}
+
+#[tauri::command]
+pub fn get_alert_rules<R: Runtime>(app: AppHandle<R>) -> Vec<AlertRule> {
+    load_rules(&app)
+}
+
+#[tauri::command]
+pub fn save_alert_rule<R: Runtime>(
+    app: AppHandle<R>,
+    mut rule: AlertRule,
+) -> Result<AlertRule, String> {
+    rule.validate()?;
+
+    let mut rules = load_rules(&app);
+
+    if rule.id.is_empty() {
+        rule.id = Uuid::new_v4().to_string();
+    }
+
+    match rules.iter_mut().find(|r| r.id == rule.id) {
+        Some(existing) => *existing = rule.clone(),
+        None => rules.push(rule.clone()),
+    }
+
+    save_rules(&app, rules)?;
+
+    // An edited rule starts over instead of carrying a pending or firing state.
+    app.state::<AlertState>()
+        .rules
+        .lock()
+        .unwrap()
+        .remove(&rule.id);
+
+    poller::wake(&app);
+
+    Ok(rule)
+}
+
+#[tauri::command]
+pub fn delete_alert_rule<R: Runtime>(app: AppHandle<R>, id: String) -> Result<(), String> {
+    let mut rules = load_rules(&app);
+    let count = rules.len();
+
+    rules.retain(|r| r.id != id);
+
+    if rules.len() == count {
+        return Err("Alert rule not found".to_string());
+    }
+
+    app.state::<AlertState>().rules.lock().unwrap().remove(&id);
+
+    save_rules(&app, rules)
+}
+
+#[tauri::command]
+pub fn get_quiet_hours<R: Runtime>(app: AppHandle<R>) -> QuietHours {
+    load_quiet_hours(&app)
+}
+
+#[tauri::command]
+pub fn set_quiet_hours<R: Runtime>(
+    app: AppHandle<R>,
+    quiet_hours: QuietHours,
+) -> Result<(), String> {
+    if quiet_hours.from.to_time().is_none() || quiet_hours.until.to_time().is_none() {
+        return Err("Invalid quiet hours".to_string());
+    }
+
+    settings::update(&app, |s| {
+        s.alert_quiet_hours = quiet_hours;
+        Ok(())
+    })
+}
+
+/// Most recent alerts first.
+#[tauri::command]
+pub fn get_alert_log<R: Runtime>(app: AppHandle<R>) -> Vec<AlertEvent> {
+    let mut log = load_log(&app);
+    log.reverse();
+    log
+}
+
+#[tauri::command]
+pub fn clear_alert_log<R: Runtime>(app: AppHandle<R>) -> Result<(), String> {
+    save_log(&app, &[])
+}
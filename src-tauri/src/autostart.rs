@@ -0,0 +1,73 @@
+use serde::{Deserialize, Serialize};
+use tauri::AppHandle;
+use tauri_plugin_autostart::ManagerExt;
+
+use crate::settings;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Autostart {
+    /// Start the app at login.
+    pub enabled: bool,
+    /// Stay in the menubar at startup instead of opening the panel.
+    #[serde(default = "default_start_hidden")]
+    pub start_hidden: bool,
+}
+
+fn default_start_hidden() -> bool {
+    true
+}
+
+impl Default for Autostart {
+    fn default() -> Self {
+        Autostart {
+            enabled: true,
+            start_hidden: true,
+        }
+    }
+}
+
+fn apply(app: &AppHandle, enabled: bool) -> Result<(), String> {
+    let manager = app.autolaunch();
+
+    if enabled {
+        manager.enable()
+    } else {
+        manager.disable()
+    }
+    .map_err(|e| format!("Failed to update login item: {}", e))
+}
+
+/// Enables start at login the first time the app runs. Afterwards the login item is only
+/// changed through `set_autostart`, so turning it off in the system settings sticks.
+pub fn apply_on_first_run(app: &AppHandle) -> Result<(), String> {
+    if settings::get(app).autostart.is_some() {
+        return Ok(());
+    }
+
+    let autostart = Autostart::default();
+    apply(app, autostart.enabled)?;
+
+    settings::update(app, |s| {
+        s.autostart = Some(autostart);
+        Ok(())
+    })
+}
+
+pub fn load_autostart(app: &AppHandle) -> Autostart {
+    settings::get(app).autostart.unwrap_or_default()
+}
+
+#[tauri::command]
+pub fn get_autostart(app: AppHandle) -> Autostart {
+    load_autostart(&app)
+}
+
+#[tauri::command]
+pub fn set_autostart(app: AppHandle, autostart: Autostart) -> Result<(), String> {
+    apply(&app, autostart.enabled)?;
+
+    settings::update(&app, |s| {
+        s.autostart = Some(autostart);
+        Ok(())
+    })
+}
@@ -0,0 +1,3 @@
+fn main() {
+    statusbar_govee_lib::cli::run()
+}
@@ -0,0 +1,180 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use tauri::{AppHandle, Manager, Runtime};
+
+use crate::command::{CapabilityState, DeviceState, GoveeDevice, SceneOption, StateValue};
+use crate::events::{self, Payload};
+use crate::tray;
+
+/// Last known devices, states and scenes, as seen by the commands. Backend features such
+/// as the tray menu read from here instead of hitting the API again.
+#[derive(Default)]
+pub struct DeviceCache {
+    devices: Mutex<Vec<GoveeDevice>>,
+    states: Mutex<HashMap<String, DeviceState>>,
+    scenes: Mutex<HashMap<String, Vec<SceneOption>>>,
+}
+
+fn refresh_tray<R: Runtime>(app: &AppHandle<R>, menu: bool) {
+    if menu {
+        if let Err(e) = tray::refresh_menu(app) {
+            eprintln!("Failed to update tray menu: {}", e);
+        }
+    }
+
+    if let Err(e) = tray::refresh_icon(app) {
+        eprintln!("Failed to update tray icon: {}", e);
+    }
+
+    if let Err(e) = tray::refresh_title(app) {
+        eprintln!("Failed to update tray title: {}", e);
+    }
+}
+
+fn power_of(state: &DeviceState) -> Option<bool> {
+    state
+        .value("powerSwitch")
+        .and_then(|v| v.as_i64())
+        .map(|v| v == 1)
+}
+
+pub fn devices<R: Runtime>(app: &AppHandle<R>) -> Vec<GoveeDevice> {
+    app.try_state::<DeviceCache>()
+        .map(|cache| cache.devices.lock().unwrap().clone())
+        .unwrap_or_default()
+}
+
+pub fn set_devices<R: Runtime>(app: &AppHandle<R>, devices: &[GoveeDevice]) {
+    let Some(cache) = app.try_state::<DeviceCache>() else {
+        return;
+    };
+
+    let changed = {
+        let mut cached = cache.devices.lock().unwrap();
+        let changed = cached.len() != devices.len()
+            || cached
+                .iter()
+                .zip(devices)
+                .any(|(a, b)| a.device != b.device || a.display_name() != b.display_name());
+
+        *cached = devices.to_vec();
+        changed
+    };
+
+    refresh_tray(app, changed);
+}
+
+pub fn state<R: Runtime>(app: &AppHandle<R>, device: &str) -> Option<DeviceState> {
+    let cache = app.try_state::<DeviceCache>()?;
+    let states = cache.states.lock().unwrap();
+    states.get(device).cloned()
+}
+
+pub fn power<R: Runtime>(app: &AppHandle<R>, device: &str) -> Option<bool> {
+    state(app, device).as_ref().and_then(power_of)
+}
+
+/// Number of lights known to be switched on.
+pub fn lights_on<R: Runtime>(app: &AppHandle<R>) -> u32 {
+    let Some(cache) = app.try_state::<DeviceCache>() else {
+        return 0;
+    };
+    let devices = cache.devices.lock().unwrap();
+    let states = cache.states.lock().unwrap();
+
+    devices
+        .iter()
+        .filter(|d| d.device_type == "light")
+        .filter(|d| states.get(&d.device).and_then(power_of) == Some(true))
+        .count() as u32
+}
+
+/// Current colour of a light as RGB, if it reports one.
+pub fn color<R: Runtime>(app: &AppHandle<R>, device: &str) -> Option<[u8; 3]> {
+    let rgb = state(app, device)?.value("colorRgb")?.as_u64()?;
+
+    Some([(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8])
+}
+
+pub fn set_state<R: Runtime>(app: &AppHandle<R>, state: &DeviceState) {
+    let Some(cache) = app.try_state::<DeviceCache>() else {
+        return;
+    };
+
+    let previous = cache
+        .states
+        .lock()
+        .unwrap()
+        .insert(state.device.clone(), state.clone());
+
+    events::publish_state_changes(app, previous.as_ref(), state);
+
+    refresh_tray(app, previous.as_ref().and_then(power_of) != power_of(state));
+}
+
+/// Applies a successful control request to the cached state so it stays current until the
+/// next state fetch.
+pub fn note_change<R: Runtime>(
+    app: &AppHandle<R>,
+    device: &str,
+    instance: &str,
+    value: &serde_json::Value,
+) {
+    let Some(cache) = app.try_state::<DeviceCache>() else {
+        return;
+    };
+
+    let previous = state(app, device);
+    if previous.as_ref().and_then(|s| s.value(instance)) != Some(value) {
+        let changes = BTreeMap::from([(instance.to_string(), value.clone())]);
+        events::publish(
+            app,
+            Some(device),
+            Payload::State {
+                changes,
+                initial: false,
+            },
+        );
+    }
+
+    {
+        let mut states = cache.states.lock().unwrap();
+        let Some(state) = states.get_mut(device) else {
+            return;
+        };
+
+        match state
+            .capabilities
+            .iter_mut()
+            .find(|c| c.instance == instance)
+        {
+            Some(capability) => capability.state.value = value.clone(),
+            None => state.capabilities.push(CapabilityState {
+                capability_type: String::new(),
+                instance: instance.to_string(),
+                state: StateValue {
+                    value: value.clone(),
+                },
+            }),
+        }
+    }
+
+    refresh_tray(app, instance == "powerSwitch");
+}
+
+pub fn scenes<R: Runtime>(app: &AppHandle<R>, device: &str) -> Vec<SceneOption> {
+    app.try_state::<DeviceCache>()
+        .and_then(|cache| cache.scenes.lock().unwrap().get(device).cloned())
+        .unwrap_or_default()
+}
+
+pub fn set_scenes<R: Runtime>(app: &AppHandle<R>, device: &str, scenes: &[SceneOption]) {
+    if let Some(cache) = app.try_state::<DeviceCache>() {
+        cache
+            .scenes
+            .lock()
+            .unwrap()
+            .insert(device.to_string(), scenes.to_vec());
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration as StdDuration;
+
+use chrono::{DateTime, Duration, Local, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Manager, Runtime};
+
+use crate::command::{get_device_state, send_capability};
+use crate::profiles;
+use crate::ratelimit;
+use crate::scheduler::{DeviceTarget, Trigger};
+use crate::settings;
+use crate::solar::{self, Location};
+
+const TICK_INTERVAL: StdDuration = StdDuration::from_secs(60);
+
+// Circadian updates may use at most 1/QUOTA_SHARE of the daily API quota, and stop
+// entirely once the remaining quota drops below QUOTA_RESERVE so manual control keeps
+// working.
+const QUOTA_SHARE: u32 = 4;
+const QUOTA_RESERVE: u32 = 500;
+// Each update reads the device state, then sets colour temperature and brightness.
+const REQUESTS_PER_UPDATE: u32 = 3;
+const MIN_UPDATE_INTERVAL_SECS: i64 = 120;
+
+// Changes smaller than this are not worth spending a request on.
+const MIN_KELVIN_STEP: u32 = 50;
+const MIN_BRIGHTNESS_STEP: u32 = 2;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CurvePoint {
+    pub at: Trigger,
+    pub color_temperature: u32,
+    pub brightness: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CircadianConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub devices: Vec<DeviceTarget>,
+    #[serde(default = "default_curve")]
+    pub curve: Vec<CurvePoint>,
+}
+
+impl Default for CircadianConfig {
+    fn default() -> Self {
+        CircadianConfig {
+            enabled: false,
+            devices: Vec::new(),
+            curve: default_curve(),
+        }
+    }
+}
+
+fn point(hour: u32, minute: u32, color_temperature: u32, brightness: u32) -> CurvePoint {
+    CurvePoint {
+        at: Trigger::At { hour, minute },
+        color_temperature,
+        brightness,
+    }
+}
+
+fn default_curve() -> Vec<CurvePoint> {
+    vec![
+        point(6, 30, 2700, 40),
+        point(9, 0, 4500, 85),
+        point(13, 0, 6000, 100),
+        point(18, 0, 3500, 70),
+        point(21, 0, 2700, 40),
+        point(23, 0, 2200, 15),
+    ]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct CurveSample {
+    pub color_temperature: u32,
+    pub brightness: u32,
+    /// When the curve segment containing the sample started. A device changed by hand
+    /// stays paused until a later phase begins.
+    pub phase_start: DateTime<Utc>,
+}
+
+/// Interpolates the curve at `at`.
+///
+/// Anchors are resolved for the surrounding days so the segment spanning midnight
+/// interpolates between the last point of one day and the first of the next.
+pub fn sample<Tz: TimeZone>(
+    curve: &[CurvePoint],
+    tz: &Tz,
+    location: Option<Location>,
+    at: DateTime<Utc>,
+) -> Option<CurveSample> {
+    let today = at.with_timezone(tz).date_naive();
+    let days = [today.pred_opt()?, today, today.succ_opt()?];
+
+    let mut anchors: Vec<(DateTime<Utc>, &CurvePoint)> = days
+        .iter()
+        .flat_map(|date| {
+            curve.iter().filter_map(move |point| {
+                point.at.resolve_on(tz, location, *date).map(|t| (t, point))
+            })
+        })
+        .collect();
+    anchors.sort_by_key(|(t, _)| *t);
+
+    let next = anchors.iter().position(|(t, _)| *t > at)?;
+    let (start, from) = *anchors.get(next.checked_sub(1)?)?;
+    let (end, to) = anchors[next];
+
+    let progress = (at - start).num_seconds() as f64 / (end - start).num_seconds().max(1) as f64;
+    let lerp = |a: u32, b: u32| (a as f64 + (b as f64 - a as f64) * progress).round() as u32;
+
+    Some(CurveSample {
+        color_temperature: lerp(from.color_temperature, to.color_temperature),
+        brightness: lerp(from.brightness, to.brightness),
+        phase_start: start,
+    })
+}
+
+/// How often each device may be updated so that all of them together stay within the
+/// circadian share of the daily quota.
+fn update_interval(device_count: usize) -> Duration {
+    let budget = ratelimit::DAILY_LIMIT / QUOTA_SHARE;
+    let updates_per_device = budget / (REQUESTS_PER_UPDATE * device_count.max(1) as u32);
+    let seconds = 86_400 / i64::from(updates_per_device.max(1));
+
+    Duration::seconds(seconds.max(MIN_UPDATE_INTERVAL_SECS))
+}
+
+#[derive(Default)]
+pub struct CircadianState {
+    manual_changes: Mutex<HashMap<String, DateTime<Utc>>>,
+    last_checked: Mutex<HashMap<String, DateTime<Utc>>>,
+    last_sent: Mutex<HashMap<String, (u32, u32)>>,
+}
+
+impl CircadianState {
+    fn is_paused(&self, device: &str, phase_start: DateTime<Utc>) -> bool {
+        self.manual_changes
+            .lock()
+            .unwrap()
+            .get(device)
+            .is_some_and(|changed| *changed >= phase_start)
+    }
+
+    fn needs_update(
+        &self,
+        device: &str,
+        sample: &CurveSample,
+        now: DateTime<Utc>,
+        interval: Duration,
+    ) -> bool {
+        if let Some(checked) = self.last_checked.lock().unwrap().get(device) {
+            if now - *checked < interval {
+                return false;
+            }
+        }
+
+        match self.last_sent.lock().unwrap().get(device) {
+            Some((kelvin, brightness)) => {
+                kelvin.abs_diff(sample.color_temperature) >= MIN_KELVIN_STEP
+                    || brightness.abs_diff(sample.brightness) >= MIN_BRIGHTNESS_STEP
+            }
+            None => true,
+        }
+    }
+}
+
+/// Pauses circadian updates for `device` until the next phase of the curve.
+pub fn note_manual_change<R: Runtime>(app: &AppHandle<R>, device: &str) {
+    if let Some(state) = app.try_state::<CircadianState>() {
+        state
+            .manual_changes
+            .lock()
+            .unwrap()
+            .insert(device.to_string(), Utc::now());
+        state.last_sent.lock().unwrap().remove(device);
+    }
+}
+
+pub fn load_config<R: Runtime>(app: &AppHandle<R>) -> CircadianConfig {
+    settings::get(app).circadian
+}
+
+/// Sends the sample to a device. Returns `false` when the device was skipped because it is
+/// switched off or offline, since setting brightness would turn it back on.
+async fn apply<R: Runtime>(
+    app: &AppHandle<R>,
+    target: &DeviceTarget,
+    sample: &CurveSample,
+) -> Result<bool, String> {
+    let state = get_device_state(app.clone(), target.device.clone(), target.sku.clone()).await?;
+
+    let powered = state
+        .value("powerSwitch")
+        .and_then(|v| v.as_i64())
+        .is_some_and(|v| v == 1);
+    let online = state
+        .value("online")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(true);
+
+    if !powered || !online {
+        return Ok(false);
+    }
+
+    send_capability(
+        app,
+        target.device.clone(),
+        target.sku.clone(),
+        "devices.capabilities.color_setting".to_string(),
+        "colorTemperatureK".to_string(),
+        serde_json::json!(sample.color_temperature),
+    )
+    .await?;
+
+    send_capability(
+        app,
+        target.device.clone(),
+        target.sku.clone(),
+        "devices.capabilities.brightness".to_string(),
+        "brightness".to_string(),
+        serde_json::json!(sample.brightness),
+    )
+    .await?;
+
+    Ok(true)
+}
+
+async fn tick<R: Runtime>(app: &AppHandle<R>) -> Result<(), String> {
+    let config = load_config(app);
+
+    if !config.enabled || config.devices.is_empty() {
+        return Ok(());
+    }
+
+    let now = Utc::now();
+    let Some(current) = sample(&config.curve, &Local, solar::load_location(app), now) else {
+        return Ok(());
+    };
+
+    let interval = update_interval(config.devices.len());
+    let state = app.state::<CircadianState>();
+
+    for target in &config.devices {
+        if profiles::remaining_for(app, &target.device) < QUOTA_RESERVE {
+            continue;
+        }
+
+        if state.is_paused(&target.device, current.phase_start)
+            || !state.needs_update(&target.device, &current, now, interval)
+        {
+            continue;
+        }
+
+        state
+            .last_checked
+            .lock()
+            .unwrap()
+            .insert(target.device.clone(), now);
+
+        match apply(app, target, &current).await {
+            Ok(true) => {
+                state.last_sent.lock().unwrap().insert(
+                    target.device.clone(),
+                    (current.color_temperature, current.brightness),
+                );
+            }
+            Ok(false) => {}
+            Err(e) => eprintln!("Circadian update for {} failed: {}", target.device, e),
+        }
+    }
+
+    Ok(())
+}
+
+pub fn start<R: Runtime>(app_handle: &AppHandle<R>) {
+    app_handle.manage(CircadianState::default());
+
+    let app = app_handle.clone();
+
+    tauri::async_runtime::spawn(async move {
+        loop {
+            if let Err(e) = tick(&app).await {
+                eprintln!("Circadian tick failed: {}", e);
+            }
+
+            tokio::time::sleep(TICK_INTERVAL).await;
+        }
+    });
+}
+
+#[tauri::command]
+pub fn get_circadian_config<R: Runtime>(app: AppHandle<R>) -> CircadianConfig {
+    load_config(&app)
+}
+
+#[tauri::command]
+pub fn set_circadian_config<R: Runtime>(
+    app: AppHandle<R>,
+    config: CircadianConfig,
+) -> Result<(), String> {
+    if config.curve.is_empty() {
+        return Err("Circadian curve needs at least one point".to_string());
+    }
+
+    for point in &config.curve {
+        point.at.validate()?;
+
+        if !(2000..=9000).contains(&point.color_temperature) {
+            return Err("Color temperature must be between 2000K and 9000K".to_string());
+        }
+        if !(1..=100).contains(&point.brightness) {
+            return Err("Brightness must be between 1 and 100".to_string());
+        }
+    }
+
+    let uses_solar = config
+        .curve
+        .iter()
+        .any(|point| matches!(point.at, Trigger::Solar { .. }));
+    if uses_solar && solar::load_location(&app).is_none() {
+        return Err("Location not set. Please set your location first.".to_string());
+    }
+
+    settings::update(&app, |s| {
+        s.circadian = config;
+        Ok(())
+    })
+}
+
+#[tauri::command]
+pub fn get_circadian_sample<R: Runtime>(app: AppHandle<R>) -> Option<CurveSample> {
+    let config = load_config(&app);
+
+    sample(
+        &config.curve,
+        &Local,
+        solar::load_location(&app),
+        Utc::now(),
+    )
+}
+
+/// Clears a manual override so the device follows the curve again on the next tick.
+#[tauri::command]
+pub fn resume_circadian<R: Runtime>(app: AppHandle<R>, device: String) {
+    let state = app.state::<CircadianState>();
+
+    state.manual_changes.lock().unwrap().remove(&device);
+    state.last_checked.lock().unwrap().remove(&device);
+}
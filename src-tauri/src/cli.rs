@@ -0,0 +1,427 @@
+//! The `govee` command-line tool. It reads the app's settings and API keys from disk and
+//! talks to the Govee API directly, so it sees the same devices and presets as the panel
+//! without starting the app.
+
+use std::path::PathBuf;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::command::{fetch_all_devices, ControlError, GoveeClient, GoveeDevice};
+use crate::presets::Preset;
+use crate::scheduler::CapabilityChange;
+use crate::settings;
+
+/// The app's identifier from `tauri.conf.json`, which names its data directory.
+const IDENTIFIER: &str = "com.pum.govee-statusbar";
+
+const USAGE: &str = "\
+Usage: govee [--json] <command>
+
+Commands:
+  devices                      List devices
+  state <device>               Show the state of a device
+  on <device>                  Turn a device on
+  off <device>                 Turn a device off
+  brightness <device> <1-100>  Set the brightness
+  color <device> <hex|name>    Set the color, e.g. ff8800 or warm-white
+  scene <device> <name>        Apply a light or DIY scene
+  preset apply <name>          Apply a preset
+
+<device> is a device name or id. Output is a table unless --json is given.";
+
+/// The panel's color presets plus a few common colors.
+const COLORS: &[(&str, u32)] = &[
+    ("warm-white", 0xfff4e5),
+    ("cool-white", 0xffffff),
+    ("white", 0xffffff),
+    ("red", 0xff0000),
+    ("orange", 0xffa500),
+    ("yellow", 0xffff00),
+    ("green", 0x00ff00),
+    ("cyan", 0x00ffff),
+    ("blue", 0x0000ff),
+    ("purple", 0x800080),
+    ("pink", 0xffc0cb),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Output {
+    Table,
+    Json,
+}
+
+fn print_json(value: &impl Serialize) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(value).map_err(|e| e.to_string())?;
+    println!("{}", json);
+
+    Ok(())
+}
+
+/// Prints `rows` in columns padded to their widest cell.
+fn print_table(headers: &[&str], rows: &[Vec<String>]) {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let line = |cells: Vec<&str>| {
+        let padded: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        println!("{}", padded.join("  ").trim_end());
+    };
+
+    line(headers.to_vec());
+    for row in rows {
+        line(row.iter().map(String::as_str).collect());
+    }
+}
+
+/// Strings without quotes, fractions to one decimal, everything else as JSON.
+fn format_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Number(n) if n.is_f64() => format!("{:.1}", n.as_f64().unwrap_or_default()),
+        value => value.to_string(),
+    }
+}
+
+fn parse_color(input: &str) -> Result<u32, String> {
+    let name = input.to_lowercase().replace([' ', '_'], "-");
+    if let Some((_, rgb)) = COLORS.iter().find(|(n, _)| *n == name) {
+        return Ok(*rgb);
+    }
+
+    let hex = input.trim_start_matches('#');
+    if hex.len() == 6 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return u32::from_str_radix(hex, 16).map_err(|e| e.to_string());
+    }
+
+    let names: Vec<&str> = COLORS.iter().map(|(n, _)| *n).collect();
+    Err(format!(
+        "Unknown color {}. Use a hex value like ff8800 or one of: {}",
+        input,
+        names.join(", ")
+    ))
+}
+
+fn parse_brightness(input: &str) -> Result<u32, String> {
+    match input.trim_end_matches('%').parse::<u32>() {
+        Ok(brightness) if (1..=100).contains(&brightness) => Ok(brightness),
+        _ => Err(format!(
+            "Brightness must be between 1 and 100, got {}",
+            input
+        )),
+    }
+}
+
+/// The app data directory, where the app keeps `settings.json`.
+fn data_dir() -> Result<PathBuf, String> {
+    dirs::data_dir()
+        .map(|dir| dir.join(IDENTIFIER))
+        .ok_or_else(|| "Couldn't find the app data directory".to_string())
+}
+
+/// A client per profile and the presets, loaded from the app's settings.
+struct Govee {
+    clients: Vec<GoveeClient>,
+    presets: Vec<Preset>,
+}
+
+impl Govee {
+    fn load() -> Result<Self, String> {
+        let settings = settings::load_file(&data_dir()?)?;
+
+        Ok(Govee {
+            clients: settings
+                .profiles
+                .into_iter()
+                .map(GoveeClient::new)
+                .collect(),
+            presets: settings.presets,
+        })
+    }
+
+    async fn devices(&self) -> Result<Vec<GoveeDevice>, String> {
+        fetch_all_devices(&self.clients).await
+    }
+
+    fn client(&self, device: &GoveeDevice) -> Result<&GoveeClient, String> {
+        self.clients
+            .iter()
+            .find(|c| c.profile().id == device.profile)
+            .ok_or_else(|| format!("No profile owns {}", device.display_name()))
+    }
+
+    async fn send(&self, device: &GoveeDevice, change: &CapabilityChange) -> Result<(), String> {
+        device.check_control(&change.capability_type, &change.instance, &change.value)?;
+
+        self.client(device)?
+            .control(
+                change.device.clone(),
+                change.sku.clone(),
+                change.capability_type.clone(),
+                change.instance.clone(),
+                change.value.clone(),
+            )
+            .await
+            .map_err(|e| match e {
+                ControlError::Offline => format!("{} is offline", device.display_name()),
+                ControlError::Failed(e) => e,
+            })
+    }
+}
+
+/// Finds a device by id, or by name if no id matches.
+fn find_device(devices: &[GoveeDevice], query: &str) -> Result<GoveeDevice, String> {
+    if let Some(device) = devices
+        .iter()
+        .find(|d| d.device.eq_ignore_ascii_case(query))
+    {
+        return Ok(device.clone());
+    }
+
+    let mut named: Vec<&GoveeDevice> = devices
+        .iter()
+        .filter(|d| d.display_name().eq_ignore_ascii_case(query))
+        .collect();
+
+    match named.len() {
+        0 => Err(format!("No device named {}", query)),
+        1 => Ok(named.remove(0).clone()),
+        n => Err(format!(
+            "{} devices are named {}, use the device id instead",
+            n, query
+        )),
+    }
+}
+
+fn print_changes(
+    devices: &[GoveeDevice],
+    changes: &[CapabilityChange],
+    output: Output,
+) -> Result<(), String> {
+    if output == Output::Json {
+        return print_json(&changes);
+    }
+
+    for change in changes {
+        let name = devices
+            .iter()
+            .find(|d| d.device == change.device)
+            .map(|d| d.display_name())
+            .unwrap_or(&change.device);
+
+        println!(
+            "{}: {} set to {}",
+            name,
+            change.instance,
+            format_value(&change.value)
+        );
+    }
+
+    Ok(())
+}
+
+async fn set(
+    govee: &Govee,
+    query: &str,
+    capability_type: &str,
+    instance: &str,
+    value: Value,
+    output: Output,
+) -> Result<(), String> {
+    let devices = govee.devices().await?;
+    let device = find_device(&devices, query)?;
+
+    let change = CapabilityChange {
+        device: device.device.clone(),
+        sku: device.sku.clone(),
+        capability_type: capability_type.to_string(),
+        instance: instance.to_string(),
+        value,
+    };
+    govee.send(&device, &change).await?;
+
+    print_changes(&devices, &[change], output)
+}
+
+async fn devices(govee: &Govee, output: Output) -> Result<(), String> {
+    let devices = govee.devices().await?;
+
+    if output == Output::Json {
+        return print_json(&devices);
+    }
+
+    // The profile column only matters when devices come from more than one account.
+    let profiles = devices.iter().any(|d| d.profile != devices[0].profile);
+
+    let rows: Vec<Vec<String>> = devices
+        .iter()
+        .map(|d| {
+            let mut row = vec![
+                d.display_name().to_string(),
+                d.device.clone(),
+                d.sku.clone(),
+                d.device_type.clone(),
+            ];
+            if profiles {
+                row.push(d.profile_name.clone());
+            }
+            row
+        })
+        .collect();
+
+    let mut headers = vec!["NAME", "DEVICE", "SKU", "TYPE"];
+    if profiles {
+        headers.push("PROFILE");
+    }
+    print_table(&headers, &rows);
+
+    Ok(())
+}
+
+async fn state(govee: &Govee, query: &str, output: Output) -> Result<(), String> {
+    let device = find_device(&govee.devices().await?, query)?;
+    let state = govee
+        .client(&device)?
+        .state(&device.device, &device.sku)
+        .await?;
+
+    if output == Output::Json {
+        return print_json(&state);
+    }
+
+    if !state.online {
+        println!("{} is offline", device.display_name());
+        return Ok(());
+    }
+
+    let rows: Vec<Vec<String>> = state
+        .capabilities
+        .iter()
+        .map(|c| vec![c.instance.clone(), format_value(&c.state.value)])
+        .collect();
+    print_table(&["INSTANCE", "VALUE"], &rows);
+
+    Ok(())
+}
+
+async fn scene(govee: &Govee, query: &str, name: &str, output: Output) -> Result<(), String> {
+    let device = find_device(&govee.devices().await?, query)?;
+
+    let (instance, value) = govee
+        .client(&device)?
+        .find_scene(&device.device, &device.sku, name)
+        .await
+        .ok_or_else(|| format!("{} has no scene named {}", device.display_name(), name))?;
+
+    let capability = "devices.capabilities.dynamic_scene";
+    set(govee, &device.device, capability, instance, value, output).await
+}
+
+/// Sends each change in order, carrying on past failures like the app does.
+async fn apply_preset(govee: &Govee, query: &str, output: Output) -> Result<(), String> {
+    let preset = govee
+        .presets
+        .iter()
+        .find(|p| p.id == query || p.name.eq_ignore_ascii_case(query))
+        .ok_or_else(|| format!("No preset named {}", query))?;
+
+    let devices = govee.devices().await?;
+    let mut errors = Vec::new();
+
+    for change in &preset.changes {
+        let sent = match devices.iter().find(|d| d.device == change.device) {
+            Some(device) => govee.send(device, change).await,
+            None => Err("not found".to_string()),
+        };
+        if let Err(e) = sent {
+            errors.push(format!("{}: {}", change.device, e));
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors.join("; "));
+    }
+
+    print_changes(&devices, &preset.changes, output)
+}
+
+async fn execute(govee: &Govee, args: &[&str], output: Output) -> Result<(), String> {
+    match args {
+        ["devices"] => devices(govee, output).await,
+        ["state", device] => state(govee, device, output).await,
+        [power @ ("on" | "off"), device] => {
+            let value = serde_json::json!(if *power == "on" { 1 } else { 0 });
+            set(
+                govee,
+                device,
+                "devices.capabilities.on_off",
+                "powerSwitch",
+                value,
+                output,
+            )
+            .await
+        }
+        ["brightness", device, brightness] => {
+            let brightness = parse_brightness(brightness)?;
+            set(
+                govee,
+                device,
+                "devices.capabilities.range",
+                "brightness",
+                serde_json::json!(brightness),
+                output,
+            )
+            .await
+        }
+        ["color", device, color] => {
+            let rgb = parse_color(color)?;
+            set(
+                govee,
+                device,
+                "devices.capabilities.color_setting",
+                "colorRgb",
+                serde_json::json!(rgb),
+                output,
+            )
+            .await
+        }
+        ["scene", device, name] => scene(govee, device, name, output).await,
+        ["preset", "apply", name] => apply_preset(govee, name, output).await,
+        _ => Err(format!("Unknown command: {}\n\n{}", args.join(" "), USAGE)),
+    }
+}
+
+pub fn run() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let output = if args.iter().any(|arg| arg == "--json") {
+        Output::Json
+    } else {
+        Output::Table
+    };
+    let args: Vec<&str> = args
+        .iter()
+        .map(String::as_str)
+        .filter(|arg| *arg != "--json")
+        .collect();
+
+    if args.is_empty() || args.iter().any(|arg| *arg == "-h" || *arg == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+
+    let result = Govee::load()
+        .and_then(|govee| tauri::async_runtime::block_on(execute(&govee, &args, output)));
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
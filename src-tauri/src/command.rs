@@ -1,18 +1,28 @@
 use std::sync::Once;
 
+use chrono::{DateTime, Utc};
 use reqwest::header::{HeaderMap, HeaderValue};
 use serde::{Deserialize, Serialize};
+use tauri::Runtime;
 use uuid::Uuid;
 
-use tauri_nspanel::ManagerExt;
-use tauri_plugin_store::StoreExt;
-
-use crate::fns::{
-    setup_menubar_panel_listeners, swizzle_to_menubar_panel, update_menubar_appearance,
-};
+use crate::alerts;
+use crate::autostart;
+use crate::cache;
+use crate::circadian;
+use crate::events;
+use crate::health;
+use crate::history;
+use crate::metrics;
+use crate::panel;
+use crate::presence;
+use crate::profiles::{self, Profile};
+use crate::ratelimit;
+use crate::rooms;
+
+const API_URL: &str = "https://openapi.api.govee.com/router/api/v1/";
 
 static INIT: Once = Once::new();
-const SETTINGS_FILE: &str = "settings.json";
 
 #[derive(Debug, Serialize, Deserialize)]
 struct GoveeResponse {
@@ -21,24 +31,127 @@ struct GoveeResponse {
     data: Vec<GoveeDevice>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GoveeDevice {
-    sku: String,
-    device: String,
+    pub(crate) sku: String,
+    pub(crate) device: String,
     #[serde(rename = "type")]
-    device_type: String,
+    pub(crate) device_type: String,
     #[serde(default, rename = "deviceName")]
-    device_name: Option<String>,
-    capabilities: Vec<GoveeCapability>,
+    pub(crate) device_name: Option<String>,
+    pub(crate) capabilities: Vec<GoveeCapability>,
+    /// Filled in from presence tracking; the device list itself doesn't say.
+    #[serde(default)]
+    pub(crate) online: Option<bool>,
+    #[serde(default, rename = "lastSeen")]
+    pub(crate) last_seen: Option<DateTime<Utc>>,
+    /// Id and name of the profile whose account owns the device.
+    #[serde(default)]
+    pub(crate) profile: String,
+    #[serde(default, rename = "profileName")]
+    pub(crate) profile_name: String,
+    #[serde(default)]
+    pub(crate) room: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct GoveeCapability {
+impl GoveeDevice {
+    pub fn display_name(&self) -> &str {
+        self.device_name.as_deref().unwrap_or(&self.sku)
+    }
+
+    pub fn has_capability(&self, instance: &str) -> bool {
+        self.capabilities.iter().any(|c| c.instance == instance)
+    }
+
+    pub fn is_light(&self) -> bool {
+        self.device_type == "light"
+            || self.has_capability("brightness")
+            || self.has_capability("colorRgb")
+    }
+
+    /// Colour temperature range a light reports, falling back to the usual Govee range.
+    pub fn kelvin_range(&self) -> (u64, u64) {
+        let range = self
+            .capabilities
+            .iter()
+            .find(|c| c.instance == "colorTemperatureK")
+            .and_then(|c| c.parameters.as_ref())
+            .and_then(|p| p.get("range"));
+        let bound = |key: &str| range.and_then(|r| r.get(key)).and_then(|v| v.as_u64());
+
+        (bound("min").unwrap_or(2000), bound("max").unwrap_or(9000))
+    }
+
+    /// Checks a control request against the capabilities the device lists, so a mistake is
+    /// reported as such rather than as whatever the Govee API makes of it.
+    pub fn check_control(
+        &self,
+        capability_type: &str,
+        instance: &str,
+        value: &serde_json::Value,
+    ) -> Result<(), String> {
+        let capability = self
+            .capabilities
+            .iter()
+            .find(|c| c.capability_type == capability_type && c.instance == instance)
+            .ok_or_else(|| {
+                format!(
+                    "{} has no {} capability {}",
+                    self.display_name(),
+                    capability_type,
+                    instance
+                )
+            })?;
+        let Some(parameters) = &capability.parameters else {
+            return Ok(());
+        };
+
+        match parameters.get("dataType").and_then(|t| t.as_str()) {
+            Some("INTEGER") => {
+                let range = parameters.get("range");
+                let bound = |key: &str| range.and_then(|r| r.get(key)).and_then(|v| v.as_i64());
+                let (min, max) = (
+                    bound("min").unwrap_or(i64::MIN),
+                    bound("max").unwrap_or(i64::MAX),
+                );
+
+                match value.as_i64() {
+                    Some(n) if (min..=max).contains(&n) => Ok(()),
+                    Some(n) => Err(format!(
+                        "{} must be between {} and {}, got {}",
+                        instance, min, max, n
+                    )),
+                    None => Err(format!("{} takes a whole number, got {}", instance, value)),
+                }
+            }
+            Some("ENUM") => {
+                let options: Vec<&serde_json::Value> = parameters
+                    .get("options")
+                    .and_then(|o| o.as_array())
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|o| o.get("value"))
+                    .collect();
+
+                // Scenes come from their own endpoints, so their options are empty here.
+                if options.is_empty() || options.contains(&value) {
+                    Ok(())
+                } else {
+                    Err(format!("{} is not a valid {}", value, instance))
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct GoveeCapability {
     #[serde(rename = "type")]
-    capability_type: String,
-    instance: String,
+    pub(crate) capability_type: String,
+    pub(crate) instance: String,
     #[serde(default)]
-    parameters: Option<serde_json::Value>,
+    pub(crate) parameters: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -63,24 +176,49 @@ struct DeviceStateResponse {
     payload: DeviceState,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeviceState {
-    capabilities: Vec<CapabilityState>,
-    device: String,
-    sku: String,
+    pub(crate) capabilities: Vec<CapabilityState>,
+    pub(crate) device: String,
+    pub(crate) sku: String,
+    /// False when the device can't be reached, so the panel can grey it out.
+    #[serde(default = "default_online")]
+    pub(crate) online: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct CapabilityState {
+fn default_online() -> bool {
+    true
+}
+
+impl DeviceState {
+    pub fn value(&self, instance: &str) -> Option<&serde_json::Value> {
+        self.capabilities
+            .iter()
+            .find(|c| c.instance == instance)
+            .map(|c| &c.state.value)
+    }
+
+    /// A numeric reading. Humidity comes as `{"currentHumidity": 45}` rather than a plain
+    /// number.
+    pub fn reading(&self, instance: &str) -> Option<f64> {
+        match self.value(instance)? {
+            serde_json::Value::Object(fields) => fields.values().find_map(|v| v.as_f64()),
+            value => value.as_f64(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct CapabilityState {
     #[serde(rename = "type")]
-    capability_type: String,
-    instance: String,
-    state: StateValue,
+    pub(crate) capability_type: String,
+    pub(crate) instance: String,
+    pub(crate) state: StateValue,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct StateValue {
-    value: serde_json::Value,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct StateValue {
+    pub(crate) value: serde_json::Value,
 }
 
 #[derive(Debug, Serialize)]
@@ -145,326 +283,609 @@ struct DynamicSceneParameters {
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SceneOption {
-    name: String,
-    value: serde_json::Value, // Can be integer or object with paramId and id
+    pub(crate) name: String,
+    pub(crate) value: serde_json::Value, // Can be integer or object with paramId and id
+}
+
+/// Govee has no separate error code for an unreachable device, only this message.
+const DEVICE_OFFLINE_MESSAGES: [&str; 2] = ["device offline", "devices offline"];
+
+/// Whether an error message, or an error body with a `msg` field, says the device is
+/// offline. Other errors such as rate limits or a bad key are not.
+fn is_device_offline(error: &str) -> bool {
+    let msg = serde_json::from_str::<serde_json::Value>(error)
+        .ok()
+        .and_then(|body| body.get("msg")?.as_str().map(str::to_string))
+        .unwrap_or_else(|| error.to_string());
+    let msg = msg.trim().trim_end_matches('.').to_lowercase();
+
+    DEVICE_OFFLINE_MESSAGES.contains(&msg.as_str())
+}
+
+fn offline_error<R: Runtime>(app: &tauri::AppHandle<R>, device: &str) -> String {
+    let name = cache::devices(app)
+        .into_iter()
+        .find(|d| d.device == device)
+        .map(|d| d.display_name().to_string())
+        .unwrap_or_else(|| device.to_string());
+
+    match presence::get(app, device).last_seen {
+        Some(at) => format!(
+            "{} is offline (last seen {})",
+            name,
+            at.with_timezone(&chrono::Local).format("%b %-d, %H:%M")
+        ),
+        None => format!("{} is offline", name),
+    }
+}
+
+fn api_headers(api_key: &str) -> Result<HeaderMap, String> {
+    if api_key.is_empty() {
+        return Err(
+            "No API key is set. If one was saved, the keyring may be locked or unavailable."
+                .to_string(),
+        );
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "Govee-API-Key",
+        HeaderValue::from_str(api_key).map_err(|e| format!("Invalid API key format: {}", e))?,
+    );
+
+    Ok(headers)
 }
 
 fn generate_request_id() -> String {
     Uuid::new_v4().to_string()
 }
 
-#[tauri::command]
-pub fn init(app_handle: tauri::AppHandle) {
-    INIT.call_once(|| {
-        swizzle_to_menubar_panel(&app_handle);
+type Observer = Box<dyn Fn(&Result<reqwest::Response, reqwest::Error>) + Send + Sync>;
 
-        update_menubar_appearance(&app_handle);
+/// Why a control request failed.
+pub enum ControlError {
+    Offline,
+    Failed(String),
+}
 
-        setup_menubar_panel_listeners(&app_handle);
-    });
+/// Talks to the Govee API with one profile's key. It needs no app, so the `govee` CLI
+/// uses it directly; the commands below add the app's cache, presence and health.
+pub struct GoveeClient {
+    profile: Profile,
+    http: reqwest::Client,
+    observer: Option<Observer>,
 }
 
-#[tauri::command]
-pub fn show_menubar_panel(app_handle: tauri::AppHandle) {
-    let panel = app_handle.get_webview_panel("main").unwrap();
+impl GoveeClient {
+    pub fn new(profile: Profile) -> Self {
+        GoveeClient {
+            profile,
+            http: reqwest::Client::new(),
+            observer: None,
+        }
+    }
 
-    panel.show();
-}
+    /// Calls `observer` with the outcome of every request.
+    pub fn observe(
+        mut self,
+        observer: impl Fn(&Result<reqwest::Response, reqwest::Error>) + Send + Sync + 'static,
+    ) -> Self {
+        self.observer = Some(Box::new(observer));
+        self
+    }
 
-#[tauri::command]
-pub async fn get_devices(app: tauri::AppHandle) -> Result<Vec<GoveeDevice>, String> {
-    let store = app.store(SETTINGS_FILE).map_err(|e| e.to_string())?;
-    let api_key: Option<String> = store
-        .get("api_key")
-        .and_then(|v| v.as_str().map(|s| s.to_string()));
+    pub fn profile(&self) -> &Profile {
+        &self.profile
+    }
 
-    let api_key = api_key.ok_or("API key not set. Please set your Govee API key first.")?;
+    async fn send(
+        &self,
+        endpoint: &str,
+        request: reqwest::RequestBuilder,
+        failure: &str,
+    ) -> Result<reqwest::Response, String> {
+        let request = request.headers(api_headers(&self.profile.api_key)?);
+
+        ratelimit::record(&self.profile.id);
+        let response = metrics::timed(endpoint, request.send()).await;
+        if let Some(observer) = &self.observer {
+            observer(&response);
+        }
 
-    let client = reqwest::Client::new();
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        "Govee-API-Key",
-        HeaderValue::from_str(&api_key).map_err(|e| format!("Invalid API key format: {}", e))?,
-    );
+        response.map_err(|e| format!("{}: {}", failure, e))
+    }
 
-    let response = client
-        .get("https://openapi.api.govee.com/router/api/v1/user/devices")
-        .headers(headers)
-        .send()
-        .await
-        .map_err(|e| format!("Failed to fetch devices: {}", e))?;
+    async fn post(
+        &self,
+        endpoint: &str,
+        body: &impl Serialize,
+        failure: &str,
+    ) -> Result<reqwest::Response, String> {
+        let request = self
+            .http
+            .post(format!("{}{}", API_URL, endpoint))
+            .json(body);
+
+        self.send(endpoint, request, failure).await
+    }
 
-    let mut govee_response: GoveeResponse = response
-        .json()
-        .await
-        .map_err(|e| format!("Failed to parse response: {}", e))?;
+    /// The profile's devices, tagged with it.
+    pub async fn devices(&self) -> Result<Vec<GoveeDevice>, String> {
+        let endpoint = "user/devices";
+        let request = self.http.get(format!("{}{}", API_URL, endpoint));
+        let response = self
+            .send(endpoint, request, "Failed to fetch devices")
+            .await?;
+
+        let mut govee_response: GoveeResponse = response
+            .json()
+            .await
+            .map_err(|e| format!("Failed to parse response: {}", e))?;
+
+        // Clean up the type field by removing the "devices.types." prefix
+        for device in &mut govee_response.data {
+            if let Some(stripped_type) = device.device_type.strip_prefix("devices.types.") {
+                device.device_type = stripped_type.to_string();
+            }
+        }
+
+        if govee_response.code != 200 {
+            return Err(format!("API error: {}", govee_response.message));
+        }
 
-    // Clean up the type field by removing the "devices.types." prefix
-    for device in &mut govee_response.data {
-        if let Some(stripped_type) = device.device_type.strip_prefix("devices.types.") {
-            device.device_type = stripped_type.to_string();
+        for device in &mut govee_response.data {
+            device.profile = self.profile.id.clone();
+            device.profile_name = self.profile.name.clone();
         }
+
+        Ok(govee_response.data)
     }
 
-    if govee_response.code != 200 {
-        return Err(format!("API error: {}", govee_response.message));
+    /// The device's state, with temperatures in °C. An unreachable device comes back
+    /// offline, without capabilities.
+    pub async fn state(&self, device: &str, sku: &str) -> Result<DeviceState, String> {
+        let request_body = DeviceStateRequest {
+            request_id: generate_request_id(),
+            payload: DeviceStatePayload {
+                device: device.to_string(),
+                sku: sku.to_string(),
+            },
+        };
+
+        let response = self
+            .post(
+                "device/state",
+                &request_body,
+                "Failed to fetch device state",
+            )
+            .await?;
+
+        let mut state_response: DeviceStateResponse = response
+            .json()
+            .await
+            .map_err(|e| format!("Failed to parse response: {}", e))?;
+
+        // Convert temperature from Fahrenheit to Celsius if sensorTemperature capability exists.
+        // It stays unrounded for history, metrics and the bridges; the panel and tray round it.
+        if let Some(temp_cap) = state_response
+            .payload
+            .capabilities
+            .iter_mut()
+            .find(|c| c.instance == "sensorTemperature")
+        {
+            if let Some(temp_f) = temp_cap.state.value.as_f64() {
+                temp_cap.state.value = serde_json::Value::from((temp_f - 32.0) * 5.0 / 9.0);
+            }
+        }
+
+        if state_response.code != 200 {
+            if is_device_offline(&state_response.msg) {
+                return Ok(DeviceState {
+                    capabilities: Vec::new(),
+                    device: device.to_string(),
+                    sku: sku.to_string(),
+                    online: false,
+                });
+            }
+
+            return Err(format!("API error: {}", state_response.msg));
+        }
+
+        Ok(state_response.payload)
     }
 
-    Ok(govee_response.data)
+    pub async fn control(
+        &self,
+        device: String,
+        sku: String,
+        capability_type: String,
+        instance: String,
+        value: serde_json::Value,
+    ) -> Result<(), ControlError> {
+        let request = DeviceControlRequest {
+            request_id: generate_request_id(),
+            payload: DeviceControlPayload {
+                device,
+                sku,
+                capability: CapabilityControl {
+                    capability_type,
+                    instance,
+                    value,
+                },
+            },
+        };
+
+        let response = self
+            .post("device/control", &request, "Failed to send control command")
+            .await
+            .map_err(ControlError::Failed)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error_text = response
+                .text()
+                .await
+                .unwrap_or_else(|_| "Unknown error".to_string());
+
+            if is_device_offline(&error_text) {
+                return Err(ControlError::Offline);
+            }
+
+            return Err(ControlError::Failed(format!(
+                "API error ({}): {}",
+                status, error_text
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Options of the `instance` scene capability, from `endpoint`.
+    async fn scenes(
+        &self,
+        endpoint: &str,
+        instance: &str,
+        device: &str,
+        sku: &str,
+    ) -> Result<Vec<SceneOption>, String> {
+        let request_body = DynamicSceneRequest {
+            request_id: generate_request_id(),
+            payload: DeviceStatePayload {
+                device: device.to_string(),
+                sku: sku.to_string(),
+            },
+        };
+
+        let kind = if instance == "diyScene" {
+            "DIY scenes"
+        } else {
+            "light scenes"
+        };
+        let response = self
+            .post(
+                endpoint,
+                &request_body,
+                &format!("Failed to fetch {}", kind),
+            )
+            .await?;
+
+        let scene_response: DynamicSceneResponse = response
+            .json()
+            .await
+            .map_err(|e| format!("Failed to parse response: {}", e))?;
+
+        if scene_response.code != 200 {
+            return Err(format!("API error: {}", scene_response.msg));
+        }
+
+        scene_response
+            .payload
+            .capabilities
+            .into_iter()
+            .find(|cap| cap.instance == instance)
+            .map(|cap| cap.parameters.options)
+            .ok_or_else(|| format!("No {} found", kind))
+    }
+
+    pub async fn light_scenes(&self, device: &str, sku: &str) -> Result<Vec<SceneOption>, String> {
+        self.scenes("device/scenes", "lightScene", device, sku)
+            .await
+    }
+
+    pub async fn diy_scenes(&self, device: &str, sku: &str) -> Result<Vec<SceneOption>, String> {
+        self.scenes("device/diy-scenes", "diyScene", device, sku)
+            .await
+    }
+
+    /// Like the app's `find_scene`, without caching the scenes.
+    pub async fn find_scene(
+        &self,
+        device: &str,
+        sku: &str,
+        name: &str,
+    ) -> Option<(&'static str, serde_json::Value)> {
+        let light_scenes = self.light_scenes(device, sku).await.unwrap_or_default();
+        if let Some(value) = scene_named(light_scenes, name) {
+            return Some(("lightScene", value));
+        }
+
+        let diy_scenes = self.diy_scenes(device, sku).await.unwrap_or_default();
+        scene_named(diy_scenes, name).map(|value| ("diyScene", value))
+    }
 }
 
-#[tauri::command]
-pub async fn get_device_state(
-    app: tauri::AppHandle,
-    device: String,
-    sku: String,
-) -> Result<DeviceState, String> {
-    let store = app.store(SETTINGS_FILE).map_err(|e| e.to_string())?;
-    let api_key: Option<String> = store
-        .get("api_key")
-        .and_then(|v| v.as_str().map(|s| s.to_string()));
+fn scene_named(scenes: Vec<SceneOption>, name: &str) -> Option<serde_json::Value> {
+    scenes
+        .into_iter()
+        .find(|s| s.name.eq_ignore_ascii_case(name))
+        .map(|s| s.value)
+}
 
-    let api_key = api_key.ok_or("API key not set. Please set your Govee API key first.")?;
+/// Devices of every client's profile. A profile that fails to load is skipped as long as
+/// another one succeeds.
+pub async fn fetch_all_devices(clients: &[GoveeClient]) -> Result<Vec<GoveeDevice>, String> {
+    if clients.is_empty() {
+        return Err("API key not set. Please set your Govee API key first.".to_string());
+    }
 
-    let client = reqwest::Client::new();
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        "Govee-API-Key",
-        HeaderValue::from_str(&api_key).map_err(|e| format!("Invalid API key format: {}", e))?,
-    );
-    let request_body = DeviceStateRequest {
-        request_id: generate_request_id(),
-        payload: DeviceStatePayload { device, sku },
-    };
-
-    let response = client
-        .post("https://openapi.api.govee.com/router/api/v1/device/state")
-        .headers(headers)
-        .json(&request_body)
-        .send()
-        .await
-        .map_err(|e| format!("Failed to fetch device state: {}", e))?;
+    let mut devices = Vec::new();
+    let mut errors = Vec::new();
 
-    let mut state_response: DeviceStateResponse = response
-        .json()
-        .await
-        .map_err(|e| format!("Failed to parse response: {}", e))?;
-
-    // Convert temperature from Fahrenheit to Celsius if sensorTemperature capability exists
-    if let Some(temp_cap) = state_response
-        .payload
-        .capabilities
-        .iter_mut()
-        .find(|c| c.instance == "sensorTemperature")
-    {
-        if let Some(temp_f) = temp_cap.state.value.as_f64() {
-            temp_cap.state.value = serde_json::Value::from(((temp_f - 32.0) * 5.0 / 9.0).round());
+    for client in clients {
+        match client.devices().await {
+            Ok(found) => devices.extend(found),
+            Err(e) if clients.len() == 1 => return Err(e),
+            Err(e) => errors.push(format!("{}: {}", client.profile.name, e)),
         }
     }
 
-    if state_response.code != 200 {
-        return Err(format!("API error: {}", state_response.msg));
+    if devices.is_empty() && !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+    for error in &errors {
+        eprintln!("Failed to fetch devices for {}", error);
     }
 
-    Ok(state_response.payload)
+    Ok(devices)
+}
+
+/// A client that keeps the app's API health up to date.
+fn client<R: Runtime>(app: &tauri::AppHandle<R>, profile: Profile) -> GoveeClient {
+    let app = app.clone();
+
+    GoveeClient::new(profile).observe(move |response| health::record(&app, response))
 }
 
 #[tauri::command]
-pub async fn change_capability_value(
-    app: tauri::AppHandle,
+pub fn init(app_handle: tauri::AppHandle) {
+    INIT.call_once(|| {
+        panel::init(&app_handle);
+
+        if !autostart::load_autostart(&app_handle).start_hidden {
+            panel::show(&app_handle, None);
+        }
+    });
+}
+
+#[tauri::command]
+pub fn show_menubar_panel(app_handle: tauri::AppHandle) {
+    panel::show(&app_handle, None);
+}
+
+/// Devices of every profile. A profile that fails to load is skipped as long as another
+/// one succeeds.
+#[tauri::command]
+pub async fn get_devices<R: Runtime>(app: tauri::AppHandle<R>) -> Result<Vec<GoveeDevice>, String> {
+    let clients: Vec<GoveeClient> = profiles::load_profiles(&app)
+        .into_iter()
+        .map(|profile| client(&app, profile))
+        .collect();
+    let mut devices = fetch_all_devices(&clients).await?;
+
+    let mut rooms = rooms::load_rooms(&app);
+    for device in &mut devices {
+        let presence = presence::get(&app, &device.device);
+        device.online = presence.online;
+        device.last_seen = presence.last_seen;
+        device.room = rooms.remove(&device.device);
+    }
+
+    cache::set_devices(&app, &devices);
+
+    Ok(devices)
+}
+
+#[tauri::command]
+pub async fn get_device_state<R: Runtime>(
+    app: tauri::AppHandle<R>,
+    device: String,
+    sku: String,
+) -> Result<DeviceState, String> {
+    let profile = profiles::for_device(&app, &device).await?;
+    let mut state = client(&app, profile).state(&device, &sku).await?;
+
+    // Report an unreachable device as offline rather than failing the whole refresh.
+    if !state.online {
+        presence::set_online(&app, &device, false);
+        return Ok(state);
+    }
+
+    presence::observe(&app, &state);
+    state.online = !presence::is_offline(&app, &device);
+
+    cache::set_state(&app, &state);
+    history::record(&app, &state);
+    alerts::evaluate(&app, &state);
+    events::publish_sensor_reading(&app, &state);
+
+    Ok(state)
+}
+
+#[tauri::command]
+pub async fn change_capability_value<R: Runtime>(
+    app: tauri::AppHandle<R>,
     device: String,
     sku: String,
     capability_type: String,
     instance: String,
     value: serde_json::Value,
 ) -> Result<(), String> {
-    let store = app.store(SETTINGS_FILE).map_err(|e| e.to_string())?;
-    let api_key: Option<String> = store
-        .get("api_key")
-        .and_then(|v| v.as_str().map(|s| s.to_string()));
+    send_capability(&app, device.clone(), sku, capability_type, instance, value).await?;
 
-    let api_key = api_key.ok_or("API key not set. Please set your Govee API key first.")?;
+    circadian::note_manual_change(&app, &device);
 
-    let client = reqwest::Client::new();
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        "Govee-API-Key",
-        HeaderValue::from_str(&api_key).map_err(|e| format!("Invalid API key format: {}", e))?,
-    );
+    Ok(())
+}
+
+/// Sends a control request without marking the device as manually changed. Automations
+/// that manage a device's state themselves use this instead of `change_capability_value`.
+pub async fn send_capability<R: Runtime>(
+    app: &tauri::AppHandle<R>,
+    device: String,
+    sku: String,
+    capability_type: String,
+    instance: String,
+    value: serde_json::Value,
+) -> Result<(), String> {
+    let profile = profiles::for_device(app, &device).await?;
 
-    let request = DeviceControlRequest {
-        request_id: generate_request_id(),
-        payload: DeviceControlPayload {
-            device,
+    client(app, profile)
+        .control(
+            device.clone(),
             sku,
-            capability: CapabilityControl {
-                capability_type,
-                instance,
-                value,
-            },
-        },
-    };
-
-    let response = client
-        .post("https://openapi.api.govee.com/router/api/v1/device/control")
-        .headers(headers)
-        .json(&request)
-        .send()
+            capability_type,
+            instance.clone(),
+            value.clone(),
+        )
         .await
-        .map_err(|e| format!("Failed to send control command: {}", e))?;
+        .map_err(|e| match e {
+            ControlError::Offline => {
+                presence::set_online(app, &device, false);
+                offline_error(app, &device)
+            }
+            ControlError::Failed(e) => e,
+        })?;
 
-    let status = response.status();
-    if !status.is_success() {
-        let error_text = response
-            .text()
-            .await
-            .unwrap_or_else(|_| "Unknown error".to_string());
-        return Err(format!("API error ({}): {}", status, error_text));
-    }
+    cache::note_change(app, &device, &instance, &value);
 
     Ok(())
 }
 
+/// Key of the default profile, which the panel's API key form edits.
 #[tauri::command]
-pub fn get_api_key(app: tauri::AppHandle) -> Option<String> {
-    let store = app.store(SETTINGS_FILE).ok()?;
-    store
-        .get("api_key")
-        .and_then(|v| v.as_str().map(|s| s.to_string()))
+pub fn get_api_key<R: Runtime>(app: tauri::AppHandle<R>) -> Option<String> {
+    let profiles = profiles::load_profiles(&app);
+
+    profiles
+        .iter()
+        .find(|p| p.id == profiles::DEFAULT_PROFILE)
+        .or(profiles.first())
+        .map(|p| p.api_key.clone())
 }
 
-#[tauri::command]
-pub async fn set_api_key(app: tauri::AppHandle, api_key: String) -> Result<(), String> {
+/// Checks a key by listing its devices.
+pub(crate) async fn verify_api_key<R: Runtime>(
+    app: &tauri::AppHandle<R>,
+    profile: &str,
+    api_key: &str,
+) -> Result<(), String> {
     // Test if the API key is valid
     if api_key.is_empty() {
         return Err("API key cannot be empty".to_string());
     }
 
     let client = reqwest::Client::new();
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        "Govee-API-Key",
-        HeaderValue::from_str(&api_key).map_err(|e| format!("Invalid API key format: {}", e))?,
-    );
-    let response = client
-        .get("https://openapi.api.govee.com/router/api/v1/user/devices")
-        .headers(headers)
-        .send()
-        .await
-        .map_err(|e| format!("Failed to fetch devices: {}", e))?;
+    let headers = api_headers(api_key)?;
+
+    ratelimit::record(profile);
+    let response = metrics::timed(
+        "user/devices",
+        client
+            .get("https://openapi.api.govee.com/router/api/v1/user/devices")
+            .headers(headers)
+            .send(),
+    )
+    .await
+    .map_err(|e| format!("Failed to fetch devices: {}", e))?;
 
     if response.status().is_success() {
-        let store = app.store(SETTINGS_FILE).map_err(|e| e.to_string())?;
-        store.set("api_key", serde_json::Value::String(api_key));
-        store
-            .save()
-            .map_err(|_| "Failed to save settings".to_string())?;
+        health::set(app, health::ApiHealth::Ok);
         Ok(())
     } else {
         Err("Invalid API key".to_string())
     }
 }
 
+/// Sets the key of the default profile, creating it if needed.
+#[tauri::command]
+pub async fn set_api_key<R: Runtime>(
+    app: tauri::AppHandle<R>,
+    api_key: String,
+) -> Result<(), String> {
+    let mut profiles = profiles::load_profiles(&app);
+    let id = profiles
+        .iter()
+        .find(|p| p.id == profiles::DEFAULT_PROFILE)
+        .or(profiles.first())
+        .map(|p| p.id.clone())
+        .unwrap_or_else(|| profiles::DEFAULT_PROFILE.to_string());
+
+    verify_api_key(&app, &id, &api_key).await?;
+
+    match profiles.iter_mut().find(|p| p.id == id) {
+        Some(profile) => profile.api_key = api_key,
+        None => profiles.push(Profile {
+            id,
+            name: "Default".to_string(),
+            api_key,
+        }),
+    }
+
+    profiles::save_profiles(&app, profiles)
+}
+
 #[tauri::command]
-pub async fn get_light_scenes(
-    app: tauri::AppHandle,
+pub async fn get_light_scenes<R: Runtime>(
+    app: tauri::AppHandle<R>,
     device: String,
     sku: String,
 ) -> Result<Vec<SceneOption>, String> {
-    let store = app.store(SETTINGS_FILE).map_err(|e| e.to_string())?;
-    let api_key: Option<String> = store
-        .get("api_key")
-        .and_then(|v| v.as_str().map(|s| s.to_string()));
-    let api_key = api_key.ok_or("API key not set. Please set your Govee API key first.")?;
+    let profile = profiles::for_device(&app, &device).await?;
+    let scenes = client(&app, profile).light_scenes(&device, &sku).await?;
 
-    let client = reqwest::Client::new();
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        "Govee-API-Key",
-        HeaderValue::from_str(&api_key).map_err(|e| format!("Invalid API key format: {}", e))?,
-    );
-
-    let request_body = DynamicSceneRequest {
-        request_id: generate_request_id(),
-        payload: DeviceStatePayload { device, sku },
-    };
-
-    let response = client
-        .post("https://openapi.api.govee.com/router/api/v1/device/scenes")
-        .headers(headers)
-        .json(&request_body)
-        .send()
-        .await
-        .map_err(|e| format!("Failed to fetch light scenes: {}", e))?;
-
-    let scene_response: DynamicSceneResponse = response
-        .json()
-        .await
-        .map_err(|e| format!("Failed to parse response: {}", e))?;
-
-    if scene_response.code != 200 {
-        return Err(format!("API error: {}", scene_response.msg));
-    }
-
-    // Find the lightScene capability and return its options
-    let light_scenes = scene_response
-        .payload
-        .capabilities
-        .iter()
-        .find(|cap| cap.instance == "lightScene")
-        .ok_or("No light scenes found")?;
+    cache::set_scenes(&app, &device, &scenes);
 
-    Ok(light_scenes.parameters.options.clone())
+    Ok(scenes)
 }
 
 #[tauri::command]
-pub async fn get_diy_scenes(
-    app: tauri::AppHandle,
+pub async fn get_diy_scenes<R: Runtime>(
+    app: tauri::AppHandle<R>,
     device: String,
     sku: String,
 ) -> Result<Vec<SceneOption>, String> {
-    let store = app.store(SETTINGS_FILE).map_err(|e| e.to_string())?;
-    let api_key: Option<String> = store
-        .get("api_key")
-        .and_then(|v| v.as_str().map(|s| s.to_string()));
-    let api_key = api_key.ok_or("API key not set. Please set your Govee API key first.")?;
+    let profile = profiles::for_device(&app, &device).await?;
 
-    let client = reqwest::Client::new();
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        "Govee-API-Key",
-        HeaderValue::from_str(&api_key).map_err(|e| format!("Invalid API key format: {}", e))?,
-    );
-
-    let request_body = DynamicSceneRequest {
-        request_id: generate_request_id(),
-        payload: DeviceStatePayload { device, sku },
-    };
-
-    let response = client
-        .post("https://openapi.api.govee.com/router/api/v1/device/diy-scenes")
-        .headers(headers)
-        .json(&request_body)
-        .send()
-        .await
-        .map_err(|e| format!("Failed to fetch DIY scenes: {}", e))?;
+    client(&app, profile).diy_scenes(&device, &sku).await
+}
 
-    let scene_response: DynamicSceneResponse = response
-        .json()
+/// Looks for `name` among the light scenes first, then the DIY scenes. Returns the
+/// `dynamic_scene` instance and value that apply it.
+pub async fn find_scene<R: Runtime>(
+    app: &tauri::AppHandle<R>,
+    device: &str,
+    sku: &str,
+    name: &str,
+) -> Option<(&'static str, serde_json::Value)> {
+    let light_scenes = get_light_scenes(app.clone(), device.to_string(), sku.to_string())
         .await
-        .map_err(|e| format!("Failed to parse response: {}", e))?;
-
-    if scene_response.code != 200 {
-        return Err(format!("API error: {}", scene_response.msg));
+        .unwrap_or_default();
+    if let Some(value) = scene_named(light_scenes, name) {
+        return Some(("lightScene", value));
     }
 
-    // Find the diyScene capability and return its options
-    let diy_scenes = scene_response
-        .payload
-        .capabilities
-        .iter()
-        .find(|cap| cap.instance == "diyScene")
-        .ok_or("No DIY scenes found")?;
-
-    Ok(diy_scenes.parameters.options.clone())
+    let diy_scenes = get_diy_scenes(app.clone(), device.to_string(), sku.to_string())
+        .await
+        .unwrap_or_default();
+    scene_named(diy_scenes, name).map(|value| ("diyScene", value))
 }
@@ -0,0 +1,112 @@
+//! Headless mode, started with `--headless`, for machines without a display. Runs the Govee
+//! client, state poller, scheduler and automations without the panel or tray.
+//!
+//! Settings come from a TOML file instead of the app's settings: `daemon.toml` in the app's
+//! config directory, or the path given with `--config <path>`. It has the same fields as
+//! `Settings`, and changes made while running are not written back to it.
+
+use std::path::{Path, PathBuf};
+
+use tauri::{AppHandle, Manager, Runtime};
+
+use crate::cache;
+use crate::server;
+use crate::settings::{self, Settings};
+
+const CONFIG_FILE: &str = "daemon.toml";
+
+fn config_path<R: Runtime>(app: &AppHandle<R>) -> Result<PathBuf, String> {
+    let mut args = std::env::args().skip_while(|arg| arg != "--config");
+
+    if args.next().is_some() {
+        return args
+            .next()
+            .map(PathBuf::from)
+            .ok_or_else(|| "--config needs a path".to_string());
+    }
+
+    let dir = app.path().app_config_dir().map_err(|e| e.to_string())?;
+
+    Ok(dir.join(CONFIG_FILE))
+}
+
+fn fill_id(id: &mut String, name: &str) {
+    if id.is_empty() {
+        *id = name.to_string();
+    }
+}
+
+pub fn load_config(path: &Path) -> Result<Settings, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let mut settings: Settings =
+        toml::from_str(&text).map_err(|e| format!("Invalid config {}: {}", path.display(), e))?;
+
+    if settings.profiles.iter().all(|p| p.api_key.is_empty()) {
+        return Err(format!(
+            "{} needs a [[profiles]] entry with an api_key",
+            path.display()
+        ));
+    }
+
+    // Entries written by hand may leave out their id. The name stands in for it so the id
+    // stays the same across restarts.
+    for schedule in &mut settings.schedules {
+        fill_id(&mut schedule.id, &schedule.name);
+    }
+    for alarm in &mut settings.alarms {
+        fill_id(&mut alarm.id, &alarm.name);
+    }
+    for preset in &mut settings.presets {
+        fill_id(&mut preset.id, &preset.name);
+    }
+    for rule in &mut settings.alert_rules {
+        fill_id(&mut rule.id, &rule.name);
+    }
+    for webhook in &mut settings.webhooks {
+        fill_id(&mut webhook.id, &webhook.name);
+    }
+    for webhook in &mut settings.outgoing_webhooks {
+        fill_id(&mut webhook.id, &webhook.name);
+    }
+
+    Ok(settings)
+}
+
+fn start<R: Runtime>(app: &AppHandle<R>) -> Result<(), String> {
+    let path = config_path(app)?;
+
+    settings::init_with(app, load_config(&path)?);
+    app.manage(cache::DeviceCache::default());
+
+    server::announce_new_token(app)?;
+    crate::start_services(app)?;
+
+    println!("Running headless with {}", path.display());
+
+    Ok(())
+}
+
+/// Tauri's mock runtime provides the app handle, plugins and managed state the services
+/// need without opening a display connection.
+#[cfg(feature = "headless")]
+pub fn run() {
+    let app = tauri::test::mock_builder()
+        .plugin(tauri_plugin_store::Builder::default().build())
+        .plugin(tauri_plugin_notification::init())
+        .build(crate::context())
+        .expect("error while building headless app");
+
+    if let Err(e) = start(app.handle()) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    app.run(|_, _| {});
+}
+
+#[cfg(not(feature = "headless"))]
+pub fn run() {
+    eprintln!("This build has no headless mode. Build it with `--features headless`.");
+    std::process::exit(1);
+}
@@ -0,0 +1,165 @@
+//! Backend events for integrations such as the WebSocket stream. They are published as
+//! the backend sees them; a subscriber that falls behind misses events instead of slowing
+//! the backend down.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use tauri::{AppHandle, Manager, Runtime};
+use tokio::sync::broadcast;
+
+use crate::command::DeviceState;
+use crate::rooms;
+
+const CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AutomationKind {
+    Schedule,
+    Alarm,
+    Timer,
+    Alert,
+    Webhook,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Payload {
+    /// Capability values that changed, by instance.
+    State {
+        changes: BTreeMap<String, Value>,
+        /// The first state seen for the device, with every value. Bridges mirror it;
+        /// subscribers that react to changes skip it.
+        #[serde(skip)]
+        initial: bool,
+    },
+    /// A fresh sensor reading, published on every fetch even if unchanged.
+    Sensor {
+        temperature: Option<f64>,
+        humidity: Option<f64>,
+    },
+    Online {
+        online: bool,
+    },
+    /// An automation ran. `detail` is the error of a failed run or what triggered an alert.
+    Automation {
+        kind: AutomationKind,
+        name: String,
+        detail: Option<String>,
+    },
+}
+
+impl Payload {
+    /// The event type clients subscribe to.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Payload::State { .. } => "state",
+            Payload::Sensor { .. } => "sensor",
+            Payload::Online { .. } => "online",
+            Payload::Automation { .. } => "automation",
+        }
+    }
+
+    /// False for a device's first state, which isn't a change.
+    pub fn is_change(&self) -> bool {
+        !matches!(self, Payload::State { initial: true, .. })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub at: DateTime<Utc>,
+    pub device: Option<String>,
+    pub room: Option<String>,
+    #[serde(flatten)]
+    pub payload: Payload,
+}
+
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+}
+
+pub fn publish<R: Runtime>(app: &AppHandle<R>, device: Option<&str>, payload: Payload) {
+    let Some(bus) = app.try_state::<EventBus>() else {
+        return;
+    };
+
+    // Sending only fails when nobody is subscribed.
+    let _ = bus.sender.send(Event {
+        at: Utc::now(),
+        device: device.map(str::to_string),
+        room: device.and_then(|d| rooms::room_of(app, d)),
+        payload,
+    });
+}
+
+/// Publishes the values of `state` that differ from `previous`, or all of them marked as
+/// initial when there is no previous state.
+pub fn publish_state_changes<R: Runtime>(
+    app: &AppHandle<R>,
+    previous: Option<&DeviceState>,
+    state: &DeviceState,
+) {
+    let changes: BTreeMap<String, Value> = state
+        .capabilities
+        .iter()
+        .filter(|c| previous.and_then(|p| p.value(&c.instance)) != Some(&c.state.value))
+        .map(|c| (c.instance.clone(), c.state.value.clone()))
+        .collect();
+
+    if !changes.is_empty() {
+        let initial = previous.is_none();
+        publish(
+            app,
+            Some(&state.device),
+            Payload::State { changes, initial },
+        );
+    }
+}
+
+pub fn publish_sensor_reading<R: Runtime>(app: &AppHandle<R>, state: &DeviceState) {
+    let temperature = state.reading("sensorTemperature");
+    let humidity = state.reading("sensorHumidity");
+
+    if temperature.is_some() || humidity.is_some() {
+        publish(
+            app,
+            Some(&state.device),
+            Payload::Sensor {
+                temperature,
+                humidity,
+            },
+        );
+    }
+}
+
+pub fn publish_automation<R: Runtime>(
+    app: &AppHandle<R>,
+    kind: AutomationKind,
+    name: &str,
+    device: Option<&str>,
+    detail: Option<String>,
+) {
+    publish(
+        app,
+        device,
+        Payload::Automation {
+            kind,
+            name: name.to_string(),
+            detail,
+        },
+    );
+}
+
+pub fn subscribe<R: Runtime>(app: &AppHandle<R>) -> broadcast::Receiver<Event> {
+    app.state::<EventBus>().sender.subscribe()
+}
+
+pub fn start<R: Runtime>(app_handle: &AppHandle<R>) {
+    let (sender, _) = broadcast::channel(CAPACITY);
+
+    app_handle.manage(EventBus { sender });
+}
@@ -0,0 +1,2143 @@
+//! HomeKit Accessory Protocol over IP, as much of it as a bridge needs: pairing, encrypted
+//! sessions, the accessory database, reads, writes and event notifications. It knows
+//! nothing about Govee devices; `homekit` builds the accessories and handles writes.
+//!
+//! Pair setup runs SRP-6a (3072-bit group, SHA-512) with the setup code, after which the
+//! controller and the bridge know each other's Ed25519 keys. Pair verify agrees on session
+//! keys with X25519, and everything after it on that connection is sent in
+//! ChaCha20-Poly1305 frames.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use hkdf::Hkdf;
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha512};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+const SERVICE_TYPE: &str = "_hap._tcp.local.";
+const CATEGORY_BRIDGE: u64 = 2;
+const BRIDGE_AID: u64 = 1;
+const MAX_FRAME_LEN: usize = 1024;
+const TAG_LEN: usize = 16;
+// Requests are small; anything larger is a confused or hostile client.
+const MAX_REQUEST_LEN: usize = 64 * 1024;
+const MAX_SETUP_ATTEMPTS: u32 = 100;
+// A setup left this long by its controller no longer blocks others.
+const SETUP_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Service types.
+pub const ACCESSORY_INFORMATION: &str = "3E";
+pub const PROTOCOL_INFORMATION: &str = "A2";
+pub const LIGHTBULB: &str = "43";
+pub const SWITCH: &str = "49";
+pub const TEMPERATURE_SENSOR: &str = "8A";
+pub const HUMIDITY_SENSOR: &str = "82";
+
+// Characteristic types.
+pub const IDENTIFY: &str = "14";
+pub const MANUFACTURER: &str = "20";
+pub const MODEL: &str = "21";
+pub const NAME: &str = "23";
+pub const SERIAL_NUMBER: &str = "30";
+pub const FIRMWARE_REVISION: &str = "52";
+pub const VERSION: &str = "37";
+pub const ON: &str = "25";
+pub const BRIGHTNESS: &str = "8";
+pub const HUE: &str = "13";
+pub const SATURATION: &str = "2F";
+pub const COLOR_TEMPERATURE: &str = "CE";
+pub const CURRENT_TEMPERATURE: &str = "11";
+pub const CURRENT_RELATIVE_HUMIDITY: &str = "10";
+
+// Characteristic permissions.
+pub const READ: u8 = 1;
+pub const WRITE: u8 = 2;
+pub const NOTIFY: u8 = 4;
+
+// TLV8 item types.
+const TLV_METHOD: u8 = 0x00;
+const TLV_IDENTIFIER: u8 = 0x01;
+const TLV_SALT: u8 = 0x02;
+const TLV_PUBLIC_KEY: u8 = 0x03;
+const TLV_PROOF: u8 = 0x04;
+const TLV_ENCRYPTED_DATA: u8 = 0x05;
+const TLV_STATE: u8 = 0x06;
+const TLV_ERROR: u8 = 0x07;
+const TLV_SIGNATURE: u8 = 0x0a;
+const TLV_PERMISSIONS: u8 = 0x0b;
+const TLV_SEPARATOR: u8 = 0xff;
+
+// TLV8 errors.
+const ERROR_UNKNOWN: u8 = 0x01;
+const ERROR_AUTHENTICATION: u8 = 0x02;
+const ERROR_MAX_TRIES: u8 = 0x05;
+const ERROR_UNAVAILABLE: u8 = 0x06;
+const ERROR_BUSY: u8 = 0x07;
+
+// `/pairings` methods.
+const METHOD_ADD_PAIRING: u8 = 3;
+const METHOD_REMOVE_PAIRING: u8 = 4;
+const METHOD_LIST_PAIRINGS: u8 = 5;
+
+// Status codes in JSON responses.
+const STATUS_SUCCESS: i64 = 0;
+const STATUS_INSUFFICIENT_PRIVILEGES: i64 = -70401;
+const STATUS_COMMUNICATION_FAILURE: i64 = -70402;
+const STATUS_READ_ONLY: i64 = -70404;
+const STATUS_WRITE_ONLY: i64 = -70405;
+const STATUS_NO_NOTIFICATION: i64 = -70406;
+const STATUS_NOT_FOUND: i64 = -70409;
+const STATUS_INVALID_VALUE: i64 = -70410;
+
+// The 3072-bit group from RFC 5054, with generator 5.
+const SRP_N: &str = "\
+    FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74\
+    020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437\
+    4FE1356D6D51C245E485B576625E7EC6F44C42E9A637ED6B0BFF5CB6F406B7ED\
+    EE386BFB5A899FA5AE9F24117C4B1FE649286651ECE45B3DC2007CB8A163BF05\
+    98DA48361C55D39A69163FA8FD24CF5F83655D23DCA3AD961C62F356208552BB\
+    9ED529077096966D670C354E4ABC9804F1746C08CA18217C32905E462E36CE3B\
+    E39E772C180E86039B2783A2EC07A28FB5C55DF06F4C52C9DE2BCBF695581718\
+    3995497CEA956AE515D2261898FA051015728E5A8AAAC42DAD33170D04507A33\
+    A85521ABDF1CBA64ECFB850458DBEF0A8AEA71575D060C7DB3970F85A6E1E4C7\
+    ABF5AE8CDB0933D71E8C94E04A25619DCEE3D2261AD2EE6BF12FFA06D98A0864\
+    D87602733EC86A64521F2B18177B200CBBE117577A615D6C770988C0BAD946E2\
+    08E24FA074E5AB3143DB5BFCE0FD108E4B82D120A93AD2CAFFFFFFFFFFFFFFFF";
+const SRP_G: u8 = 5;
+const SRP_LEN: usize = 384;
+const SRP_USERNAME: &str = "Pair-Setup";
+
+static SRP_GROUP: LazyLock<BigUint> =
+    LazyLock::new(|| BigUint::parse_bytes(SRP_N.as_bytes(), 16).expect("SRP_N is hex"));
+
+/// The bridge's long-term identity. It must stay the same for paired controllers to
+/// recognise the bridge.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Identity {
+    /// Looks like a MAC address, e.g. `1A:2B:3C:4D:5E:6F`.
+    pub device_id: String,
+    /// Four characters that tie a setup QR code to this bridge.
+    pub setup_id: String,
+    #[serde(with = "hex")]
+    pub secret_key: Vec<u8>,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        let mut bytes = [0u8; 6];
+        OsRng.fill_bytes(&mut bytes);
+        let device_id = bytes
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<_>>()
+            .join(":");
+
+        const ALPHABET: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+        let setup_id = (0..4)
+            .map(|_| ALPHABET[OsRng.next_u32() as usize % ALPHABET.len()] as char)
+            .collect();
+
+        let mut secret_key = vec![0u8; 32];
+        OsRng.fill_bytes(&mut secret_key);
+
+        Identity {
+            device_id,
+            setup_id,
+            secret_key,
+        }
+    }
+
+    fn signing_key(&self) -> Result<SigningKey, String> {
+        let seed: [u8; 32] = self
+            .secret_key
+            .as_slice()
+            .try_into()
+            .map_err(|_| "Invalid HomeKit identity key".to_string())?;
+
+        Ok(SigningKey::from_bytes(&seed))
+    }
+}
+
+/// A controller (an iPhone, a home hub) allowed to talk to the bridge.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pairing {
+    pub id: String,
+    #[serde(with = "hex")]
+    pub public_key: Vec<u8>,
+    pub admin: bool,
+}
+
+/// A setup code such as `123-45-678`. Codes with all digits equal and the two sequences
+/// are rejected by controllers.
+pub fn is_valid_setup_code(code: &str) -> bool {
+    let digits: Vec<u8> = code.bytes().filter(|b| *b != b'-').collect();
+    let shaped = code.len() == 10
+        && code.as_bytes()[3] == b'-'
+        && code.as_bytes()[6] == b'-'
+        && digits.len() == 8
+        && digits.iter().all(u8::is_ascii_digit);
+
+    shaped
+        && !digits.iter().all(|d| *d == digits[0])
+        && digits != b"12345678"
+        && digits != b"87654321"
+}
+
+pub fn generate_setup_code() -> String {
+    loop {
+        let digits: String = (0..8)
+            .map(|_| char::from(b'0' + (OsRng.next_u32() % 10) as u8))
+            .collect();
+        let code = format!("{}-{}-{}", &digits[..3], &digits[3..5], &digits[5..]);
+
+        if is_valid_setup_code(&code) {
+            return code;
+        }
+    }
+}
+
+/// The `X-HM://` payload of the setup QR code.
+pub fn setup_uri(setup_code: &str, setup_id: &str) -> String {
+    let code: u64 = setup_code.replace('-', "").parse().unwrap_or(0);
+    // Version 0, category, flags (2 = supports IP) and the code, in base 36.
+    let mut payload = (CATEGORY_BRIDGE << 31) | (2 << 27) | code;
+
+    let mut encoded = Vec::new();
+    while payload > 0 {
+        encoded.push(char::from_digit((payload % 36) as u32, 36).unwrap_or('0'));
+        payload /= 36;
+    }
+    let encoded: String = encoded.iter().rev().collect();
+
+    format!("X-HM://{:0>9}{}", encoded.to_uppercase(), setup_id)
+}
+
+/// Lets controllers match a scanned setup code to the advertisement.
+fn setup_hash(setup_id: &str, device_id: &str) -> String {
+    let digest = Sha512::digest(format!("{}{}", setup_id, device_id));
+
+    base64::engine::general_purpose::STANDARD.encode(&digest[..4])
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Bool,
+    Uint32,
+    Int,
+    Float,
+    String,
+}
+
+impl Format {
+    fn name(self) -> &'static str {
+        match self {
+            Format::Bool => "bool",
+            Format::Uint32 => "uint32",
+            Format::Int => "int",
+            Format::Float => "float",
+            Format::String => "string",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Characteristic {
+    iid: u64,
+    kind: &'static str,
+    format: Format,
+    perms: u8,
+    value: Value,
+    unit: Option<&'static str>,
+    range: Option<(f64, f64, f64)>,
+}
+
+impl Characteristic {
+    pub fn new(kind: &'static str, format: Format, perms: u8, value: Value) -> Self {
+        Characteristic {
+            iid: 0,
+            kind,
+            format,
+            perms,
+            value,
+            unit: None,
+            range: None,
+        }
+    }
+
+    pub fn unit(mut self, unit: &'static str) -> Self {
+        self.unit = Some(unit);
+        self
+    }
+
+    pub fn range(mut self, min: f64, max: f64, step: f64) -> Self {
+        self.range = Some((min, max, step));
+        self
+    }
+
+    /// `value` in this characteristic's format and range, or `None` if it doesn't fit.
+    fn coerce(&self, value: &Value) -> Option<Value> {
+        let clamp = |n: f64| match self.range {
+            Some((min, max, _)) => n.clamp(min, max),
+            None => n,
+        };
+
+        match self.format {
+            Format::Bool => match value {
+                Value::Bool(b) => Some(json!(b)),
+                Value::Number(n) => Some(json!(n.as_f64()? != 0.0)),
+                _ => None,
+            },
+            Format::Uint32 | Format::Int => {
+                let n = match value {
+                    Value::Bool(b) => *b as u8 as f64,
+                    value => value.as_f64()?,
+                };
+                Some(json!(clamp(n).round() as i64))
+            }
+            Format::Float => Some(json!(clamp(value.as_f64()?))),
+            Format::String => value.as_str().map(|s| json!(s)),
+        }
+    }
+
+    fn to_json(&self, aid: u64) -> Value {
+        let mut perms = Vec::new();
+        if self.perms & READ != 0 {
+            perms.push("pr");
+        }
+        if self.perms & WRITE != 0 {
+            perms.push("pw");
+        }
+        if self.perms & NOTIFY != 0 {
+            perms.push("ev");
+        }
+
+        let mut object = json!({
+            "aid": aid,
+            "iid": self.iid,
+            "type": self.kind,
+            "perms": perms,
+            "format": self.format.name(),
+        });
+        if self.perms & READ != 0 {
+            object["value"] = self.value.clone();
+        }
+        if let Some(unit) = self.unit {
+            object["unit"] = json!(unit);
+        }
+        if let Some((min, max, step)) = self.range {
+            object["minValue"] = json!(min);
+            object["maxValue"] = json!(max);
+            object["minStep"] = json!(step);
+        }
+
+        object
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Service {
+    iid: u64,
+    kind: &'static str,
+    primary: bool,
+    characteristics: Vec<Characteristic>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Accessory {
+    pub aid: u64,
+    reachable: bool,
+    services: Vec<Service>,
+}
+
+impl Accessory {
+    /// An accessory with only its information service.
+    pub fn new(aid: u64, name: &str, manufacturer: &str, model: &str, serial: &str) -> Self {
+        let text =
+            |kind, value: &str| Characteristic::new(kind, Format::String, READ, json!(value));
+
+        Accessory {
+            aid,
+            reachable: true,
+            services: Vec::new(),
+        }
+        .with_service(
+            ACCESSORY_INFORMATION,
+            vec![
+                Characteristic::new(IDENTIFY, Format::Bool, WRITE, Value::Null),
+                text(MANUFACTURER, manufacturer),
+                text(MODEL, model),
+                text(NAME, name),
+                text(SERIAL_NUMBER, serial),
+                text(FIRMWARE_REVISION, env!("CARGO_PKG_VERSION")),
+            ],
+        )
+    }
+
+    /// Adds a service, numbering it and its characteristics after the existing ones. The
+    /// first service after the information service is the primary one.
+    pub fn with_service(
+        mut self,
+        kind: &'static str,
+        characteristics: Vec<Characteristic>,
+    ) -> Self {
+        let mut iid = self
+            .services
+            .iter()
+            .flat_map(|s| std::iter::once(s.iid).chain(s.characteristics.iter().map(|c| c.iid)))
+            .max()
+            .unwrap_or(0)
+            + 1;
+        let primary = self.services.len() == 1;
+
+        let service_iid = iid;
+        let characteristics = characteristics
+            .into_iter()
+            .map(|mut c| {
+                iid += 1;
+                c.iid = iid;
+                c
+            })
+            .collect();
+
+        self.services.push(Service {
+            iid: service_iid,
+            kind,
+            primary,
+            characteristics,
+        });
+        self
+    }
+
+    fn characteristics(&self) -> impl Iterator<Item = &Characteristic> {
+        self.services.iter().flat_map(|s| s.characteristics.iter())
+    }
+
+    fn characteristic_mut(&mut self, iid: u64) -> Option<&mut Characteristic> {
+        self.services
+            .iter_mut()
+            .flat_map(|s| s.characteristics.iter_mut())
+            .find(|c| c.iid == iid)
+    }
+
+    fn to_json(&self) -> Value {
+        let services: Vec<Value> = self
+            .services
+            .iter()
+            .map(|s| {
+                json!({
+                    "iid": s.iid,
+                    "type": s.kind,
+                    "primary": s.primary,
+                    "characteristics": s
+                        .characteristics
+                        .iter()
+                        .map(|c| {
+                            let mut object = c.to_json(self.aid);
+                            if let Some(object) = object.as_object_mut() {
+                                object.remove("aid");
+                            }
+                            object
+                        })
+                        .collect::<Vec<_>>(),
+                })
+            })
+            .collect();
+
+        json!({ "aid": self.aid, "services": services })
+    }
+}
+
+/// Identifies the shape of the accessory database, not its values. Controllers must be
+/// told, through the configuration number, whenever it changes.
+pub fn fingerprint(accessories: &[Accessory]) -> String {
+    let mut hasher = Sha512::new();
+    for accessory in accessories {
+        hasher.update(accessory.aid.to_be_bytes());
+        for service in &accessory.services {
+            hasher.update(service.kind);
+            for characteristic in &service.characteristics {
+                hasher.update(characteristic.kind);
+                hasher.update([characteristic.perms]);
+            }
+        }
+    }
+
+    hex::encode(&hasher.finalize()[..16])
+}
+
+/// What the bridge hands back to its owner.
+pub trait Delegate: Send + Sync + 'static {
+    /// Pairings changed and should be saved.
+    fn pairings_changed(&self, pairings: &[Pairing]);
+    /// A pair setup failed. The count should be saved and passed back to `Server::new`, so
+    /// a restart doesn't reset the limit on attempts.
+    fn setup_failures_changed(&self, failures: u32);
+    /// A controller wrote values, by characteristic type, to an accessory.
+    fn write(&self, aid: u64, values: Vec<(&'static str, Value)>);
+}
+
+pub struct Config {
+    pub name: String,
+    pub model: String,
+    pub manufacturer: String,
+    pub port: u16,
+    pub setup_code: String,
+}
+
+fn tlv_encode(items: &[(u8, &[u8])]) -> Vec<u8> {
+    let mut out = Vec::new();
+
+    for (kind, value) in items {
+        if value.is_empty() {
+            out.extend([*kind, 0]);
+        }
+        // Values longer than 255 bytes are split over consecutive items.
+        for chunk in value.chunks(255) {
+            out.extend([*kind, chunk.len() as u8]);
+            out.extend_from_slice(chunk);
+        }
+    }
+
+    out
+}
+
+fn tlv_decode(data: &[u8]) -> HashMap<u8, Vec<u8>> {
+    let mut items: HashMap<u8, Vec<u8>> = HashMap::new();
+    let mut rest = data;
+
+    while let [kind, len, tail @ ..] = rest {
+        let len = (*len as usize).min(tail.len());
+        items
+            .entry(*kind)
+            .or_default()
+            .extend_from_slice(&tail[..len]);
+        rest = &tail[len..];
+    }
+
+    items
+}
+
+fn tlv_error(state: u8, error: u8) -> Vec<u8> {
+    tlv_encode(&[(TLV_STATE, &[state]), (TLV_ERROR, &[error])])
+}
+
+fn hkdf(secret: &[u8], salt: &str, info: &str) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    Hkdf::<Sha512>::new(Some(salt.as_bytes()), secret)
+        .expand(info.as_bytes(), &mut key)
+        .expect("32 bytes is a valid HKDF-SHA512 length");
+
+    key
+}
+
+/// A 96-bit nonce made of four zero bytes and `suffix`.
+fn nonce(suffix: &[u8; 8]) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(suffix);
+    nonce
+}
+
+fn seal(key: &[u8; 32], nonce: &[u8; 12], msg: &[u8], aad: &[u8]) -> Vec<u8> {
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .encrypt(Nonce::from_slice(nonce), Payload { msg, aad })
+        .expect("encrypting into a Vec can't fail")
+}
+
+fn open(key: &[u8; 32], nonce: &[u8; 12], msg: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .decrypt(Nonce::from_slice(nonce), Payload { msg, aad })
+        .ok()
+}
+
+fn verify_signature(public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    let Ok(public_key) = <[u8; 32]>::try_from(public_key) else {
+        return false;
+    };
+    let (Ok(key), Ok(signature)) = (
+        VerifyingKey::from_bytes(&public_key),
+        Signature::from_slice(signature),
+    ) else {
+        return false;
+    };
+
+    key.verify(message, &signature).is_ok()
+}
+
+// Compares in constant time so proofs can't be guessed from response times.
+fn same_bytes(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn sha512(parts: &[&[u8]]) -> Vec<u8> {
+    let mut hasher = Sha512::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().to_vec()
+}
+
+/// Big-endian bytes left-padded to the size of the group.
+fn srp_pad(n: &BigUint) -> Vec<u8> {
+    let bytes = n.to_bytes_be();
+    let mut padded = vec![0u8; SRP_LEN.saturating_sub(bytes.len())];
+    padded.extend(bytes);
+    padded
+}
+
+/// The accessory's side of an SRP-6a exchange, with the setup code as the password.
+struct Srp {
+    username: &'static str,
+    salt: [u8; 16],
+    verifier: BigUint,
+    private: BigUint,
+    public: BigUint,
+}
+
+impl Srp {
+    fn new(setup_code: &str) -> Self {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let mut private = [0u8; 32];
+        OsRng.fill_bytes(&mut private);
+
+        Srp::with_keys(SRP_USERNAME, setup_code, salt, &private)
+    }
+
+    fn with_keys(username: &'static str, password: &str, salt: [u8; 16], private: &[u8]) -> Self {
+        let n = &*SRP_GROUP;
+        let g = BigUint::from(SRP_G);
+
+        let credentials = sha512(&[format!("{}:{}", username, password).as_bytes()]);
+        let x = BigUint::from_bytes_be(&sha512(&[&salt, &credentials]));
+        let verifier = g.modpow(&x, n);
+
+        let k = BigUint::from_bytes_be(&sha512(&[&n.to_bytes_be(), &srp_pad(&g)]));
+        let private = BigUint::from_bytes_be(private);
+        let public = (k * &verifier + g.modpow(&private, n)) % n;
+
+        Srp {
+            username,
+            salt,
+            verifier,
+            private,
+            public,
+        }
+    }
+
+    /// Checks the controller's proof. Returns the shared secret and the accessory's proof.
+    fn verify(&self, public: &[u8], proof: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+        let n = &*SRP_GROUP;
+        let a = BigUint::from_bytes_be(public);
+        if (&a % n) == BigUint::ZERO {
+            return None;
+        }
+
+        let u = BigUint::from_bytes_be(&sha512(&[&srp_pad(&a), &srp_pad(&self.public)]));
+        let shared = (&a * self.verifier.modpow(&u, n)).modpow(&self.private, n);
+        let key = sha512(&[&shared.to_bytes_be()]);
+
+        let group: Vec<u8> = sha512(&[&n.to_bytes_be()])
+            .iter()
+            .zip(sha512(&[&[SRP_G]]))
+            .map(|(x, y)| x ^ y)
+            .collect();
+        let expected = sha512(&[
+            &group,
+            &sha512(&[self.username.as_bytes()]),
+            &self.salt,
+            &a.to_bytes_be(),
+            &self.public.to_bytes_be(),
+            &key,
+        ]);
+        if !same_bytes(&expected, proof) {
+            return None;
+        }
+
+        let accessory_proof = sha512(&[&a.to_bytes_be(), proof, &key]);
+        Some((key, accessory_proof))
+    }
+}
+
+#[derive(Default)]
+struct Setup {
+    /// The session running the setup, and when it started.
+    owner: Option<(u64, Instant)>,
+    srp: Option<Srp>,
+    key: Option<Vec<u8>>,
+    failures: u32,
+}
+
+impl Setup {
+    fn is_owned_by(&self, session: u64) -> bool {
+        self.owner.is_some_and(|(owner, _)| owner == session)
+    }
+
+    /// Whether a session other than `session` is in the middle of a setup.
+    fn is_busy(&self, session: u64) -> bool {
+        self.owner
+            .is_some_and(|(owner, at)| owner != session && at.elapsed() < SETUP_TIMEOUT)
+    }
+
+    /// Ends the setup run by `session`, if any.
+    fn release(&mut self, session: u64) {
+        if self.is_owned_by(session) {
+            self.owner = None;
+            self.srp = None;
+            self.key = None;
+        }
+    }
+}
+
+/// Keys of a verified connection and the number of frames sent each way.
+struct SessionKeys {
+    incoming: [u8; 32],
+    outgoing: [u8; 32],
+    received: u64,
+    sent: u64,
+}
+
+impl SessionKeys {
+    fn encrypt(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        for chunk in data.chunks(MAX_FRAME_LEN) {
+            let aad = (chunk.len() as u16).to_le_bytes();
+            out.extend(aad);
+            out.extend(seal(
+                &self.outgoing,
+                &nonce(&self.sent.to_le_bytes()),
+                chunk,
+                &aad,
+            ));
+            self.sent += 1;
+        }
+
+        out
+    }
+
+    /// Takes one whole frame off `buffer`, if it has one.
+    fn decrypt(&mut self, buffer: &mut Vec<u8>) -> Result<Option<Vec<u8>>, String> {
+        let Some(aad) = buffer.get(..2) else {
+            return Ok(None);
+        };
+        let aad = [aad[0], aad[1]];
+        let len = u16::from_le_bytes(aad) as usize;
+        if len > MAX_FRAME_LEN {
+            return Err("Frame too long".to_string());
+        }
+        if buffer.len() < 2 + len + TAG_LEN {
+            return Ok(None);
+        }
+
+        let frame: Vec<u8> = buffer.drain(..2 + len + TAG_LEN).skip(2).collect();
+        let plain = open(
+            &self.incoming,
+            &nonce(&self.received.to_le_bytes()),
+            &frame,
+            &aad,
+        )
+        .ok_or("Failed to decrypt frame")?;
+        self.received += 1;
+
+        Ok(Some(plain))
+    }
+}
+
+/// Pair verify between its two requests.
+struct PendingVerify {
+    shared: [u8; 32],
+    public: [u8; 32],
+    controller_public: [u8; 32],
+    key: [u8; 32],
+}
+
+struct Connection {
+    stream: TcpStream,
+    received: Vec<u8>,
+    plain: Vec<u8>,
+    keys: Option<SessionKeys>,
+    verify: Option<PendingVerify>,
+    /// Keys and controller of a pair verify that succeeded, used once its reply is sent.
+    verified: Option<(SessionKeys, String)>,
+    controller: Option<String>,
+}
+
+impl Connection {
+    async fn send(&mut self, data: &[u8]) -> Result<(), String> {
+        let data = match &mut self.keys {
+            Some(keys) => keys.encrypt(data),
+            None => data.to_vec(),
+        };
+
+        self.stream
+            .write_all(&data)
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+/// Takes one whole HTTP request off `buffer`, if it has one.
+fn take_request(buffer: &mut Vec<u8>) -> Result<Option<Request>, String> {
+    let Some(end) = buffer.windows(4).position(|w| w == b"\r\n\r\n") else {
+        return if buffer.len() > MAX_REQUEST_LEN {
+            Err("Request too long".to_string())
+        } else {
+            Ok(None)
+        };
+    };
+
+    let head = std::str::from_utf8(&buffer[..end]).map_err(|e| e.to_string())?;
+    let mut lines = head.split("\r\n");
+    let mut start = lines.next().unwrap_or_default().split(' ');
+    let method = start.next().unwrap_or_default().to_string();
+    let path = start.next().unwrap_or_default().to_string();
+    let length = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+
+    if length > MAX_REQUEST_LEN {
+        return Err("Request too long".to_string());
+    }
+    if buffer.len() < end + 4 + length {
+        return Ok(None);
+    }
+
+    let body = buffer[end + 4..end + 4 + length].to_vec();
+    buffer.drain(..end + 4 + length);
+
+    Ok(Some(Request { method, path, body }))
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn tlv(body: Vec<u8>) -> Self {
+        Response {
+            status: 200,
+            content_type: "application/pairing+tlv8",
+            body,
+        }
+    }
+
+    fn json(status: u16, body: Value) -> Self {
+        Response {
+            status,
+            content_type: "application/hap+json",
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    fn empty(status: u16) -> Self {
+        Response {
+            status,
+            content_type: "",
+            body: Vec::new(),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let reason = match self.status {
+            200 => "OK",
+            204 => "No Content",
+            207 => "Multi-Status",
+            400 => "Bad Request",
+            404 => "Not Found",
+            470 => "Connection Authorization Required",
+            _ => "Internal Server Error",
+        };
+
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason);
+        if !self.content_type.is_empty() {
+            head.push_str(&format!("Content-Type: {}\r\n", self.content_type));
+        }
+        head.push_str(&format!("Content-Length: {}\r\n\r\n", self.body.len()));
+
+        let mut bytes = head.into_bytes();
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+}
+
+fn event_bytes(body: &Value) -> Vec<u8> {
+    let body = body.to_string();
+    format!(
+        "EVENT/1.0 200 OK\r\nContent-Type: application/hap+json\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+enum Outgoing {
+    Event(Vec<u8>),
+    Close,
+}
+
+struct Session {
+    controller: Option<String>,
+    sender: mpsc::UnboundedSender<Outgoing>,
+    /// Characteristics the controller asked to be notified about, as `(aid, iid)`.
+    events: HashSet<(u64, u64)>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WriteRequest {
+    characteristics: Vec<Write>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Write {
+    aid: u64,
+    iid: u64,
+    value: Option<Value>,
+    ev: Option<bool>,
+}
+
+pub struct Server {
+    config: Config,
+    identity: Identity,
+    signing_key: SigningKey,
+    config_number: AtomicU64,
+    accessories: Mutex<Vec<Accessory>>,
+    pairings: Mutex<Vec<Pairing>>,
+    setup: Mutex<Setup>,
+    sessions: Mutex<HashMap<u64, Session>>,
+    next_session: AtomicU64,
+    mdns: Mutex<Option<ServiceDaemon>>,
+    delegate: Box<dyn Delegate>,
+}
+
+impl Server {
+    pub fn new(
+        config: Config,
+        identity: Identity,
+        pairings: Vec<Pairing>,
+        setup_failures: u32,
+        delegate: impl Delegate,
+    ) -> Result<Self, String> {
+        if !is_valid_setup_code(&config.setup_code) {
+            return Err("Invalid HomeKit setup code".to_string());
+        }
+
+        let bridge = Accessory::new(
+            BRIDGE_AID,
+            &config.name,
+            &config.manufacturer,
+            &config.model,
+            &identity.device_id,
+        )
+        .with_service(
+            PROTOCOL_INFORMATION,
+            vec![Characteristic::new(
+                VERSION,
+                Format::String,
+                READ,
+                json!("1.1.0"),
+            )],
+        );
+
+        Ok(Server {
+            signing_key: identity.signing_key()?,
+            config,
+            identity,
+            config_number: AtomicU64::new(1),
+            accessories: Mutex::new(vec![bridge]),
+            pairings: Mutex::new(pairings),
+            setup: Mutex::new(Setup {
+                failures: setup_failures,
+                ..Setup::default()
+            }),
+            sessions: Mutex::new(HashMap::new()),
+            next_session: AtomicU64::new(0),
+            mdns: Mutex::new(None),
+            delegate: Box::new(delegate),
+        })
+    }
+
+    pub fn is_paired(&self) -> bool {
+        !self.pairings.lock().unwrap().is_empty()
+    }
+
+    /// Replaces the bridged accessories (everything but the bridge itself). Changing them
+    /// needs a new, higher configuration number so controllers fetch them again.
+    pub fn set_accessories(&self, accessories: Vec<Accessory>, config_number: u64) {
+        {
+            let mut current = self.accessories.lock().unwrap();
+            current.truncate(1);
+            current.extend(accessories.into_iter().filter(|a| a.aid != BRIDGE_AID));
+        }
+
+        let previous = self.config_number.swap(config_number, Ordering::SeqCst);
+        if previous != config_number {
+            self.advertise();
+        }
+    }
+
+    pub fn value(&self, aid: u64, kind: &str) -> Option<Value> {
+        let accessories = self.accessories.lock().unwrap();
+        let accessory = accessories.iter().find(|a| a.aid == aid)?;
+        let characteristic = accessory.characteristics().find(|c| c.kind == kind)?;
+
+        Some(characteristic.value.clone())
+    }
+
+    /// Updates a value the accessory reports, notifying controllers if it changed.
+    /// Accessories without a characteristic of that type ignore it.
+    pub fn set_value(&self, aid: u64, kind: &str, value: Value) {
+        let changed = {
+            let mut accessories = self.accessories.lock().unwrap();
+            let characteristic = accessories.iter_mut().find(|a| a.aid == aid).and_then(|a| {
+                a.services
+                    .iter_mut()
+                    .flat_map(|s| s.characteristics.iter_mut())
+                    .find(|c| c.kind == kind)
+            });
+
+            match characteristic.and_then(|c| Some((c.coerce(&value)?, c))) {
+                Some((value, c)) if c.value != value => {
+                    c.value = value.clone();
+                    Some((c.iid, value))
+                }
+                _ => None,
+            }
+        };
+
+        if let Some((iid, value)) = changed {
+            self.notify(None, &[(aid, iid, value)]);
+        }
+    }
+
+    /// Marks an accessory as (un)reachable. Reads and writes of an unreachable accessory
+    /// fail, which controllers show as "No Response".
+    pub fn set_reachable(&self, aid: u64, reachable: bool) {
+        if let Some(accessory) = self
+            .accessories
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .find(|a| a.aid == aid)
+        {
+            accessory.reachable = reachable;
+        }
+    }
+
+    /// Sends the changed values to every session that subscribed to them, except the one
+    /// that made the change.
+    fn notify(&self, except: Option<u64>, changes: &[(u64, u64, Value)]) {
+        let sessions = self.sessions.lock().unwrap();
+
+        for (id, session) in sessions.iter().filter(|(id, _)| Some(**id) != except) {
+            let characteristics: Vec<Value> = changes
+                .iter()
+                .filter(|(aid, iid, _)| session.events.contains(&(*aid, *iid)))
+                .map(|(aid, iid, value)| json!({ "aid": aid, "iid": iid, "value": value }))
+                .collect();
+
+            if !characteristics.is_empty() {
+                let body = json!({ "characteristics": characteristics });
+                if session
+                    .sender
+                    .send(Outgoing::Event(event_bytes(&body)))
+                    .is_err()
+                {
+                    eprintln!("HomeKit session {} is gone", id);
+                }
+            }
+        }
+    }
+
+    /// Announces the bridge over mDNS, or updates the announcement.
+    fn advertise(&self) {
+        let mdns = self.mdns.lock().unwrap();
+        let Some(daemon) = mdns.as_ref() else {
+            return;
+        };
+
+        let config_number = self.config_number.load(Ordering::SeqCst).to_string();
+        let paired = self.is_paired();
+        let setup_hash = setup_hash(&self.identity.setup_id, &self.identity.device_id);
+        let properties = [
+            ("c#", config_number.as_str()),
+            ("ff", "0"),
+            ("id", self.identity.device_id.as_str()),
+            ("md", self.config.model.as_str()),
+            ("pv", "1.1"),
+            ("s#", "1"),
+            ("sf", if paired { "0" } else { "1" }),
+            ("ci", "2"),
+            ("sh", setup_hash.as_str()),
+        ];
+        let host = format!("{}.local.", self.identity.device_id.replace(':', ""));
+
+        let registered = ServiceInfo::new(
+            SERVICE_TYPE,
+            &self.config.name,
+            &host,
+            "",
+            self.config.port,
+            &properties[..],
+        )
+        .map_err(|e| e.to_string())
+        .and_then(|info| {
+            daemon
+                .register(info.enable_addr_auto())
+                .map_err(|e| e.to_string())
+        });
+
+        if let Err(e) = registered {
+            eprintln!("Failed to advertise the HomeKit bridge: {}", e);
+        }
+    }
+
+    /// Listens for controllers until the task is dropped. Call `stop` as well to close the
+    /// open connections and the advertisement.
+    pub async fn run(self: Arc<Self>) -> Result<(), String> {
+        let listener = TcpListener::bind(("0.0.0.0", self.config.port))
+            .await
+            .map_err(|e| format!("Failed to listen on port {}: {}", self.config.port, e))?;
+
+        match ServiceDaemon::new() {
+            Ok(daemon) => *self.mdns.lock().unwrap() = Some(daemon),
+            Err(e) => eprintln!("mDNS unavailable, the bridge won't be found: {}", e),
+        }
+        self.advertise();
+
+        loop {
+            let (stream, _) = listener.accept().await.map_err(|e| e.to_string())?;
+            let _ = stream.set_nodelay(true);
+
+            tokio::spawn(self.clone().serve(stream));
+        }
+    }
+
+    pub fn stop(&self) {
+        if let Some(daemon) = self.mdns.lock().unwrap().take() {
+            let _ = daemon.shutdown();
+        }
+
+        for session in self.sessions.lock().unwrap().values() {
+            let _ = session.sender.send(Outgoing::Close);
+        }
+    }
+
+    async fn serve(self: Arc<Self>, stream: TcpStream) {
+        let id = self.next_session.fetch_add(1, Ordering::SeqCst);
+        let (sender, mut outgoing) = mpsc::unbounded_channel();
+        self.sessions.lock().unwrap().insert(
+            id,
+            Session {
+                controller: None,
+                sender,
+                events: HashSet::new(),
+            },
+        );
+
+        let mut connection = Connection {
+            stream,
+            received: Vec::new(),
+            plain: Vec::new(),
+            keys: None,
+            verify: None,
+            verified: None,
+            controller: None,
+        };
+        let mut chunk = [0u8; 4096];
+
+        loop {
+            let result = tokio::select! {
+                read = connection.stream.read(&mut chunk) => match read {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => self.receive(id, &mut connection, &chunk[..n]).await,
+                },
+                message = outgoing.recv() => match message {
+                    // Events only go out on verified connections.
+                    Some(Outgoing::Event(event)) if connection.keys.is_some() => {
+                        connection.send(&event).await
+                    }
+                    Some(Outgoing::Event(_)) => Ok(()),
+                    Some(Outgoing::Close) | None => break,
+                },
+            };
+
+            if result.is_err() {
+                break;
+            }
+        }
+
+        self.sessions.lock().unwrap().remove(&id);
+        self.setup.lock().unwrap().release(id);
+    }
+
+    async fn receive(
+        &self,
+        session: u64,
+        connection: &mut Connection,
+        data: &[u8],
+    ) -> Result<(), String> {
+        match &mut connection.keys {
+            Some(keys) => {
+                connection.received.extend_from_slice(data);
+                while let Some(plain) = keys.decrypt(&mut connection.received)? {
+                    connection.plain.extend(plain);
+                }
+            }
+            None => connection.plain.extend_from_slice(data),
+        }
+
+        while let Some(request) = take_request(&mut connection.plain)? {
+            let response = self.handle(session, connection, request);
+            connection.send(&response.to_bytes()).await?;
+
+            // The pair verify reply itself goes out in the clear.
+            if let Some((keys, controller)) = connection.verified.take() {
+                connection.keys = Some(keys);
+                connection.controller = Some(controller.clone());
+                if let Some(session) = self.sessions.lock().unwrap().get_mut(&session) {
+                    session.controller = Some(controller);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle(&self, session: u64, connection: &mut Connection, request: Request) -> Response {
+        let (path, query) = request
+            .path
+            .split_once('?')
+            .unwrap_or((request.path.as_str(), ""));
+
+        match (request.method.as_str(), path) {
+            ("POST", "/pair-setup") => Response::tlv(self.pair_setup(session, &request.body)),
+            ("POST", "/pair-verify") => Response::tlv(self.pair_verify(connection, &request.body)),
+            ("POST", "/identify") if !self.is_paired() => Response::empty(204),
+            ("POST", "/identify") => {
+                Response::json(400, json!({ "status": STATUS_INSUFFICIENT_PRIVILEGES }))
+            }
+            _ if connection.keys.is_none() => {
+                Response::json(470, json!({ "status": STATUS_INSUFFICIENT_PRIVILEGES }))
+            }
+            ("GET", "/accessories") => {
+                let accessories: Vec<Value> = self
+                    .accessories
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(Accessory::to_json)
+                    .collect();
+                Response::json(200, json!({ "accessories": accessories }))
+            }
+            ("GET", "/characteristics") => self.read(session, query),
+            ("PUT", "/characteristics") => self.write(session, &request.body),
+            ("POST", "/pairings") => Response::tlv(self.manage_pairings(connection, &request.body)),
+            _ => Response::empty(404),
+        }
+    }
+
+    /// Runs one step of pair setup. Only one session can run a setup at a time.
+    fn pair_setup(&self, session: u64, body: &[u8]) -> Vec<u8> {
+        let request = tlv_decode(body);
+        let state = request.get(&TLV_STATE).and_then(|s| s.first()).copied();
+        let mut setup = self.setup.lock().unwrap();
+
+        match state {
+            Some(1) => {
+                if self.is_paired() {
+                    return tlv_error(2, ERROR_UNAVAILABLE);
+                }
+                if setup.failures >= MAX_SETUP_ATTEMPTS {
+                    return tlv_error(2, ERROR_MAX_TRIES);
+                }
+                if setup.is_busy(session) {
+                    return tlv_error(2, ERROR_BUSY);
+                }
+
+                let srp = Srp::new(&self.config.setup_code);
+                let reply = tlv_encode(&[
+                    (TLV_STATE, &[2]),
+                    (TLV_SALT, &srp.salt),
+                    (TLV_PUBLIC_KEY, &srp.public.to_bytes_be()),
+                ]);
+                setup.owner = Some((session, Instant::now()));
+                setup.srp = Some(srp);
+                setup.key = None;
+                reply
+            }
+            _ if !setup.is_owned_by(session) => tlv_error(state.unwrap_or(0) + 1, ERROR_UNKNOWN),
+            Some(3) => {
+                let (Some(srp), Some(public), Some(proof)) = (
+                    setup.srp.take(),
+                    request.get(&TLV_PUBLIC_KEY),
+                    request.get(&TLV_PROOF),
+                ) else {
+                    setup.release(session);
+                    return tlv_error(4, ERROR_UNKNOWN);
+                };
+
+                match srp.verify(public, proof) {
+                    Some((key, proof)) => {
+                        setup.key = Some(key);
+                        tlv_encode(&[(TLV_STATE, &[4]), (TLV_PROOF, &proof)])
+                    }
+                    None => {
+                        setup.release(session);
+                        setup.failures += 1;
+                        self.delegate.setup_failures_changed(setup.failures);
+                        tlv_error(4, ERROR_AUTHENTICATION)
+                    }
+                }
+            }
+            Some(5) => {
+                let key = setup.key.take();
+                setup.release(session);
+                drop(setup);
+
+                let (Some(key), Some(encrypted)) = (key, request.get(&TLV_ENCRYPTED_DATA)) else {
+                    return tlv_error(6, ERROR_UNKNOWN);
+                };
+
+                self.exchange_keys(&key, encrypted)
+                    .unwrap_or_else(|error| tlv_error(6, error))
+            }
+            _ => tlv_error(state.unwrap_or(0) + 1, ERROR_UNKNOWN),
+        }
+    }
+
+    /// The last step of pair setup: checks the controller's long-term key, saves the
+    /// pairing and replies with the bridge's key.
+    fn exchange_keys(&self, srp_key: &[u8], encrypted: &[u8]) -> Result<Vec<u8>, u8> {
+        let key = hkdf(
+            srp_key,
+            "Pair-Setup-Encrypt-Salt",
+            "Pair-Setup-Encrypt-Info",
+        );
+        let plain = open(&key, &nonce(b"PS-Msg05"), encrypted, &[]).ok_or(ERROR_AUTHENTICATION)?;
+        let controller = tlv_decode(&plain);
+
+        let (Some(id), Some(public_key), Some(signature)) = (
+            controller.get(&TLV_IDENTIFIER),
+            controller.get(&TLV_PUBLIC_KEY),
+            controller.get(&TLV_SIGNATURE),
+        ) else {
+            return Err(ERROR_UNKNOWN);
+        };
+
+        let controller_x = hkdf(
+            srp_key,
+            "Pair-Setup-Controller-Sign-Salt",
+            "Pair-Setup-Controller-Sign-Info",
+        );
+        let info = [controller_x.as_slice(), id, public_key].concat();
+        if !verify_signature(public_key, &info, signature) {
+            return Err(ERROR_AUTHENTICATION);
+        }
+
+        let pairing = Pairing {
+            id: String::from_utf8_lossy(id).to_string(),
+            public_key: public_key.clone(),
+            admin: true,
+        };
+        {
+            let mut pairings = self.pairings.lock().unwrap();
+            pairings.retain(|p| p.id != pairing.id);
+            pairings.push(pairing);
+            self.delegate.pairings_changed(&pairings);
+        }
+        self.advertise();
+
+        let accessory_x = hkdf(
+            srp_key,
+            "Pair-Setup-Accessory-Sign-Salt",
+            "Pair-Setup-Accessory-Sign-Info",
+        );
+        let public_key = self.signing_key.verifying_key().to_bytes();
+        let info = [
+            accessory_x.as_slice(),
+            self.identity.device_id.as_bytes(),
+            &public_key,
+        ]
+        .concat();
+        let signature = self.signing_key.sign(&info).to_bytes();
+
+        let reply = tlv_encode(&[
+            (TLV_IDENTIFIER, self.identity.device_id.as_bytes()),
+            (TLV_PUBLIC_KEY, &public_key),
+            (TLV_SIGNATURE, &signature),
+        ]);
+        let encrypted = seal(&key, &nonce(b"PS-Msg06"), &reply, &[]);
+
+        Ok(tlv_encode(&[
+            (TLV_STATE, &[6]),
+            (TLV_ENCRYPTED_DATA, &encrypted),
+        ]))
+    }
+
+    fn pair_verify(&self, connection: &mut Connection, body: &[u8]) -> Vec<u8> {
+        let request = tlv_decode(body);
+
+        match request.get(&TLV_STATE).and_then(|s| s.first()) {
+            Some(1) => {
+                let Some(controller_public) = request
+                    .get(&TLV_PUBLIC_KEY)
+                    .and_then(|k| <[u8; 32]>::try_from(k.as_slice()).ok())
+                else {
+                    return tlv_error(2, ERROR_UNKNOWN);
+                };
+
+                let secret = EphemeralSecret::random_from_rng(OsRng);
+                let public = PublicKey::from(&secret).to_bytes();
+                let shared = secret
+                    .diffie_hellman(&PublicKey::from(controller_public))
+                    .to_bytes();
+
+                let info = [
+                    public.as_slice(),
+                    self.identity.device_id.as_bytes(),
+                    &controller_public,
+                ]
+                .concat();
+                let signature = self.signing_key.sign(&info).to_bytes();
+                let key = hkdf(
+                    &shared,
+                    "Pair-Verify-Encrypt-Salt",
+                    "Pair-Verify-Encrypt-Info",
+                );
+                let encrypted = seal(
+                    &key,
+                    &nonce(b"PV-Msg02"),
+                    &tlv_encode(&[
+                        (TLV_IDENTIFIER, self.identity.device_id.as_bytes()),
+                        (TLV_SIGNATURE, &signature),
+                    ]),
+                    &[],
+                );
+
+                connection.verify = Some(PendingVerify {
+                    shared,
+                    public,
+                    controller_public,
+                    key,
+                });
+                tlv_encode(&[
+                    (TLV_STATE, &[2]),
+                    (TLV_PUBLIC_KEY, &public),
+                    (TLV_ENCRYPTED_DATA, &encrypted),
+                ])
+            }
+            Some(3) => {
+                let (Some(pending), Some(encrypted)) =
+                    (connection.verify.take(), request.get(&TLV_ENCRYPTED_DATA))
+                else {
+                    return tlv_error(4, ERROR_UNKNOWN);
+                };
+                let Some(plain) = open(&pending.key, &nonce(b"PV-Msg03"), encrypted, &[]) else {
+                    return tlv_error(4, ERROR_AUTHENTICATION);
+                };
+
+                let controller = tlv_decode(&plain);
+                let (Some(id), Some(signature)) = (
+                    controller.get(&TLV_IDENTIFIER),
+                    controller.get(&TLV_SIGNATURE),
+                ) else {
+                    return tlv_error(4, ERROR_UNKNOWN);
+                };
+                let id = String::from_utf8_lossy(id).to_string();
+                let Some(pairing) = self
+                    .pairings
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .find(|p| p.id == id)
+                    .cloned()
+                else {
+                    return tlv_error(4, ERROR_AUTHENTICATION);
+                };
+
+                let info = [
+                    pending.controller_public.as_slice(),
+                    id.as_bytes(),
+                    &pending.public,
+                ]
+                .concat();
+                if !verify_signature(&pairing.public_key, &info, signature) {
+                    return tlv_error(4, ERROR_AUTHENTICATION);
+                }
+
+                let keys = SessionKeys {
+                    incoming: hkdf(
+                        &pending.shared,
+                        "Control-Salt",
+                        "Control-Write-Encryption-Key",
+                    ),
+                    outgoing: hkdf(
+                        &pending.shared,
+                        "Control-Salt",
+                        "Control-Read-Encryption-Key",
+                    ),
+                    received: 0,
+                    sent: 0,
+                };
+                connection.verified = Some((keys, id));
+                tlv_encode(&[(TLV_STATE, &[4])])
+            }
+            state => tlv_error(state.map_or(0, |s| s + 1), ERROR_UNKNOWN),
+        }
+    }
+
+    fn manage_pairings(&self, connection: &Connection, body: &[u8]) -> Vec<u8> {
+        let request = tlv_decode(body);
+        let mut pairings = self.pairings.lock().unwrap();
+
+        let admin = pairings
+            .iter()
+            .any(|p| Some(&p.id) == connection.controller.as_ref() && p.admin);
+        if !admin {
+            return tlv_error(2, ERROR_AUTHENTICATION);
+        }
+
+        let id = request
+            .get(&TLV_IDENTIFIER)
+            .map(|id| String::from_utf8_lossy(id).to_string());
+
+        let reply = match request.get(&TLV_METHOD).and_then(|m| m.first()) {
+            Some(&METHOD_ADD_PAIRING) => {
+                let (Some(id), Some(public_key)) = (id, request.get(&TLV_PUBLIC_KEY)) else {
+                    return tlv_error(2, ERROR_UNKNOWN);
+                };
+                let admin = request
+                    .get(&TLV_PERMISSIONS)
+                    .and_then(|p| p.first())
+                    .is_some_and(|p| p & 1 == 1);
+
+                match pairings.iter_mut().find(|p| p.id == id) {
+                    Some(existing) if existing.public_key != *public_key => {
+                        return tlv_error(2, ERROR_UNKNOWN);
+                    }
+                    Some(existing) => existing.admin = admin,
+                    None => pairings.push(Pairing {
+                        id,
+                        public_key: public_key.clone(),
+                        admin,
+                    }),
+                }
+                tlv_encode(&[(TLV_STATE, &[2])])
+            }
+            Some(&METHOD_REMOVE_PAIRING) => {
+                let Some(id) = id else {
+                    return tlv_error(2, ERROR_UNKNOWN);
+                };
+                pairings.retain(|p| p.id != id);
+                // Without an admin nobody could manage the bridge any more.
+                if !pairings.iter().any(|p| p.admin) {
+                    pairings.clear();
+                }
+
+                // Removed controllers lose their connections once this reply is sent.
+                for session in self.sessions.lock().unwrap().values() {
+                    let removed = session
+                        .controller
+                        .as_ref()
+                        .is_some_and(|c| !pairings.iter().any(|p| &p.id == c));
+                    if removed {
+                        let _ = session.sender.send(Outgoing::Close);
+                    }
+                }
+                tlv_encode(&[(TLV_STATE, &[2])])
+            }
+            Some(&METHOD_LIST_PAIRINGS) => {
+                let mut reply = tlv_encode(&[(TLV_STATE, &[2])]);
+                for (i, pairing) in pairings.iter().enumerate() {
+                    if i > 0 {
+                        reply.extend(tlv_encode(&[(TLV_SEPARATOR, &[])]));
+                    }
+                    reply.extend(tlv_encode(&[
+                        (TLV_IDENTIFIER, pairing.id.as_bytes()),
+                        (TLV_PUBLIC_KEY, &pairing.public_key),
+                        (TLV_PERMISSIONS, &[pairing.admin as u8]),
+                    ]));
+                }
+                return reply;
+            }
+            _ => return tlv_error(2, ERROR_UNKNOWN),
+        };
+
+        self.delegate.pairings_changed(&pairings);
+        drop(pairings);
+        self.advertise();
+
+        reply
+    }
+
+    /// `GET /characteristics?id=1.10,2.10`, optionally with `meta`, `perms`, `type` and `ev`.
+    fn read(&self, session: u64, query: &str) -> Response {
+        let mut ids = Vec::new();
+        let mut flags = HashSet::new();
+        for (name, value) in query.split('&').filter_map(|p| p.split_once('=')) {
+            match name {
+                "id" => ids.extend(value.split(',').filter_map(|id| {
+                    let (aid, iid) = id.split_once('.')?;
+                    Some((aid.parse::<u64>().ok()?, iid.parse::<u64>().ok()?))
+                })),
+                _ if value == "1" => {
+                    flags.insert(name);
+                }
+                _ => {}
+            }
+        }
+
+        let subscribed = self
+            .sessions
+            .lock()
+            .unwrap()
+            .get(&session)
+            .map(|s| s.events.clone())
+            .unwrap_or_default();
+        let accessories = self.accessories.lock().unwrap();
+
+        let results: Vec<(Value, i64)> = ids
+            .iter()
+            .map(|&(aid, iid)| {
+                let mut result = json!({ "aid": aid, "iid": iid });
+                let accessory = accessories.iter().find(|a| a.aid == aid);
+                let characteristic =
+                    accessory.and_then(|a| a.characteristics().find(|c| c.iid == iid));
+
+                let status = match (accessory, characteristic) {
+                    (Some(accessory), Some(c)) if c.perms & READ != 0 && accessory.reachable => {
+                        let full = c.to_json(aid);
+                        result["value"] = c.value.clone();
+                        if flags.contains("meta") {
+                            for key in ["format", "unit", "minValue", "maxValue", "minStep"] {
+                                if let Some(value) = full.get(key) {
+                                    result[key] = value.clone();
+                                }
+                            }
+                        }
+                        if flags.contains("perms") {
+                            result["perms"] = full["perms"].clone();
+                        }
+                        if flags.contains("type") {
+                            result["type"] = json!(c.kind);
+                        }
+                        if flags.contains("ev") {
+                            result["ev"] = json!(subscribed.contains(&(aid, iid)));
+                        }
+                        STATUS_SUCCESS
+                    }
+                    (Some(_), Some(c)) if c.perms & READ == 0 => STATUS_WRITE_ONLY,
+                    (Some(_), Some(_)) => STATUS_COMMUNICATION_FAILURE,
+                    _ => STATUS_NOT_FOUND,
+                };
+
+                (result, status)
+            })
+            .collect();
+
+        multi_status(results, 200)
+    }
+
+    /// `PUT /characteristics`: writes values and (un)subscribes from events.
+    fn write(&self, session: u64, body: &[u8]) -> Response {
+        let Ok(request) = serde_json::from_slice::<WriteRequest>(body) else {
+            return Response::json(400, json!({ "status": STATUS_INVALID_VALUE }));
+        };
+
+        let mut changes = Vec::new();
+        let mut written: Vec<(u64, &'static str, Value)> = Vec::new();
+        let mut results = Vec::new();
+
+        {
+            let mut accessories = self.accessories.lock().unwrap();
+            let mut sessions = self.sessions.lock().unwrap();
+
+            for write in request.characteristics {
+                let accessory = accessories.iter_mut().find(|a| a.aid == write.aid);
+                let reachable = accessory.as_ref().is_some_and(|a| a.reachable);
+                let characteristic = accessory.and_then(|a| a.characteristic_mut(write.iid));
+
+                let status = match characteristic {
+                    None => STATUS_NOT_FOUND,
+                    Some(c) => {
+                        let mut status = STATUS_SUCCESS;
+
+                        if let Some(ev) = write.ev {
+                            if c.perms & NOTIFY == 0 {
+                                status = STATUS_NO_NOTIFICATION;
+                            } else if let Some(session) = sessions.get_mut(&session) {
+                                if ev {
+                                    session.events.insert((write.aid, write.iid));
+                                } else {
+                                    session.events.remove(&(write.aid, write.iid));
+                                }
+                            }
+                        }
+
+                        if let (Some(value), STATUS_SUCCESS) = (&write.value, status) {
+                            status = if c.perms & WRITE == 0 {
+                                STATUS_READ_ONLY
+                            } else if !reachable {
+                                STATUS_COMMUNICATION_FAILURE
+                            } else {
+                                match c.coerce(value) {
+                                    Some(value) => {
+                                        // Write-only characteristics such as Identify
+                                        // don't keep a value.
+                                        if c.perms & READ != 0 {
+                                            if c.value != value {
+                                                changes.push((write.aid, c.iid, value.clone()));
+                                            }
+                                            c.value = value.clone();
+                                        }
+                                        written.push((write.aid, c.kind, value));
+                                        STATUS_SUCCESS
+                                    }
+                                    None => STATUS_INVALID_VALUE,
+                                }
+                            };
+                        }
+
+                        status
+                    }
+                };
+
+                results.push((json!({ "aid": write.aid, "iid": write.iid }), status));
+            }
+        }
+
+        self.notify(Some(session), &changes);
+
+        let mut by_accessory: Vec<(u64, Vec<(&'static str, Value)>)> = Vec::new();
+        for (aid, kind, value) in written {
+            match by_accessory.iter_mut().find(|(a, _)| *a == aid) {
+                Some((_, values)) => values.push((kind, value)),
+                None => by_accessory.push((aid, vec![(kind, value)])),
+            }
+        }
+        for (aid, values) in by_accessory {
+            self.delegate.write(aid, values);
+        }
+
+        if results.iter().all(|(_, status)| *status == STATUS_SUCCESS) {
+            Response::empty(204)
+        } else {
+            multi_status(results, 207)
+        }
+    }
+}
+
+/// Replies with `ok_status` when everything succeeded, otherwise with 207 and the status
+/// of each characteristic.
+fn multi_status(results: Vec<(Value, i64)>, ok_status: u16) -> Response {
+    let failed = results.iter().any(|(_, status)| *status != STATUS_SUCCESS);
+    let characteristics: Vec<Value> = results
+        .into_iter()
+        .map(|(mut result, status)| {
+            if failed {
+                result["status"] = json!(status);
+            }
+            result
+        })
+        .collect();
+
+    Response::json(
+        if failed { 207 } else { ok_status },
+        json!({ "characteristics": characteristics }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The SRP test vector from the HAP specification: the inputs of RFC 5054 (user `alice`,
+    // password `password123`) with the 3072-bit group and SHA-512.
+    const SALT: &str = "BEB25379D1A8581EB5A727673A2441EE";
+    const PRIVATE_B: &str = "E487CB59D31AC550471E81F00F6928E01DDA08E974A004F49E61F5D105284D20";
+    const PUBLIC_A: &str = "\
+        FAB6F5D2615D1E323512E7991CC37443F487DA604CA8C9230FCB04E541DCE628\
+        0B27CA4680B0374F179DC3BDC7553FE62459798C701AD864A91390A28C93B644\
+        ADBF9C00745B942B79F9012A21B9B78782319D83A1F8362866FBD6F46BFC0DDB\
+        2E1AB6E4B45A9906B82E37F05D6F97F6A3EB6E182079759C4F6847837B62321A\
+        C1B4FA68641FCB4BB98DD697A0C73641385F4BAB25B793584CC39FC8D48D4BD8\
+        67A9A3C10F8EA12170268E34FE3BBE6FF89998D60DA2F3E4283CBEC1393D52AF\
+        724A57230C604E9FBCE583D7613E6BFFD67596AD121A8707EEC4694495703368\
+        6A155F644D5C5863B48F61BDBF19A53EAB6DAD0A186B8C152E5F5D8CAD4B0EF8\
+        AA4EA5008834C3CD342E5E0F167AD04592CD8BD279639398EF9E114DFAAAB919\
+        E14E850989224DDD98576D79385D2210902E9F9B1F2D86CFA47EE244635465F7\
+        1058421A0184BE51DD10CC9D079E6F1604E7AA9B7CF7883C7D4CE12B06EBE160\
+        81E23F27A231D18432D7D1BB55C28AE21FFCF005F57528D15A88881BB3BBB7FE";
+    const PUBLIC_B: &str = "\
+        40F57088A482D4C7733384FE0D301FDDCA9080AD7D4F6FDF09A01006C3CB6D56\
+        2E41639AE8FA21DE3B5DBA7585B275589BDB279863C562807B2B99083CD1429C\
+        DBE89E25BFBD7E3CAD3173B2E3C5A0B174DA6D5391E6A06E465F037A40062548\
+        39A56BF76DA84B1C94E0AE208576156FE5C140A4BA4FFC9E38C3B07B88845FC6\
+        F7DDDA93381FE0CA6084C4CD2D336E5451C464CCB6EC65E7D16E548A273E8262\
+        84AF2559B6264274215960FFF47BDD63D3AFF064D6137AF769661C9D4FEE4738\
+        2603C88EAA0980581D07758461B777E4356DDA5835198B51FEEA308D70F75450\
+        B71675C08C7D8302FD7539DD1FF2A11CB4258AA70D234436AA42B6A0615F3F91\
+        5D55CC3B966B2716B36E4D1A06CE5E5D2EA3BEE5A1270E8751DA45B60B997B0F\
+        FDB0F9962FEE4F03BEE780BA0A845B1D9271421783AE6601A61EA2E342E4F2E8\
+        BC935A409EAD19F221BD1B74E2964DD19FC845F60EFC09338B60B6B256D8CAC8\
+        89CCA306CC370A0B18C8B886E95DA0AF5235FEF4393020D2B7F3056904759042";
+    const SESSION_KEY: &str = "\
+        5CBC219DB052138EE1148C71CD4498963D682549CE91CA24F098468F06015BEB\
+        6AF245C2093F98C3651BCA83AB8CAB2B580BBF02184FEFDF26142F73DF95AC50";
+    const PROOF_M1: &str = "\
+        5F7C14AB57ED0E94FD1D78C6B4DD09ED7E340B7E05D419A9FD760F6B35E523D1\
+        310777A1AE1D2826F596F3A85116CC457C7C964D4F44DED5559DA818C88B617F";
+    const PROOF_M2: &str = "\
+        2FA0E81F5CB73B88FA0964270F321DD641F2227A5D805C40F1BFE96AAF6A19FF\
+        CE8E23287965A39EAB9D5A02215F89E128177ED2C4F103E655A045531BCBF7AD";
+
+    const SETUP_CODE: &str = "031-45-154";
+
+    fn unhex(text: &str) -> Vec<u8> {
+        hex::decode(text).unwrap()
+    }
+
+    #[test]
+    fn srp_matches_the_test_vector() {
+        let salt = unhex(SALT).try_into().unwrap();
+        let srp = Srp::with_keys("alice", "password123", salt, &unhex(PRIVATE_B));
+        assert_eq!(srp.public.to_bytes_be(), unhex(PUBLIC_B));
+
+        let (key, proof) = srp.verify(&unhex(PUBLIC_A), &unhex(PROOF_M1)).unwrap();
+        assert_eq!(key, unhex(SESSION_KEY));
+        assert_eq!(proof, unhex(PROOF_M2));
+    }
+
+    #[test]
+    fn srp_rejects_a_wrong_proof_or_public_key() {
+        let salt = unhex(SALT).try_into().unwrap();
+        let srp = Srp::with_keys("alice", "password123", salt, &unhex(PRIVATE_B));
+
+        let mut proof = unhex(PROOF_M1);
+        proof[0] ^= 1;
+        assert!(srp.verify(&unhex(PUBLIC_A), &proof).is_none());
+        assert!(srp
+            .verify(&SRP_GROUP.to_bytes_be(), &unhex(PROOF_M1))
+            .is_none());
+    }
+
+    #[test]
+    fn tlv_round_trips_long_and_empty_values() {
+        let long: Vec<u8> = (0..600).map(|i| i as u8).collect();
+        let encoded = tlv_encode(&[
+            (TLV_STATE, &[3]),
+            (TLV_PUBLIC_KEY, &long),
+            (TLV_SEPARATOR, &[]),
+            (TLV_PROOF, &[]),
+        ]);
+
+        // 600 bytes take three items of 255, 255 and 90 bytes.
+        assert_eq!(encoded.len(), 3 + (3 * 2 + 600) + 2 + 2);
+        assert_eq!(&encoded[3..5], &[TLV_PUBLIC_KEY, 255]);
+        assert_eq!(&encoded[260..262], &[TLV_PUBLIC_KEY, 255]);
+        assert_eq!(&encoded[517..519], &[TLV_PUBLIC_KEY, 90]);
+
+        let decoded = tlv_decode(&encoded);
+        assert_eq!(decoded[&TLV_STATE], [3]);
+        assert_eq!(decoded[&TLV_PUBLIC_KEY], long);
+        assert!(decoded[&TLV_SEPARATOR].is_empty());
+        assert!(decoded[&TLV_PROOF].is_empty());
+    }
+
+    #[test]
+    fn tlv_decode_stops_at_a_truncated_item() {
+        let decoded = tlv_decode(&[TLV_STATE, 1, 2, TLV_PROOF, 10, 1, 2]);
+
+        assert_eq!(decoded[&TLV_STATE], [2]);
+        assert_eq!(decoded[&TLV_PROOF], [1, 2]);
+    }
+
+    fn session_keys() -> (SessionKeys, SessionKeys) {
+        let (up, down) = ([1u8; 32], [2u8; 32]);
+        let keys = |incoming, outgoing| SessionKeys {
+            incoming,
+            outgoing,
+            received: 0,
+            sent: 0,
+        };
+
+        (keys(up, down), keys(down, up))
+    }
+
+    #[test]
+    fn session_frames_round_trip_with_counted_nonces() {
+        let (mut accessory, mut controller) = session_keys();
+        let message: Vec<u8> = (0..2500).map(|i| (i % 251) as u8).collect();
+
+        let mut wire = accessory.encrypt(&message);
+        assert_eq!(accessory.sent, 3);
+        assert_eq!(wire.len(), message.len() + 3 * (2 + TAG_LEN));
+
+        let mut partial = wire[..100].to_vec();
+        assert_eq!(controller.decrypt(&mut partial), Ok(None));
+        assert_eq!(partial.len(), 100);
+
+        let mut plain = Vec::new();
+        while let Some(frame) = controller.decrypt(&mut wire).unwrap() {
+            plain.extend(frame);
+        }
+        assert_eq!(plain, message);
+        assert!(wire.is_empty());
+        assert_eq!(controller.received, 3);
+
+        // A replayed first frame was sealed with nonce 0, but the controller expects 3.
+        let mut replayed = session_keys().0.encrypt(b"on");
+        assert!(controller.decrypt(&mut replayed).is_err());
+
+        let mut next = accessory.encrypt(b"on");
+        assert_eq!(controller.decrypt(&mut next), Ok(Some(b"on".to_vec())));
+        assert_eq!(controller.received, 4);
+    }
+
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<Pairing>>>, Arc<AtomicU64>);
+
+    impl Delegate for Recorder {
+        fn pairings_changed(&self, pairings: &[Pairing]) {
+            *self.0.lock().unwrap() = pairings.to_vec();
+        }
+
+        fn setup_failures_changed(&self, failures: u32) {
+            self.1.store(failures.into(), Ordering::SeqCst);
+        }
+
+        fn write(&self, _: u64, _: Vec<(&'static str, Value)>) {}
+    }
+
+    fn server(identity: Identity, setup_failures: u32, recorder: &Recorder) -> Server {
+        let config = Config {
+            name: "Test Bridge".to_string(),
+            model: "Test".to_string(),
+            manufacturer: "Test".to_string(),
+            port: 0,
+            setup_code: SETUP_CODE.to_string(),
+        };
+
+        Server::new(
+            config,
+            identity,
+            Vec::new(),
+            setup_failures,
+            recorder.clone(),
+        )
+        .unwrap()
+    }
+
+    /// The controller's side of SRP: its public key, proof and the shared key.
+    fn srp_client(setup_code: &str, salt: &[u8], server_public: &[u8]) -> [Vec<u8>; 3] {
+        let n = &*SRP_GROUP;
+        let g = BigUint::from(SRP_G);
+        let private = BigUint::from_bytes_be(&[7u8; 32]);
+        let public = g.modpow(&private, n);
+        let b = BigUint::from_bytes_be(server_public);
+
+        let k = BigUint::from_bytes_be(&sha512(&[&n.to_bytes_be(), &srp_pad(&g)]));
+        let u = BigUint::from_bytes_be(&sha512(&[&srp_pad(&public), &srp_pad(&b)]));
+        let credentials = sha512(&[format!("{}:{}", SRP_USERNAME, setup_code).as_bytes()]);
+        let x = BigUint::from_bytes_be(&sha512(&[salt, &credentials]));
+        let base = (&b + n - (&k * g.modpow(&x, n)) % n) % n;
+        let shared = base.modpow(&(&private + &u * &x), n);
+        let key = sha512(&[&shared.to_bytes_be()]);
+
+        let group: Vec<u8> = sha512(&[&n.to_bytes_be()])
+            .iter()
+            .zip(sha512(&[&[SRP_G]]))
+            .map(|(x, y)| x ^ y)
+            .collect();
+        let proof = sha512(&[
+            &group,
+            &sha512(&[SRP_USERNAME.as_bytes()]),
+            salt,
+            &public.to_bytes_be(),
+            server_public,
+            &key,
+        ]);
+
+        [public.to_bytes_be(), proof, key]
+    }
+
+    #[test]
+    fn pair_setup_adds_the_controller() {
+        let identity = Identity::generate();
+        let recorder = Recorder::default();
+        let server = server(identity.clone(), 0, &recorder);
+
+        let m2 = tlv_decode(
+            &server.pair_setup(0, &tlv_encode(&[(TLV_STATE, &[1]), (TLV_METHOD, &[0])])),
+        );
+        assert_eq!(m2[&TLV_STATE], [2]);
+
+        let [public, proof, key] = srp_client(SETUP_CODE, &m2[&TLV_SALT], &m2[&TLV_PUBLIC_KEY]);
+        let m4 = tlv_decode(&server.pair_setup(
+            0,
+            &tlv_encode(&[
+                (TLV_STATE, &[3]),
+                (TLV_PUBLIC_KEY, &public),
+                (TLV_PROOF, &proof),
+            ]),
+        ));
+        assert_eq!(m4[&TLV_STATE], [4]);
+        assert!(!m4.contains_key(&TLV_ERROR));
+        assert_eq!(m4[&TLV_PROOF], sha512(&[&public, &proof, &key]));
+
+        let controller_key = SigningKey::from_bytes(&[9u8; 32]);
+        let controller_public = controller_key.verifying_key().to_bytes();
+        let controller_x = hkdf(
+            &key,
+            "Pair-Setup-Controller-Sign-Salt",
+            "Pair-Setup-Controller-Sign-Info",
+        );
+        let info = [controller_x.as_slice(), b"controller", &controller_public].concat();
+        let signature = controller_key.sign(&info).to_bytes();
+        let encrypt_key = hkdf(&key, "Pair-Setup-Encrypt-Salt", "Pair-Setup-Encrypt-Info");
+        let m5 = seal(
+            &encrypt_key,
+            &nonce(b"PS-Msg05"),
+            &tlv_encode(&[
+                (TLV_IDENTIFIER, b"controller"),
+                (TLV_PUBLIC_KEY, &controller_public),
+                (TLV_SIGNATURE, &signature),
+            ]),
+            &[],
+        );
+
+        let m6 = tlv_decode(&server.pair_setup(
+            0,
+            &tlv_encode(&[(TLV_STATE, &[5]), (TLV_ENCRYPTED_DATA, &m5)]),
+        ));
+        assert_eq!(m6[&TLV_STATE], [6]);
+        let accessory = tlv_decode(
+            &open(
+                &encrypt_key,
+                &nonce(b"PS-Msg06"),
+                &m6[&TLV_ENCRYPTED_DATA],
+                &[],
+            )
+            .unwrap(),
+        );
+        assert_eq!(accessory[&TLV_IDENTIFIER], identity.device_id.as_bytes());
+
+        let accessory_public = identity.signing_key().unwrap().verifying_key().to_bytes();
+        assert_eq!(accessory[&TLV_PUBLIC_KEY], accessory_public);
+        let accessory_x = hkdf(
+            &key,
+            "Pair-Setup-Accessory-Sign-Salt",
+            "Pair-Setup-Accessory-Sign-Info",
+        );
+        let info = [
+            accessory_x.as_slice(),
+            identity.device_id.as_bytes(),
+            &accessory_public,
+        ]
+        .concat();
+        assert!(verify_signature(
+            &accessory_public,
+            &info,
+            &accessory[&TLV_SIGNATURE]
+        ));
+
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            [Pairing {
+                id: "controller".to_string(),
+                public_key: controller_public.to_vec(),
+                admin: true,
+            }]
+        );
+
+        // Once paired, the bridge refuses to start another setup.
+        let again = tlv_decode(&server.pair_setup(0, &tlv_encode(&[(TLV_STATE, &[1])])));
+        assert_eq!(again[&TLV_ERROR], [ERROR_UNAVAILABLE]);
+    }
+
+    #[test]
+    fn pair_setup_rejects_a_wrong_setup_code() {
+        let recorder = Recorder::default();
+        let server = server(Identity::generate(), 0, &recorder);
+
+        let m2 = tlv_decode(&server.pair_setup(0, &tlv_encode(&[(TLV_STATE, &[1])])));
+        let [public, proof, _] = srp_client("111-22-333", &m2[&TLV_SALT], &m2[&TLV_PUBLIC_KEY]);
+        let m4 = tlv_decode(&server.pair_setup(
+            0,
+            &tlv_encode(&[
+                (TLV_STATE, &[3]),
+                (TLV_PUBLIC_KEY, &public),
+                (TLV_PROOF, &proof),
+            ]),
+        ));
+
+        assert_eq!(m4[&TLV_STATE], [4]);
+        assert_eq!(m4[&TLV_ERROR], [ERROR_AUTHENTICATION]);
+        assert!(recorder.0.lock().unwrap().is_empty());
+        assert_eq!(recorder.1.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn pair_setup_is_busy_while_another_session_runs_one() {
+        let recorder = Recorder::default();
+        let server = server(Identity::generate(), 0, &recorder);
+        let m1 = tlv_encode(&[(TLV_STATE, &[1])]);
+
+        let m2 = tlv_decode(&server.pair_setup(0, &m1));
+        assert!(!m2.contains_key(&TLV_ERROR));
+
+        let busy = tlv_decode(&server.pair_setup(1, &m1));
+        assert_eq!(busy[&TLV_ERROR], [ERROR_BUSY]);
+
+        // The other session can't finish the first one's setup either.
+        let [public, proof, _] = srp_client(SETUP_CODE, &m2[&TLV_SALT], &m2[&TLV_PUBLIC_KEY]);
+        let m3 = tlv_encode(&[
+            (TLV_STATE, &[3]),
+            (TLV_PUBLIC_KEY, &public),
+            (TLV_PROOF, &proof),
+        ]);
+        assert_eq!(
+            tlv_decode(&server.pair_setup(1, &m3))[&TLV_ERROR],
+            [ERROR_UNKNOWN]
+        );
+        assert!(!tlv_decode(&server.pair_setup(0, &m3)).contains_key(&TLV_ERROR));
+
+        // Once its owner disconnects, another session can start over.
+        server.setup.lock().unwrap().release(0);
+        let m2 = tlv_decode(&server.pair_setup(1, &m1));
+        assert!(!m2.contains_key(&TLV_ERROR));
+    }
+
+    #[test]
+    fn pair_setup_keeps_its_attempt_limit_across_restarts() {
+        let recorder = Recorder::default();
+        let server = server(Identity::generate(), MAX_SETUP_ATTEMPTS, &recorder);
+
+        let m2 = tlv_decode(&server.pair_setup(0, &tlv_encode(&[(TLV_STATE, &[1])])));
+        assert_eq!(m2[&TLV_ERROR], [ERROR_MAX_TRIES]);
+    }
+}
@@ -0,0 +1,51 @@
+use std::sync::Mutex;
+
+use serde::Serialize;
+use tauri::{AppHandle, Runtime};
+
+use crate::tray;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiHealth {
+    Unknown,
+    Ok,
+    InvalidKey,
+    Unreachable,
+}
+
+static HEALTH: Mutex<ApiHealth> = Mutex::new(ApiHealth::Unknown);
+
+pub fn current() -> ApiHealth {
+    *HEALTH.lock().unwrap()
+}
+
+pub fn set<R: Runtime>(app: &AppHandle<R>, health: ApiHealth) {
+    let previous = std::mem::replace(&mut *HEALTH.lock().unwrap(), health);
+
+    if previous != health {
+        if let Err(e) = tray::refresh_icon(app) {
+            eprintln!("Failed to update tray icon: {}", e);
+        }
+    }
+}
+
+/// Updates the health from the outcome of a request made with the stored API key.
+pub fn record<R: Runtime>(
+    app: &AppHandle<R>,
+    response: &Result<reqwest::Response, reqwest::Error>,
+) {
+    let health = match response {
+        Err(_) => ApiHealth::Unreachable,
+        Ok(r) if r.status() == 401 || r.status() == 403 => ApiHealth::InvalidKey,
+        Ok(r) if r.status().is_server_error() => ApiHealth::Unreachable,
+        Ok(_) => ApiHealth::Ok,
+    };
+
+    set(app, health);
+}
+
+#[tauri::command]
+pub fn get_api_health() -> ApiHealth {
+    current()
+}
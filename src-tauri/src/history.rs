@@ -0,0 +1,244 @@
+use std::sync::Mutex;
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Manager, Runtime};
+
+use crate::command::{DeviceState, GoveeDevice};
+use crate::settings;
+
+const DATABASE_FILE: &str = "sensor_history.sqlite";
+
+/// Instances worth keeping. Everything else a sensor reports is configuration.
+pub const RECORDED_INSTANCES: [&str; 2] = ["sensorTemperature", "sensorHumidity"];
+
+// Enough points for a smooth chart in the panel without shipping thousands of rows.
+const DEFAULT_POINTS: i64 = 200;
+const MAX_RETENTION_DAYS: u32 = 3650;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryConfig {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Readings older than this are deleted.
+    #[serde(default = "default_retention_days")]
+    pub retention_days: u32,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_retention_days() -> u32 {
+    90
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            enabled: default_enabled(),
+            retention_days: default_retention_days(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HistoryRange {
+    Day,
+    Week,
+    Month,
+    Year,
+}
+
+impl HistoryRange {
+    fn duration(self) -> Duration {
+        match self {
+            HistoryRange::Day => Duration::days(1),
+            HistoryRange::Week => Duration::weeks(1),
+            HistoryRange::Month => Duration::days(30),
+            HistoryRange::Year => Duration::days(365),
+        }
+    }
+}
+
+/// One bucket of a downsampled series.
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryPoint {
+    pub at: DateTime<Utc>,
+    pub average: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+pub struct History {
+    db: Mutex<Connection>,
+    last_pruned: Mutex<Option<DateTime<Utc>>>,
+}
+
+pub fn load_config<R: Runtime>(app: &AppHandle<R>) -> HistoryConfig {
+    settings::get(app).sensor_history
+}
+
+pub fn is_sensor(device: &GoveeDevice) -> bool {
+    device
+        .capabilities
+        .iter()
+        .any(|c| RECORDED_INSTANCES.contains(&c.instance.as_str()))
+}
+
+fn open<R: Runtime>(app: &AppHandle<R>) -> Result<Connection, String> {
+    let dir = app.path().app_data_dir().map_err(|e| e.to_string())?;
+    std::fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+
+    let db = Connection::open(dir.join(DATABASE_FILE)).map_err(|e| e.to_string())?;
+    db.execute_batch(
+        "CREATE TABLE IF NOT EXISTS readings (
+            device TEXT NOT NULL,
+            instance TEXT NOT NULL,
+            at INTEGER NOT NULL,
+            value REAL NOT NULL,
+            PRIMARY KEY (device, instance, at)
+        ) WITHOUT ROWID;",
+    )
+    .map_err(|e| e.to_string())?;
+
+    Ok(db)
+}
+
+fn prune(db: &Connection, retention_days: u32) -> rusqlite::Result<usize> {
+    let cutoff = Utc::now() - Duration::days(retention_days.into());
+    db.execute(
+        "DELETE FROM readings WHERE at < ?1",
+        params![cutoff.timestamp()],
+    )
+}
+
+/// Saves the sensor values of a freshly fetched state. Prunes old readings at most once an
+/// hour.
+pub fn record<R: Runtime>(app: &AppHandle<R>, state: &DeviceState) {
+    let Some(history) = app.try_state::<History>() else {
+        return;
+    };
+    let config = load_config(app);
+    if !config.enabled {
+        return;
+    }
+
+    let now = Utc::now();
+    let db = history.db.lock().unwrap();
+
+    for instance in RECORDED_INSTANCES {
+        let Some(value) = state.reading(instance) else {
+            continue;
+        };
+
+        if let Err(e) = db.execute(
+            "INSERT OR REPLACE INTO readings (device, instance, at, value) VALUES (?1, ?2, ?3, ?4)",
+            params![state.device, instance, now.timestamp(), value],
+        ) {
+            eprintln!("Failed to record {} of {}: {}", instance, state.device, e);
+        }
+    }
+
+    let mut last_pruned = history.last_pruned.lock().unwrap();
+    if last_pruned.is_none_or(|at| now - at >= Duration::hours(1)) {
+        if let Err(e) = prune(&db, config.retention_days) {
+            eprintln!("Failed to prune sensor history: {}", e);
+        }
+        *last_pruned = Some(now);
+    }
+}
+
+pub fn start<R: Runtime>(app_handle: &AppHandle<R>) -> Result<(), String> {
+    let db = open(app_handle)?;
+
+    app_handle.manage(History {
+        db: Mutex::new(db),
+        last_pruned: Mutex::new(None),
+    });
+
+    Ok(())
+}
+
+#[tauri::command]
+pub fn get_history_config<R: Runtime>(app: AppHandle<R>) -> HistoryConfig {
+    load_config(&app)
+}
+
+#[tauri::command]
+pub fn set_history_config<R: Runtime>(
+    app: AppHandle<R>,
+    config: HistoryConfig,
+) -> Result<(), String> {
+    if !(1..=MAX_RETENTION_DAYS).contains(&config.retention_days) {
+        return Err(format!(
+            "Retention must be between 1 and {} days",
+            MAX_RETENTION_DAYS
+        ));
+    }
+
+    let retention_days = config.retention_days;
+    settings::update(&app, |s| {
+        s.sensor_history = config;
+        Ok(())
+    })?;
+
+    let history = app.state::<History>();
+    let db = history.db.lock().unwrap();
+    prune(&db, retention_days).map_err(|e| e.to_string())?;
+    *history.last_pruned.lock().unwrap() = Some(Utc::now());
+
+    Ok(())
+}
+
+/// Readings of one sensor value over `range`, averaged into buckets of `resolution`
+/// seconds. Without a resolution the range is split into about 200 buckets.
+#[tauri::command]
+pub fn get_sensor_history<R: Runtime>(
+    app: AppHandle<R>,
+    device: String,
+    instance: String,
+    range: HistoryRange,
+    resolution: Option<i64>,
+) -> Result<Vec<HistoryPoint>, String> {
+    if !RECORDED_INSTANCES.contains(&instance.as_str()) {
+        return Err(format!("No history is recorded for {}", instance));
+    }
+
+    let span = range.duration().num_seconds();
+    let bucket = resolution.unwrap_or(span / DEFAULT_POINTS).max(60);
+    let since = Utc::now().timestamp() - span;
+
+    let history = app.state::<History>();
+    let db = history.db.lock().unwrap();
+
+    let mut query = db
+        .prepare(
+            "SELECT (at / ?4) * ?4 AS bucket, AVG(value), MIN(value), MAX(value)
+             FROM readings
+             WHERE device = ?1 AND instance = ?2 AND at >= ?3
+             GROUP BY bucket
+             ORDER BY bucket",
+        )
+        .map_err(|e| e.to_string())?;
+
+    let points = query
+        .query_map(params![device, instance, since, bucket], |row| {
+            Ok(HistoryPoint {
+                at: Utc
+                    .timestamp_opt(row.get(0)?, 0)
+                    .single()
+                    .unwrap_or_default(),
+                average: row.get(1)?,
+                min: row.get(2)?,
+                max: row.get(3)?,
+            })
+        })
+        .map_err(|e| e.to_string())?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+
+    Ok(points)
+}
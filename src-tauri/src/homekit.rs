@@ -0,0 +1,659 @@
+//! HomeKit bridge built on `hap`. Lights show up in Apple Home as lightbulbs (on/off,
+//! brightness, hue, saturation and colour temperature), plugs as switches and
+//! thermo-hygrometers as temperature and humidity sensors. Values follow backend events, and
+//! changes made in Home go through `apply_changes` like the other integrations.
+//!
+//! The bridge identity and the setup code are kept in the keyring. Pairings, the accessory
+//! id of each device and the configuration number are runtime state in the settings file.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tauri::async_runtime::JoinHandle;
+use tauri::{AppHandle, Manager, Runtime};
+use tauri_plugin_store::StoreExt;
+
+use crate::cache;
+use crate::command::{get_devices, DeviceState, GoveeDevice};
+use crate::events::{self, Payload};
+use crate::hap::{
+    self, Accessory, Characteristic, Format, Identity, Pairing, Server, NOTIFY, READ, WRITE,
+};
+use crate::poller;
+use crate::presence;
+use crate::scheduler::{apply_changes, CapabilityChange};
+use crate::secrets;
+use crate::settings::{self, SETTINGS_FILE};
+
+pub const IDENTITY_ACCOUNT: &str = "homekit-identity";
+pub const SETUP_CODE_ACCOUNT: &str = "homekit-setup-code";
+const STATE_KEY: &str = "homekit";
+const MANUFACTURER: &str = "Govee";
+const BRIDGE_MODEL: &str = "Govee Status Bar";
+// Colour temperatures Apple Home can show, in mireds.
+const MIN_MIREDS: u64 = 140;
+const MAX_MIREDS: u64 = 500;
+// Configuration numbers wrap around after this.
+const MAX_CONFIG_NUMBER: u64 = 65535;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HomekitConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Shown in Apple Home while pairing.
+    #[serde(default = "default_name")]
+    pub name: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+}
+
+fn default_name() -> String {
+    "Govee Bridge".to_string()
+}
+
+fn default_port() -> u16 {
+    51826
+}
+
+impl Default for HomekitConfig {
+    fn default() -> Self {
+        HomekitConfig {
+            enabled: false,
+            name: default_name(),
+            port: default_port(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct BridgeState {
+    pairings: Vec<Pairing>,
+    /// Accessory id of each device, by device id. Ids are never reused.
+    aids: BTreeMap<String, u64>,
+    config_number: u64,
+    fingerprint: String,
+    /// Failed pair setups since the setup code last changed.
+    #[serde(default)]
+    setup_failures: u32,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HomekitStatus {
+    pub running: bool,
+    pub paired: bool,
+    pub setup_code: Option<String>,
+    /// Payload of the setup QR code.
+    pub setup_uri: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Default)]
+pub struct HomekitState {
+    server: Mutex<Option<Arc<Server>>>,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+    error: Mutex<Option<String>>,
+    // Pairings are saved from connection tasks while accessory ids are saved from the
+    // event task, so changes to the stored state take turns.
+    saving: Mutex<()>,
+}
+
+pub fn load_config<R: Runtime>(app: &AppHandle<R>) -> HomekitConfig {
+    settings::get(app).homekit
+}
+
+fn load_state<R: Runtime>(app: &AppHandle<R>) -> BridgeState {
+    app.store(SETTINGS_FILE)
+        .ok()
+        .and_then(|store| store.get(STATE_KEY))
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default()
+}
+
+fn update_state<R: Runtime>(
+    app: &AppHandle<R>,
+    update: impl FnOnce(&mut BridgeState),
+) -> Result<BridgeState, String> {
+    let homekit = app.state::<HomekitState>();
+    let _saving = homekit.saving.lock().unwrap();
+
+    let mut state = load_state(app);
+    update(&mut state);
+
+    let store = app.store(SETTINGS_FILE).map_err(|e| e.to_string())?;
+    let value = serde_json::to_value(&state).map_err(|e| e.to_string())?;
+    store.set(STATE_KEY, value);
+    store
+        .save()
+        .map_err(|_| "Failed to save settings".to_string())?;
+
+    Ok(state)
+}
+
+fn load_identity<R: Runtime>(app: &AppHandle<R>) -> Result<Identity, String> {
+    let stored = secrets::get(app, IDENTITY_ACCOUNT).and_then(|s| serde_json::from_str(&s).ok());
+    if let Some(identity) = stored {
+        return Ok(identity);
+    }
+
+    let identity = Identity::generate();
+    let json = serde_json::to_string(&identity).map_err(|e| e.to_string())?;
+    secrets::set(app, IDENTITY_ACCOUNT, &json)?;
+
+    Ok(identity)
+}
+
+fn load_setup_code<R: Runtime>(app: &AppHandle<R>) -> Result<String, String> {
+    let stored = secrets::get(app, SETUP_CODE_ACCOUNT).filter(|c| hap::is_valid_setup_code(c));
+    if let Some(code) = stored {
+        return Ok(code);
+    }
+
+    let code = hap::generate_setup_code();
+    secrets::set(app, SETUP_CODE_ACCOUNT, &code)?;
+
+    Ok(code)
+}
+
+/// Hue in degrees and saturation in percent of a packed RGB colour.
+fn hue_saturation(rgb: u64) -> (f64, f64) {
+    let [r, g, b] = [(rgb >> 16) & 0xff, (rgb >> 8) & 0xff, rgb & 0xff].map(|c| c as f64 / 255.0);
+    let max = r.max(g).max(b);
+    let delta = max - r.min(g).min(b);
+
+    let hue = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    let saturation = if max == 0.0 { 0.0 } else { delta / max * 100.0 };
+
+    (hue, saturation)
+}
+
+/// Packed RGB colour at full value; brightness is set on its own.
+fn rgb(hue: f64, saturation: f64) -> u32 {
+    let chroma = (saturation / 100.0).clamp(0.0, 1.0);
+    let sector = hue.rem_euclid(360.0) / 60.0;
+    let x = chroma * (1.0 - (sector % 2.0 - 1.0).abs());
+
+    let (r, g, b) = match sector as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let channel = |c: f64| ((c + 1.0 - chroma) * 255.0).round() as u32;
+
+    (channel(r) << 16) | (channel(g) << 8) | channel(b)
+}
+
+fn mireds(kelvin: u64) -> u64 {
+    1_000_000 / kelvin.max(1)
+}
+
+/// The accessory for a device, or `None` if it has nothing HomeKit can show.
+fn accessory(aid: u64, device: &GoveeDevice) -> Option<Accessory> {
+    let mut accessory = Accessory::new(
+        aid,
+        device.display_name(),
+        MANUFACTURER,
+        &device.sku,
+        &device.device,
+    );
+    let mut bridged = false;
+    let on = || Characteristic::new(hap::ON, Format::Bool, READ | WRITE | NOTIFY, json!(false));
+
+    if device.is_light() {
+        let mut characteristics = vec![on()];
+
+        if device.has_capability("brightness") {
+            characteristics.push(
+                Characteristic::new(
+                    hap::BRIGHTNESS,
+                    Format::Int,
+                    READ | WRITE | NOTIFY,
+                    json!(100),
+                )
+                .unit("percentage")
+                .range(0.0, 100.0, 1.0),
+            );
+        }
+        if device.has_capability("colorRgb") {
+            characteristics.push(
+                Characteristic::new(hap::HUE, Format::Float, READ | WRITE | NOTIFY, json!(0.0))
+                    .unit("arcdegrees")
+                    .range(0.0, 360.0, 1.0),
+            );
+            characteristics.push(
+                Characteristic::new(
+                    hap::SATURATION,
+                    Format::Float,
+                    READ | WRITE | NOTIFY,
+                    json!(0.0),
+                )
+                .unit("percentage")
+                .range(0.0, 100.0, 1.0),
+            );
+        }
+        if device.has_capability("colorTemperatureK") {
+            let (min_kelvin, max_kelvin) = device.kelvin_range();
+            let min = mireds(max_kelvin).clamp(MIN_MIREDS, MAX_MIREDS);
+            let max = mireds(min_kelvin).clamp(min, MAX_MIREDS);
+
+            characteristics.push(
+                Characteristic::new(
+                    hap::COLOR_TEMPERATURE,
+                    Format::Uint32,
+                    READ | WRITE | NOTIFY,
+                    json!(min),
+                )
+                .range(min as f64, max as f64, 1.0),
+            );
+        }
+
+        accessory = accessory.with_service(hap::LIGHTBULB, characteristics);
+        bridged = true;
+    } else if device.has_capability("powerSwitch") {
+        accessory = accessory.with_service(hap::SWITCH, vec![on()]);
+        bridged = true;
+    }
+
+    if device.has_capability("sensorTemperature") {
+        accessory = accessory.with_service(
+            hap::TEMPERATURE_SENSOR,
+            vec![Characteristic::new(
+                hap::CURRENT_TEMPERATURE,
+                Format::Float,
+                READ | NOTIFY,
+                json!(0.0),
+            )
+            .unit("celsius")
+            .range(-270.0, 100.0, 0.1)],
+        );
+        bridged = true;
+    }
+    if device.has_capability("sensorHumidity") {
+        accessory = accessory.with_service(
+            hap::HUMIDITY_SENSOR,
+            vec![Characteristic::new(
+                hap::CURRENT_RELATIVE_HUMIDITY,
+                Format::Float,
+                READ | NOTIFY,
+                json!(0.0),
+            )
+            .unit("percentage")
+            .range(0.0, 100.0, 1.0)],
+        );
+        bridged = true;
+    }
+
+    bridged.then_some(accessory)
+}
+
+/// Characteristic values for a device state. Govee reports a colour temperature of 0
+/// while a light is in RGB mode, so only one of the two colour settings is set.
+fn values(state: &DeviceState) -> Vec<(&'static str, Value)> {
+    let mut values = Vec::new();
+
+    if let Some(power) = state.value("powerSwitch").and_then(|v| v.as_i64()) {
+        values.push((hap::ON, json!(power == 1)));
+    }
+    if let Some(brightness) = state.value("brightness").and_then(|v| v.as_u64()) {
+        values.push((hap::BRIGHTNESS, json!(brightness)));
+    }
+
+    let kelvin = state
+        .value("colorTemperatureK")
+        .and_then(|v| v.as_u64())
+        .filter(|k| *k > 0);
+    if let Some(kelvin) = kelvin {
+        values.push((hap::COLOR_TEMPERATURE, json!(mireds(kelvin))));
+    } else if let Some(rgb) = state.value("colorRgb").and_then(|v| v.as_u64()) {
+        let (hue, saturation) = hue_saturation(rgb);
+        values.push((hap::HUE, json!(hue)));
+        values.push((hap::SATURATION, json!(saturation)));
+    }
+
+    if let Some(temperature) = state.reading("sensorTemperature") {
+        values.push((hap::CURRENT_TEMPERATURE, json!(temperature)));
+    }
+    if let Some(humidity) = state.reading("sensorHumidity") {
+        values.push((hap::CURRENT_RELATIVE_HUMIDITY, json!(humidity)));
+    }
+
+    values
+}
+
+fn write_changes(
+    server: &Server,
+    aid: u64,
+    device: &GoveeDevice,
+    values: &[(&'static str, Value)],
+) -> Vec<CapabilityChange> {
+    let value = |kind: &str| values.iter().find(|(k, _)| *k == kind).map(|(_, v)| v);
+    let change = |capability_type: &str, instance: &str, value: Value| CapabilityChange {
+        device: device.device.clone(),
+        sku: device.sku.clone(),
+        capability_type: capability_type.to_string(),
+        instance: instance.to_string(),
+        value,
+    };
+    let power = |on: bool| {
+        change(
+            "devices.capabilities.on_off",
+            "powerSwitch",
+            json!(if on { 1 } else { 0 }),
+        )
+    };
+
+    let on = value(hap::ON).and_then(|v| v.as_bool());
+    if on == Some(false) {
+        return vec![power(false)];
+    }
+
+    let mut changes = Vec::new();
+    if let Some(brightness) = value(hap::BRIGHTNESS).and_then(|v| v.as_u64()) {
+        changes.push(change(
+            "devices.capabilities.brightness",
+            "brightness",
+            json!(brightness.clamp(1, 100)),
+        ));
+    }
+    // Home usually writes hue and saturation together, but either may come alone. The
+    // bridge already holds the new value, so the other one comes from there.
+    if value(hap::HUE).is_some() || value(hap::SATURATION).is_some() {
+        let current = |kind| {
+            server
+                .value(aid, kind)
+                .and_then(|v| v.as_f64())
+                .unwrap_or(0.0)
+        };
+        changes.push(change(
+            "devices.capabilities.color_setting",
+            "colorRgb",
+            json!(rgb(current(hap::HUE), current(hap::SATURATION))),
+        ));
+    }
+    if let Some(mireds) = value(hap::COLOR_TEMPERATURE).and_then(|v| v.as_u64()) {
+        let (min_kelvin, max_kelvin) = device.kelvin_range();
+        changes.push(change(
+            "devices.capabilities.color_setting",
+            "colorTemperatureK",
+            json!(self::mireds(mireds).clamp(min_kelvin, max_kelvin)),
+        ));
+    }
+    // Power on last so the light doesn't flash at its previous setting.
+    if on == Some(true) {
+        changes.push(power(true));
+    }
+
+    changes
+}
+
+fn running_server<R: Runtime>(app: &AppHandle<R>) -> Option<Arc<Server>> {
+    app.state::<HomekitState>().server.lock().unwrap().clone()
+}
+
+async fn handle_write<R: Runtime>(
+    app: &AppHandle<R>,
+    aid: u64,
+    values: Vec<(&'static str, Value)>,
+) -> Result<(), String> {
+    let server = running_server(app).ok_or("HomeKit bridge isn't running")?;
+    let device = load_state(app)
+        .aids
+        .into_iter()
+        .find(|(_, a)| *a == aid)
+        .and_then(|(device, _)| cache::devices(app).into_iter().find(|d| d.device == device))
+        .ok_or_else(|| format!("Unknown accessory {}", aid))?;
+
+    apply_changes(app, write_changes(&server, aid, &device, &values)).await
+}
+
+struct Bridge<R: Runtime> {
+    app: AppHandle<R>,
+}
+
+impl<R: Runtime> hap::Delegate for Bridge<R> {
+    fn pairings_changed(&self, pairings: &[Pairing]) {
+        if let Err(e) = update_state(&self.app, |s| s.pairings = pairings.to_vec()) {
+            eprintln!("Failed to save HomeKit pairings: {}", e);
+        }
+    }
+
+    fn setup_failures_changed(&self, failures: u32) {
+        if let Err(e) = update_state(&self.app, |s| s.setup_failures = failures) {
+            eprintln!("Failed to save HomeKit setup attempts: {}", e);
+        }
+    }
+
+    fn write(&self, aid: u64, values: Vec<(&'static str, Value)>) {
+        let app = self.app.clone();
+        tauri::async_runtime::spawn(async move {
+            if let Err(e) = handle_write(&app, aid, values).await {
+                eprintln!("HomeKit command failed: {}", e);
+            }
+        });
+    }
+}
+
+fn refresh<R: Runtime>(app: &AppHandle<R>, server: &Server, aid: u64, device: &str) {
+    server.set_reachable(aid, !presence::is_offline(app, device));
+
+    if let Some(state) = cache::state(app, device) {
+        for (kind, value) in values(&state) {
+            server.set_value(aid, kind, value);
+        }
+    }
+}
+
+/// Builds an accessory for every device, giving new devices the next free id, and raises
+/// the configuration number if the accessories changed.
+fn publish_accessories<R: Runtime>(app: &AppHandle<R>, server: &Server) -> Result<(), String> {
+    let devices = cache::devices(app);
+    let mut accessories = Vec::new();
+
+    let state = update_state(app, |state| {
+        for device in &devices {
+            let next = state.aids.values().max().copied().unwrap_or(1) + 1;
+            let aid = *state.aids.entry(device.device.clone()).or_insert(next);
+            accessories.extend(accessory(aid, device));
+        }
+
+        let fingerprint = hap::fingerprint(&accessories);
+        if fingerprint != state.fingerprint {
+            state.config_number = state.config_number % MAX_CONFIG_NUMBER + 1;
+            state.fingerprint = fingerprint;
+        }
+    })?;
+
+    server.set_accessories(accessories, state.config_number);
+    for (device, aid) in &state.aids {
+        refresh(app, server, *aid, device);
+    }
+
+    Ok(())
+}
+
+fn launch<R: Runtime>(app: &AppHandle<R>) -> Result<(), String> {
+    let config = load_config(app);
+    let state = load_state(app);
+    let server = Arc::new(Server::new(
+        hap::Config {
+            name: config.name,
+            model: BRIDGE_MODEL.to_string(),
+            manufacturer: MANUFACTURER.to_string(),
+            port: config.port,
+            setup_code: load_setup_code(app)?,
+        },
+        load_identity(app)?,
+        state.pairings,
+        state.setup_failures,
+        Bridge { app: app.clone() },
+    )?);
+
+    let (handle, bridge) = (app.clone(), server.clone());
+    let listener = tauri::async_runtime::spawn(async move {
+        if cache::devices(&handle).is_empty() {
+            let _ = get_devices(handle.clone()).await;
+        }
+        if let Err(e) = publish_accessories(&handle, &bridge) {
+            eprintln!("Failed to publish HomeKit accessories: {}", e);
+        }
+
+        if let Err(e) = bridge.run().await {
+            eprintln!("HomeKit bridge stopped: {}", e);
+            *handle.state::<HomekitState>().error.lock().unwrap() = Some(e);
+        }
+    });
+
+    let (handle, bridge) = (app.clone(), server.clone());
+    let updates = tauri::async_runtime::spawn(async move {
+        let mut events = events::subscribe(&handle);
+
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    let relevant = matches!(
+                        event.payload,
+                        Payload::State { .. } | Payload::Sensor { .. } | Payload::Online { .. }
+                    );
+                    let Some(device) = event.device.filter(|_| relevant) else {
+                        continue;
+                    };
+
+                    match load_state(&handle).aids.get(&device) {
+                        Some(aid) => refresh(&handle, &bridge, *aid, &device),
+                        None => {
+                            if let Err(e) = publish_accessories(&handle, &bridge) {
+                                eprintln!("Failed to publish HomeKit accessories: {}", e);
+                            }
+                        }
+                    }
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {}
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+
+    let state = app.state::<HomekitState>();
+    *state.server.lock().unwrap() = Some(server);
+    *state.tasks.lock().unwrap() = vec![listener, updates];
+    *state.error.lock().unwrap() = None;
+
+    Ok(())
+}
+
+fn stop<R: Runtime>(app: &AppHandle<R>) {
+    let state = app.state::<HomekitState>();
+
+    if let Some(server) = state.server.lock().unwrap().take() {
+        server.stop();
+    }
+    for task in state.tasks.lock().unwrap().drain(..) {
+        task.abort();
+    }
+    *state.error.lock().unwrap() = None;
+}
+
+fn restart<R: Runtime>(app: &AppHandle<R>) -> Result<(), String> {
+    stop(app);
+
+    if load_config(app).enabled {
+        launch(app)?;
+    }
+
+    Ok(())
+}
+
+pub fn start<R: Runtime>(app_handle: &AppHandle<R>) {
+    app_handle.manage(HomekitState::default());
+
+    if let Err(e) = restart(app_handle) {
+        eprintln!("Failed to start the HomeKit bridge: {}", e);
+        *app_handle.state::<HomekitState>().error.lock().unwrap() = Some(e);
+    }
+}
+
+#[tauri::command]
+pub fn get_homekit_config<R: Runtime>(app: AppHandle<R>) -> HomekitConfig {
+    load_config(&app)
+}
+
+#[tauri::command]
+pub fn set_homekit_config<R: Runtime>(
+    app: AppHandle<R>,
+    config: HomekitConfig,
+) -> Result<(), String> {
+    if config.name.trim().is_empty() {
+        return Err("Bridge name cannot be empty".to_string());
+    }
+    if config.port == 0 {
+        return Err("Port must be between 1 and 65535".to_string());
+    }
+
+    settings::update(&app, |s| {
+        s.homekit = config;
+        Ok(())
+    })?;
+    poller::wake(&app);
+
+    restart(&app)
+}
+
+#[tauri::command]
+pub fn get_homekit_status<R: Runtime>(app: AppHandle<R>) -> HomekitStatus {
+    let state = app.state::<HomekitState>();
+    let server = state.server.lock().unwrap().clone();
+    let error = state.error.lock().unwrap().clone();
+
+    let setup_code = secrets::get(&app, SETUP_CODE_ACCOUNT);
+    let setup_id = secrets::get(&app, IDENTITY_ACCOUNT)
+        .and_then(|s| serde_json::from_str::<Identity>(&s).ok())
+        .map(|identity| identity.setup_id);
+
+    HomekitStatus {
+        running: server.is_some() && error.is_none(),
+        paired: match &server {
+            Some(server) => server.is_paired(),
+            None => !load_state(&app).pairings.is_empty(),
+        },
+        setup_uri: setup_code
+            .as_ref()
+            .zip(setup_id)
+            .map(|(code, id)| hap::setup_uri(code, &id)),
+        setup_code,
+        error,
+    }
+}
+
+/// Replaces the setup code used for new pairings. Existing pairings keep working. Guesses
+/// at the old code no longer count against the limit on setup attempts.
+#[tauri::command]
+pub fn regenerate_homekit_setup_code<R: Runtime>(app: AppHandle<R>) -> Result<(), String> {
+    secrets::set(&app, SETUP_CODE_ACCOUNT, &hap::generate_setup_code())?;
+    update_state(&app, |s| s.setup_failures = 0)?;
+
+    restart(&app)
+}
+
+/// Forgets every controller so the bridge can be added to a home again. The bridge gets a
+/// new identity, so Apple Home sees a new accessory rather than one it can't reach.
+#[tauri::command]
+pub fn reset_homekit_pairings<R: Runtime>(app: AppHandle<R>) -> Result<(), String> {
+    stop(&app);
+
+    secrets::delete(&app, IDENTITY_ACCOUNT)?;
+    update_state(&app, |s| s.pairings.clear())?;
+
+    restart(&app)
+}
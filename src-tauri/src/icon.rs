@@ -0,0 +1,199 @@
+//! Draws the tray icon from the template in `icons/tray.png` plus live-state overlays.
+//!
+//! Everything is drawn at twice the template size. Overlays that only need a shape (the
+//! count badge and the warning sign) are punched out of opaque pixels so the result still
+//! works as a macOS template image; only the colour swatch needs a real colour image.
+
+const SCALE: u32 = 2;
+
+// 3x5 bitmap digits, one row per byte, most significant of the low three bits leftmost.
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b011, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b010, 0b010, 0b010],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+
+const BLACK: [u8; 4] = [0, 0, 0, 255];
+const CLEAR: [u8; 4] = [0, 0, 0, 0];
+// Mid grey reads on both light and dark menu bars once the image is no longer a template.
+const NEUTRAL: [u8; 4] = [142, 142, 147, 255];
+const WARNING: [u8; 4] = [255, 149, 0, 255];
+const OUTLINE: [u8; 4] = [60, 60, 67, 255];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Badge {
+    None,
+    /// Number of lights switched on.
+    Count(u32),
+    /// Current colour of a light, or `None` while it is off.
+    Swatch(Option<[u8; 3]>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IconState {
+    pub badge: Badge,
+    pub warning: bool,
+}
+
+impl IconState {
+    /// Whether the icon can be shown as a template image, which macOS tints to match the
+    /// menu bar.
+    pub fn is_template(&self) -> bool {
+        !matches!(self.badge, Badge::Swatch(_))
+    }
+}
+
+struct Canvas {
+    rgba: Vec<u8>,
+    size: u32,
+}
+
+impl Canvas {
+    fn from_template(rgba: &[u8], width: u32, height: u32, tint: Option<[u8; 4]>) -> Self {
+        let size = width.max(height) * SCALE;
+        let mut canvas = Canvas {
+            rgba: vec![0; (size * size * 4) as usize],
+            size,
+        };
+
+        for y in 0..size {
+            for x in 0..size {
+                let (sx, sy) = (x / SCALE, y / SCALE);
+                if sx >= width || sy >= height {
+                    continue;
+                }
+
+                let i = ((sy * width + sx) * 4) as usize;
+                let mut pixel = [rgba[i], rgba[i + 1], rgba[i + 2], rgba[i + 3]];
+                if let Some(tint) = tint {
+                    pixel = [tint[0], tint[1], tint[2], pixel[3]];
+                }
+                canvas.set(x as i32, y as i32, pixel);
+            }
+        }
+
+        canvas
+    }
+
+    fn set(&mut self, x: i32, y: i32, pixel: [u8; 4]) {
+        if x < 0 || y < 0 || x >= self.size as i32 || y >= self.size as i32 {
+            return;
+        }
+
+        let i = ((y as u32 * self.size + x as u32) * 4) as usize;
+        self.rgba[i..i + 4].copy_from_slice(&pixel);
+    }
+
+    fn disc(&mut self, cx: i32, cy: i32, radius: i32, pixel: [u8; 4]) {
+        for y in -radius..=radius {
+            for x in -radius..=radius {
+                if x * x + y * y <= radius * radius {
+                    self.set(cx + x, cy + y, pixel);
+                }
+            }
+        }
+    }
+
+    /// A filled triangle pointing up, with its apex at (`cx`, `top`).
+    fn triangle(&mut self, cx: i32, top: i32, height: i32, pixel: [u8; 4]) {
+        for row in 0..height {
+            let half = row * 2 / 3 + 1;
+            for x in -half..=half {
+                self.set(cx + x, top + row, pixel);
+            }
+        }
+    }
+
+    fn rect(&mut self, x: i32, y: i32, width: i32, height: i32, pixel: [u8; 4]) {
+        for dy in 0..height {
+            for dx in 0..width {
+                self.set(x + dx, y + dy, pixel);
+            }
+        }
+    }
+
+    fn digit(&mut self, x: i32, y: i32, digit: usize, pixel: [u8; 4]) {
+        let scale = SCALE as i32;
+
+        for (row, bits) in DIGITS[digit].iter().enumerate() {
+            for col in 0..3 {
+                if bits & (0b100 >> col) != 0 {
+                    self.rect(x + col * scale, y + row as i32 * scale, scale, scale, pixel);
+                }
+            }
+        }
+    }
+
+    fn number(&mut self, cx: i32, cy: i32, value: u32, pixel: [u8; 4]) {
+        let scale = SCALE as i32;
+        let digits: Vec<usize> = value
+            .min(99)
+            .to_string()
+            .bytes()
+            .map(|b| (b - b'0') as usize)
+            .collect();
+
+        let glyph = 3 * scale;
+        let width = digits.len() as i32 * glyph + (digits.len() as i32 - 1) * scale;
+        let mut x = cx - width / 2;
+        let y = cy - 5 * scale / 2;
+
+        for digit in digits {
+            self.digit(x, y, digit, pixel);
+            x += glyph + scale;
+        }
+    }
+}
+
+/// Renders the tray icon. `template` is the decoded RGBA of `icons/tray.png`.
+pub fn render(template: &[u8], width: u32, height: u32, state: IconState) -> (Vec<u8>, u32) {
+    let tint = (!state.is_template()).then_some(NEUTRAL);
+    let mut canvas = Canvas::from_template(template, width, height, tint);
+
+    let size = canvas.size as i32;
+    let radius = size / 4 + 1;
+    let (cx, cy) = (size - radius - 1, size - radius - 1);
+
+    match state.badge {
+        Badge::None => {}
+        Badge::Count(0) => {}
+        Badge::Count(count) => {
+            // Clear a ring around the badge so it stands apart from the glyph.
+            canvas.disc(cx, cy, radius + 2, CLEAR);
+            canvas.disc(cx, cy, radius, BLACK);
+            canvas.number(cx, cy, count, CLEAR);
+        }
+        Badge::Swatch(color) => {
+            canvas.disc(cx, cy, radius + 2, CLEAR);
+            canvas.disc(cx, cy, radius, OUTLINE);
+            let fill = match color {
+                Some([r, g, b]) => [r, g, b, 255],
+                None => CLEAR,
+            };
+            canvas.disc(cx, cy, radius - 2, fill);
+        }
+    }
+
+    if state.warning {
+        let height = size / 3;
+        let cx = size - height / 2 - 1;
+        let fill = if state.is_template() { BLACK } else { WARNING };
+
+        canvas.triangle(cx, 0, height + 2, CLEAR);
+        canvas.triangle(cx, 1, height, fill);
+
+        // Exclamation mark
+        let scale = SCALE as i32;
+        canvas.rect(cx - scale / 2, height / 3, scale, height / 3, CLEAR);
+        canvas.rect(cx - scale / 2, height - scale * 2, scale, scale, CLEAR);
+    }
+
+    (canvas.rgba, canvas.size)
+}
@@ -0,0 +1,193 @@
+mod alarm;
+mod alerts;
+mod autostart;
+mod cache;
+mod circadian;
+pub mod cli;
+mod command;
+mod daemon;
+mod events;
+#[cfg(target_os = "macos")]
+mod fns;
+mod hap;
+mod health;
+mod history;
+mod homekit;
+mod icon;
+mod metrics;
+mod mqtt;
+mod outgoing;
+mod panel;
+mod poller;
+mod presence;
+mod presets;
+mod profiles;
+mod ratelimit;
+mod rooms;
+mod scheduler;
+mod secrets;
+mod sensors;
+mod server;
+mod settings;
+mod solar;
+mod stream;
+mod timer;
+mod tray;
+mod webhooks;
+
+use tauri::{AppHandle, Builder, Context, Manager, Runtime};
+use tauri_plugin_autostart::MacosLauncher;
+
+/// Shared by the app, headless mode and the `govee` CLI, since the context can only be
+/// generated once.
+fn context<R: Runtime>() -> Context<R> {
+    tauri::generate_context!()
+}
+
+/// Background services that run in both the app and headless mode. Settings must be
+/// loaded first.
+fn start_services<R: Runtime>(app_handle: &AppHandle<R>) -> Result<(), String> {
+    events::start(app_handle);
+    scheduler::start(app_handle);
+    circadian::start(app_handle);
+    alarm::start(app_handle);
+    timer::start(app_handle);
+    history::start(app_handle)?;
+    alerts::start(app_handle);
+    presence::start(app_handle);
+    poller::start(app_handle);
+    server::start(app_handle);
+    mqtt::start(app_handle);
+    homekit::start(app_handle);
+    outgoing::start(app_handle);
+
+    Ok(())
+}
+
+pub fn run() {
+    if std::env::args().any(|arg| arg == "--headless") {
+        daemon::run();
+        return;
+    }
+
+    let builder = Builder::default();
+
+    #[cfg(target_os = "macos")]
+    let builder = builder.plugin(tauri_nspanel::init());
+
+    builder
+        .plugin(tauri_plugin_autostart::init(
+            MacosLauncher::LaunchAgent,
+            Some(vec![]),
+        ))
+        .plugin(tauri_plugin_store::Builder::default().build())
+        .plugin(tauri_plugin_notification::init())
+        .invoke_handler(tauri::generate_handler![
+            command::init,
+            command::show_menubar_panel,
+            command::get_devices,
+            command::get_device_state,
+            command::change_capability_value,
+            command::get_api_key,
+            command::set_api_key,
+            command::get_light_scenes,
+            command::get_diy_scenes,
+            scheduler::get_schedules,
+            scheduler::save_schedule,
+            scheduler::delete_schedule,
+            scheduler::run_schedule_now,
+            solar::get_location,
+            solar::set_location,
+            solar::get_solar_times,
+            circadian::get_circadian_config,
+            circadian::set_circadian_config,
+            circadian::get_circadian_sample,
+            circadian::resume_circadian,
+            alarm::get_alarms,
+            alarm::save_alarm,
+            alarm::delete_alarm,
+            alarm::cancel_alarm,
+            timer::get_timers,
+            timer::start_timer,
+            timer::extend_timer,
+            timer::cancel_timer,
+            presets::get_presets,
+            presets::save_preset,
+            presets::delete_preset,
+            presets::apply_preset,
+            tray::get_pinned_devices,
+            tray::set_pinned_devices,
+            tray::get_tray_icon_mode,
+            tray::set_tray_icon_mode,
+            health::get_api_health,
+            sensors::get_tray_sensors,
+            sensors::set_tray_sensors,
+            history::get_history_config,
+            history::set_history_config,
+            history::get_sensor_history,
+            alerts::get_alert_rules,
+            alerts::save_alert_rule,
+            alerts::delete_alert_rule,
+            alerts::get_quiet_hours,
+            alerts::set_quiet_hours,
+            alerts::get_alert_log,
+            alerts::clear_alert_log,
+            presence::get_device_presence,
+            presence::get_offline_notifications,
+            presence::set_offline_notifications,
+            profiles::get_profiles,
+            profiles::add_profile,
+            profiles::rename_profile,
+            profiles::delete_profile,
+            settings::get_settings_issues,
+            autostart::get_autostart,
+            autostart::set_autostart,
+            server::get_api_server_status,
+            server::start_api_server,
+            server::stop_api_server,
+            server::set_api_server_config,
+            server::regenerate_api_server_token,
+            rooms::get_device_rooms,
+            rooms::set_device_room,
+            mqtt::get_mqtt_config,
+            mqtt::set_mqtt_config,
+            mqtt::get_mqtt_status,
+            homekit::get_homekit_config,
+            homekit::set_homekit_config,
+            homekit::get_homekit_status,
+            homekit::regenerate_homekit_setup_code,
+            homekit::reset_homekit_pairings,
+            webhooks::get_webhooks,
+            webhooks::save_webhook,
+            webhooks::delete_webhook,
+            webhooks::get_webhook_log,
+            webhooks::clear_webhook_log,
+            outgoing::get_outgoing_webhooks,
+            outgoing::save_outgoing_webhook,
+            outgoing::delete_outgoing_webhook,
+            outgoing::get_outgoing_webhook_history,
+            outgoing::clear_outgoing_webhook_history
+        ])
+        .setup(|app| {
+            #[cfg(target_os = "macos")]
+            app.set_activation_policy(tauri::ActivationPolicy::Accessory);
+
+            let app_handle = app.app_handle();
+
+            app.manage(cache::DeviceCache::default());
+
+            settings::init(app_handle)?;
+
+            tray::create(app_handle)?;
+
+            start_services(app_handle)?;
+
+            if let Err(e) = autostart::apply_on_first_run(app_handle) {
+                eprintln!("{}", e);
+            }
+
+            Ok(())
+        })
+        .run(context())
+        .expect("error while running tauri application");
+}
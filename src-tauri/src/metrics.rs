@@ -0,0 +1,225 @@
+//! Prometheus metrics, served at `/metrics` by the local API when enabled. Device gauges
+//! are read from the states the poller keeps in the cache; request counts and latencies
+//! are recorded by the Govee API client.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tauri::{AppHandle, Runtime};
+
+use crate::cache;
+use crate::presence;
+use crate::profiles;
+use crate::ratelimit;
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const BUCKETS: [f64; 9] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+#[derive(Default)]
+struct Requests {
+    count: u64,
+    seconds: f64,
+    /// Requests at or under each bound of `BUCKETS`.
+    buckets: [u64; BUCKETS.len()],
+}
+
+/// Requests by endpoint and status.
+static REQUESTS: Mutex<BTreeMap<(String, String), Requests>> = Mutex::new(BTreeMap::new());
+
+fn record_request(endpoint: &str, status: String, elapsed: Duration) {
+    let seconds = elapsed.as_secs_f64();
+    let mut requests = REQUESTS.lock().unwrap();
+    let requests = requests.entry((endpoint.to_string(), status)).or_default();
+
+    requests.count += 1;
+    requests.seconds += seconds;
+    for (bucket, bound) in requests.buckets.iter_mut().zip(BUCKETS) {
+        if seconds <= bound {
+            *bucket += 1;
+        }
+    }
+}
+
+/// Sends a Govee API request and records how long it took and how it ended. Requests that
+/// fail before a response count under the status `error`.
+pub async fn timed(
+    endpoint: &str,
+    request: impl Future<Output = Result<reqwest::Response, reqwest::Error>>,
+) -> Result<reqwest::Response, reqwest::Error> {
+    let started = Instant::now();
+    let response = request.await;
+
+    let status = match &response {
+        Ok(response) => response.status().as_u16().to_string(),
+        Err(_) => "error".to_string(),
+    };
+    record_request(endpoint, status, started.elapsed());
+
+    response
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn labels(pairs: &[(&str, &str)]) -> String {
+    let pairs: Vec<String> = pairs
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect();
+
+    format!("{{{}}}", pairs.join(","))
+}
+
+/// Writes one metric family. Families without samples are left out.
+fn family(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(String, f64)]) {
+    if samples.is_empty() {
+        return;
+    }
+
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{}{} {}", name, labels, value);
+    }
+}
+
+/// All metrics in the Prometheus text format.
+pub fn render<R: Runtime>(app: &AppHandle<R>) -> String {
+    let mut out = String::new();
+
+    let mut power = Vec::new();
+    let mut brightness = Vec::new();
+    let mut temperature = Vec::new();
+    let mut humidity = Vec::new();
+    let mut online = Vec::new();
+
+    for device in cache::devices(app) {
+        let labels = labels(&[
+            ("device", &device.device),
+            ("name", device.display_name()),
+            ("sku", &device.sku),
+        ]);
+
+        if let Some(seen) = presence::get(app, &device.device).online {
+            online.push((labels.clone(), seen as u8 as f64));
+        }
+
+        let Some(state) = cache::state(app, &device.device) else {
+            continue;
+        };
+        if let Some(on) = state.value("powerSwitch").and_then(|v| v.as_f64()) {
+            power.push((labels.clone(), on));
+        }
+        if let Some(level) = state.value("brightness").and_then(|v| v.as_f64()) {
+            brightness.push((labels.clone(), level));
+        }
+        if let Some(reading) = state.reading("sensorTemperature") {
+            temperature.push((labels.clone(), reading));
+        }
+        if let Some(reading) = state.reading("sensorHumidity") {
+            humidity.push((labels, reading));
+        }
+    }
+
+    family(
+        &mut out,
+        "govee_device_power",
+        "gauge",
+        "Whether the device is switched on.",
+        &power,
+    );
+    family(
+        &mut out,
+        "govee_device_brightness_percent",
+        "gauge",
+        "Brightness of the light.",
+        &brightness,
+    );
+    family(
+        &mut out,
+        "govee_device_online",
+        "gauge",
+        "Whether the device was reachable when last checked.",
+        &online,
+    );
+    family(
+        &mut out,
+        "govee_sensor_temperature_celsius",
+        "gauge",
+        "Temperature reported by the sensor.",
+        &temperature,
+    );
+    family(
+        &mut out,
+        "govee_sensor_humidity_percent",
+        "gauge",
+        "Relative humidity reported by the sensor.",
+        &humidity,
+    );
+
+    let quota: Vec<(String, f64)> = profiles::load_profiles(app)
+        .iter()
+        .map(|p| {
+            (
+                labels(&[("profile", &p.id), ("name", &p.name)]),
+                ratelimit::remaining(&p.id) as f64,
+            )
+        })
+        .collect();
+    family(
+        &mut out,
+        "govee_api_quota_remaining",
+        "gauge",
+        "Govee API requests left today, by profile.",
+        &quota,
+    );
+
+    let requests = REQUESTS.lock().unwrap();
+    let counts: Vec<(String, f64)> = requests
+        .iter()
+        .map(|((endpoint, status), r)| {
+            (
+                labels(&[("endpoint", endpoint), ("status", status)]),
+                r.count as f64,
+            )
+        })
+        .collect();
+    family(
+        &mut out,
+        "govee_api_requests_total",
+        "counter",
+        "Requests sent to the Govee API.",
+        &counts,
+    );
+
+    if !requests.is_empty() {
+        let name = "govee_api_request_duration_seconds";
+        let _ = writeln!(out, "# HELP {} Latency of Govee API requests.", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+
+        for ((endpoint, status), r) in requests.iter() {
+            let buckets = BUCKETS
+                .iter()
+                .map(|bound| bound.to_string())
+                .zip(r.buckets)
+                .chain([("+Inf".to_string(), r.count)]);
+            for (bound, count) in buckets {
+                let labels = labels(&[("endpoint", endpoint), ("status", status), ("le", &bound)]);
+                let _ = writeln!(out, "{}_bucket{} {}", name, labels, count);
+            }
+
+            let labels = labels(&[("endpoint", endpoint), ("status", status)]);
+            let _ = writeln!(out, "{}_sum{} {}", name, labels, r.seconds);
+            let _ = writeln!(out, "{}_count{} {}", name, labels, r.count);
+        }
+    }
+
+    out
+}
@@ -0,0 +1,574 @@
+//! Home Assistant bridge over MQTT. Devices are announced through MQTT discovery as lights,
+//! switches and sensors built from their capabilities, their state is published whenever
+//! the backend sees it change, and commands from Home Assistant are sent on through
+//! `change_capability_value`.
+//!
+//! Topics, with `<id>` the device id without separators:
+//! - `<base>/status`: `online` or `offline` (retained, also the last will)
+//! - `<base>/<id>/state`: JSON state (retained)
+//! - `<base>/<id>/availability`: `online` or `offline` (retained)
+//! - `<base>/<id>/set`: JSON commands, e.g. `{"state": "ON", "brightness": 40}`
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use tauri::async_runtime::JoinHandle;
+use tauri::{AppHandle, Manager, Runtime};
+
+use crate::cache;
+use crate::command::{get_devices, DeviceState, GoveeDevice};
+use crate::events::{self, Payload};
+use crate::poller;
+use crate::presence;
+use crate::scheduler::{apply_changes, CapabilityChange};
+use crate::secrets;
+use crate::settings;
+
+pub const PASSWORD_ACCOUNT: &str = "mqtt-password";
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+// Requests queued for the event loop. Announcing devices queues a few per device.
+const QUEUE_SIZE: usize = 128;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MqttConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_host")]
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub username: Option<String>,
+    /// Kept in the keyring, not in the settings file.
+    #[serde(default, skip_serializing)]
+    pub password: String,
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+    #[serde(default = "default_base_topic")]
+    pub base_topic: String,
+}
+
+fn default_host() -> String {
+    "localhost".to_string()
+}
+
+fn default_port() -> u16 {
+    1883
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+fn default_base_topic() -> String {
+    "govee-statusbar".to_string()
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            enabled: false,
+            host: default_host(),
+            port: default_port(),
+            username: None,
+            password: String::new(),
+            discovery_prefix: default_discovery_prefix(),
+            base_topic: default_base_topic(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MqttStatus {
+    pub connected: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Default)]
+pub struct MqttState {
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+    status: Mutex<MqttStatus>,
+}
+
+pub fn load_config<R: Runtime>(app: &AppHandle<R>) -> MqttConfig {
+    settings::get(app).mqtt
+}
+
+fn set_status<R: Runtime>(app: &AppHandle<R>, connected: bool, error: Option<String>) {
+    *app.state::<MqttState>().status.lock().unwrap() = MqttStatus { connected, error };
+}
+
+/// The device id as a topic segment, e.g. `AA:BB:...` becomes `aabb...`.
+fn topic_id(device: &str) -> String {
+    device
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Discovery topics and configs for one device. A light or switch gets one entity, a
+/// sensor one per reading.
+fn discovery(config: &MqttConfig, device: &GoveeDevice) -> Vec<(String, Value)> {
+    let id = topic_id(&device.device);
+    let base = format!("{}/{}", config.base_topic, id);
+    let topic = |component: &str, object: &str| {
+        format!(
+            "{}/{}/{}/{}/config",
+            config.discovery_prefix, component, id, object
+        )
+    };
+
+    let common = json!({
+        "state_topic": format!("{}/state", base),
+        "availability": [
+            { "topic": format!("{}/status", config.base_topic) },
+            { "topic": format!("{}/availability", base) },
+        ],
+        "availability_mode": "all",
+        "device": {
+            "identifiers": [format!("govee_{}", id)],
+            "name": device.display_name(),
+            "manufacturer": "Govee",
+            "model": device.sku,
+        },
+    });
+    let entity = |extra: Value| {
+        let mut config = common.clone();
+        if let (Some(config), Value::Object(extra)) = (config.as_object_mut(), extra) {
+            config.extend(extra);
+        }
+        config
+    };
+
+    let mut configs = Vec::new();
+    if device.is_light() {
+        let mut modes = Vec::new();
+        if device.has_capability("colorRgb") {
+            modes.push("rgb");
+        }
+        if device.has_capability("colorTemperatureK") {
+            modes.push("color_temp");
+        }
+        if modes.is_empty() {
+            modes.push(if device.has_capability("brightness") {
+                "brightness"
+            } else {
+                "onoff"
+            });
+        }
+        let (min_kelvin, max_kelvin) = device.kelvin_range();
+
+        configs.push((
+            topic("light", "light"),
+            entity(json!({
+                "name": null,
+                "unique_id": format!("govee_{}_light", id),
+                "schema": "json",
+                "command_topic": format!("{}/set", base),
+                "brightness": device.has_capability("brightness"),
+                "brightness_scale": 100,
+                "supported_color_modes": modes,
+                "color_temp_kelvin": true,
+                "min_kelvin": min_kelvin,
+                "max_kelvin": max_kelvin,
+            })),
+        ));
+    } else if device.has_capability("powerSwitch") {
+        configs.push((
+            topic("switch", "switch"),
+            entity(json!({
+                "name": null,
+                "unique_id": format!("govee_{}_switch", id),
+                "command_topic": format!("{}/set", base),
+                "value_template": "{{ value_json.state }}",
+                "payload_on": r#"{"state": "ON"}"#,
+                "payload_off": r#"{"state": "OFF"}"#,
+                "state_on": "ON",
+                "state_off": "OFF",
+            })),
+        ));
+    }
+
+    if device.has_capability("sensorTemperature") {
+        configs.push((
+            topic("sensor", "temperature"),
+            entity(json!({
+                "name": "Temperature",
+                "unique_id": format!("govee_{}_temperature", id),
+                "device_class": "temperature",
+                "state_class": "measurement",
+                "unit_of_measurement": "°C",
+                "suggested_display_precision": 1,
+                "value_template": "{{ value_json.temperature }}",
+            })),
+        ));
+    }
+    if device.has_capability("sensorHumidity") {
+        configs.push((
+            topic("sensor", "humidity"),
+            entity(json!({
+                "name": "Humidity",
+                "unique_id": format!("govee_{}_humidity", id),
+                "device_class": "humidity",
+                "state_class": "measurement",
+                "unit_of_measurement": "%",
+                "value_template": "{{ value_json.humidity }}",
+            })),
+        ));
+    }
+
+    configs
+}
+
+/// State in the format of Home Assistant's JSON light schema, plus sensor readings.
+fn state_payload(state: &DeviceState) -> Value {
+    let mut payload = Map::new();
+
+    if let Some(power) = state.value("powerSwitch").and_then(|v| v.as_i64()) {
+        payload.insert("state".into(), json!(if power == 1 { "ON" } else { "OFF" }));
+    }
+    if let Some(brightness) = state.value("brightness").and_then(|v| v.as_u64()) {
+        payload.insert("brightness".into(), json!(brightness));
+    }
+
+    // Govee reports a colour temperature of 0 while the light is in RGB mode.
+    let kelvin = state
+        .value("colorTemperatureK")
+        .and_then(|v| v.as_u64())
+        .filter(|k| *k > 0);
+    if let Some(kelvin) = kelvin {
+        payload.insert("color_mode".into(), json!("color_temp"));
+        payload.insert("color_temp".into(), json!(kelvin));
+    } else if let Some(rgb) = state.value("colorRgb").and_then(|v| v.as_u64()) {
+        payload.insert("color_mode".into(), json!("rgb"));
+        payload.insert(
+            "color".into(),
+            json!({ "r": (rgb >> 16) & 0xff, "g": (rgb >> 8) & 0xff, "b": rgb & 0xff }),
+        );
+    }
+
+    // The sensor reports tenths of a °F, which two decimals of a °C keep.
+    if let Some(temperature) = state.reading("sensorTemperature") {
+        let temperature = (temperature * 100.0).round() / 100.0;
+        payload.insert("temperature".into(), json!(temperature));
+    }
+    if let Some(humidity) = state.reading("sensorHumidity") {
+        payload.insert("humidity".into(), json!(humidity));
+    }
+
+    Value::Object(payload)
+}
+
+#[derive(Debug, Deserialize)]
+struct Rgb {
+    r: u32,
+    g: u32,
+    b: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct Command {
+    state: Option<String>,
+    brightness: Option<u32>,
+    color: Option<Rgb>,
+    color_temp: Option<u32>,
+}
+
+fn command_changes(device: &GoveeDevice, command: Command) -> Vec<CapabilityChange> {
+    let change = |capability_type: &str, instance: &str, value: Value| CapabilityChange {
+        device: device.device.clone(),
+        sku: device.sku.clone(),
+        capability_type: capability_type.to_string(),
+        instance: instance.to_string(),
+        value,
+    };
+    let power = |on: bool| {
+        change(
+            "devices.capabilities.on_off",
+            "powerSwitch",
+            json!(if on { 1 } else { 0 }),
+        )
+    };
+
+    if command.state.as_deref() == Some("OFF") {
+        return vec![power(false)];
+    }
+
+    let mut changes = Vec::new();
+    if let Some(brightness) = command.brightness {
+        changes.push(change(
+            "devices.capabilities.brightness",
+            "brightness",
+            json!(brightness.clamp(1, 100)),
+        ));
+    }
+    if let Some(Rgb { r, g, b }) = command.color {
+        changes.push(change(
+            "devices.capabilities.color_setting",
+            "colorRgb",
+            json!(((r & 0xff) << 16) | ((g & 0xff) << 8) | (b & 0xff)),
+        ));
+    }
+    if let Some(kelvin) = command.color_temp {
+        changes.push(change(
+            "devices.capabilities.color_setting",
+            "colorTemperatureK",
+            json!(kelvin),
+        ));
+    }
+    // Power on last so the light doesn't flash at its previous setting.
+    if command.state.as_deref() == Some("ON") {
+        changes.push(power(true));
+    }
+
+    changes
+}
+
+async fn publish<R: Runtime>(app: &AppHandle<R>, client: &AsyncClient, device: &str) {
+    let config = load_config(app);
+    let base = format!("{}/{}", config.base_topic, topic_id(device));
+
+    let online = !presence::is_offline(app, device);
+    let _ = client
+        .publish(
+            format!("{}/availability", base),
+            QoS::AtLeastOnce,
+            true,
+            if online { "online" } else { "offline" },
+        )
+        .await;
+
+    if let Some(state) = cache::state(app, device) {
+        let _ = client
+            .publish(
+                format!("{}/state", base),
+                QoS::AtLeastOnce,
+                true,
+                state_payload(&state).to_string(),
+            )
+            .await;
+    }
+}
+
+/// Announces every device and subscribes to commands. Runs on every (re)connect and when
+/// Home Assistant comes back online, since it may have lost the retained configs.
+async fn announce<R: Runtime>(app: &AppHandle<R>, client: &AsyncClient) {
+    let config = load_config(app);
+
+    let mut devices = cache::devices(app);
+    if devices.is_empty() {
+        devices = get_devices(app.clone()).await.unwrap_or_default();
+    }
+
+    let _ = client
+        .publish(
+            format!("{}/status", config.base_topic),
+            QoS::AtLeastOnce,
+            true,
+            "online",
+        )
+        .await;
+
+    for device in &devices {
+        for (topic, payload) in discovery(&config, device) {
+            let _ = client
+                .publish(topic, QoS::AtLeastOnce, true, payload.to_string())
+                .await;
+        }
+        publish(app, client, &device.device).await;
+    }
+
+    let _ = client
+        .subscribe(format!("{}/+/set", config.base_topic), QoS::AtLeastOnce)
+        .await;
+    let _ = client
+        .subscribe(
+            format!("{}/status", config.discovery_prefix),
+            QoS::AtLeastOnce,
+        )
+        .await;
+}
+
+async fn handle_message<R: Runtime>(
+    app: &AppHandle<R>,
+    client: &AsyncClient,
+    topic: &str,
+    payload: &[u8],
+) -> Result<(), String> {
+    let config = load_config(app);
+
+    if topic == format!("{}/status", config.discovery_prefix) {
+        if payload == b"online" {
+            announce(app, client).await;
+        }
+        return Ok(());
+    }
+
+    let Some(id) = topic
+        .strip_prefix(&format!("{}/", config.base_topic))
+        .and_then(|rest| rest.strip_suffix("/set"))
+    else {
+        return Ok(());
+    };
+
+    let device = cache::devices(app)
+        .into_iter()
+        .find(|d| topic_id(&d.device) == id)
+        .ok_or_else(|| format!("Unknown device {}", id))?;
+    let command: Command = serde_json::from_slice(payload)
+        .map_err(|e| format!("Invalid command for {}: {}", device.display_name(), e))?;
+
+    apply_changes(app, command_changes(&device, command)).await
+}
+
+fn options(config: &MqttConfig) -> MqttOptions {
+    let client_id = format!("{}-{}", config.base_topic, std::process::id());
+    let mut options = MqttOptions::new(client_id, &config.host, config.port);
+
+    options.set_keep_alive(KEEP_ALIVE);
+    options.set_last_will(LastWill::new(
+        format!("{}/status", config.base_topic),
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if let Some(username) = &config.username {
+        options.set_credentials(username, &config.password);
+    }
+
+    options
+}
+
+fn connect<R: Runtime>(app: &AppHandle<R>) {
+    let config = load_config(app);
+    let (client, mut eventloop) = AsyncClient::new(options(&config), QUEUE_SIZE);
+
+    // Publishing waits for the event loop, so the work triggered by incoming packets runs in
+    // its own tasks instead of blocking the loop that has to send it.
+    let handle = app.clone();
+    let sender = client.clone();
+    let connection = tauri::async_runtime::spawn(async move {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    set_status(&handle, true, None);
+
+                    let (app, client) = (handle.clone(), sender.clone());
+                    tauri::async_runtime::spawn(async move { announce(&app, &client).await });
+                }
+                Ok(Event::Incoming(Packet::Publish(message))) => {
+                    let (app, client) = (handle.clone(), sender.clone());
+                    tauri::async_runtime::spawn(async move {
+                        if let Err(e) =
+                            handle_message(&app, &client, &message.topic, &message.payload).await
+                        {
+                            eprintln!("MQTT command failed: {}", e);
+                        }
+                    });
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    set_status(&handle, false, Some(e.to_string()));
+
+                    // The next poll reconnects.
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            }
+        }
+    });
+
+    let handle = app.clone();
+    let updates = tauri::async_runtime::spawn(async move {
+        let mut events = events::subscribe(&handle);
+
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    let relevant = matches!(
+                        event.payload,
+                        Payload::State { .. } | Payload::Sensor { .. } | Payload::Online { .. }
+                    );
+                    if let (true, Some(device)) = (relevant, &event.device) {
+                        publish(&handle, &client, device).await;
+                    }
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {}
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+
+    *app.state::<MqttState>().tasks.lock().unwrap() = vec![connection, updates];
+}
+
+fn disconnect<R: Runtime>(app: &AppHandle<R>) {
+    for task in app.state::<MqttState>().tasks.lock().unwrap().drain(..) {
+        task.abort();
+    }
+
+    set_status(app, false, None);
+}
+
+pub fn start<R: Runtime>(app_handle: &AppHandle<R>) {
+    app_handle.manage(MqttState::default());
+
+    if load_config(app_handle).enabled {
+        connect(app_handle);
+    }
+}
+
+#[tauri::command]
+pub fn get_mqtt_config<R: Runtime>(app: AppHandle<R>) -> MqttConfig {
+    load_config(&app)
+}
+
+/// Saves the bridge settings and reconnects. `password` replaces the stored password,
+/// an empty one removes it, and `None` keeps it.
+#[tauri::command]
+pub fn set_mqtt_config<R: Runtime>(
+    app: AppHandle<R>,
+    mut config: MqttConfig,
+    password: Option<String>,
+) -> Result<(), String> {
+    if config.host.trim().is_empty() {
+        return Err("Broker host cannot be empty".to_string());
+    }
+    if config.base_topic.trim().is_empty() || config.discovery_prefix.trim().is_empty() {
+        return Err("Topics cannot be empty".to_string());
+    }
+
+    config.password = match password {
+        Some(password) if password.is_empty() => {
+            secrets::delete(&app, PASSWORD_ACCOUNT)?;
+            password
+        }
+        Some(password) => {
+            secrets::set(&app, PASSWORD_ACCOUNT, &password)?;
+            password
+        }
+        None => load_config(&app).password,
+    };
+
+    settings::update(&app, |s| {
+        s.mqtt = config.clone();
+        Ok(())
+    })?;
+
+    disconnect(&app);
+    if config.enabled {
+        connect(&app);
+    }
+    poller::wake(&app);
+
+    Ok(())
+}
+
+#[tauri::command]
+pub fn get_mqtt_status<R: Runtime>(app: AppHandle<R>) -> MqttStatus {
+    app.state::<MqttState>().status.lock().unwrap().clone()
+}
@@ -0,0 +1,571 @@
+//! Outgoing webhooks: JSON posted to other systems when backend events match a trigger,
+//! such as a light switching on or a sensor crossing a threshold. The body is a template
+//! filled in from the event; failed deliveries are retried with backoff and each target
+//! has its own rate limit.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tauri::{AppHandle, Manager, Runtime};
+use tauri_plugin_store::StoreExt;
+use tokio::sync::broadcast::error::RecvError;
+use uuid::Uuid;
+
+use crate::alerts::Comparison;
+use crate::cache;
+use crate::events::{self, Event, Payload};
+use crate::history::RECORDED_INSTANCES;
+use crate::poller;
+use crate::settings::{self, SETTINGS_FILE};
+use crate::webhooks::{lookup, text};
+
+const HISTORY_KEY: &str = "outgoing_webhook_log";
+const MAX_HISTORY_ENTRIES: usize = 200;
+const MAX_ATTEMPTS: u32 = 4;
+/// Doubled after each failed attempt.
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+/// Deliveries dropped by the rate limit are logged at most this often per webhook.
+const DROP_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Serializes writes to the delivery history, since deliveries finish concurrently.
+static HISTORY_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Trigger {
+    /// A device switching on or off. `on` narrows it to one direction.
+    Power {
+        #[serde(default)]
+        devices: Vec<String>,
+        #[serde(default)]
+        on: Option<bool>,
+    },
+    /// Any change of the given capability instances, or of any instance when empty.
+    State {
+        #[serde(default)]
+        devices: Vec<String>,
+        #[serde(default)]
+        instances: Vec<String>,
+    },
+    /// A sensor reading crossing the threshold. Fires once per crossing, not while the
+    /// reading stays past it.
+    Threshold {
+        device: String,
+        /// `sensorTemperature` (in °C) or `sensorHumidity` (in %).
+        instance: String,
+        comparison: Comparison,
+        threshold: f64,
+    },
+    /// A device going offline or coming back. `online` narrows it to one direction.
+    Online {
+        #[serde(default)]
+        devices: Vec<String>,
+        #[serde(default)]
+        online: Option<bool>,
+    },
+}
+
+impl Trigger {
+    /// Whether the trigger depends on the state of `device`.
+    pub fn watches(&self, device: &str) -> bool {
+        match self {
+            Trigger::Power { devices, .. }
+            | Trigger::State { devices, .. }
+            | Trigger::Online { devices, .. } => {
+                devices.is_empty() || devices.iter().any(|d| d == device)
+            }
+            Trigger::Threshold { device: d, .. } => d == device,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutgoingWebhook {
+    #[serde(default)]
+    pub id: String,
+    pub name: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    pub url: String,
+    pub trigger: Trigger,
+    /// JSON with `{{path}}` placeholders looked up in the event, e.g. `{{device}}`,
+    /// `{{name}}` or `{{changes.powerSwitch}}`. A string that is just one placeholder
+    /// takes the value as is. Without a template the event itself is sent.
+    #[serde(default)]
+    pub body: Option<Value>,
+    /// Deliveries beyond this are dropped and logged.
+    #[serde(default = "default_max_per_minute")]
+    pub max_per_minute: u32,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_max_per_minute() -> u32 {
+    10
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutgoingDelivery {
+    pub webhook: String,
+    pub at: DateTime<Utc>,
+    /// Type of the event that triggered it.
+    pub event: String,
+    pub device: Option<String>,
+    /// Status of the last attempt, if the target answered.
+    pub status: Option<u16>,
+    pub attempts: u32,
+    /// Why the delivery failed, if it did.
+    pub error: Option<String>,
+}
+
+#[derive(Default)]
+pub struct OutgoingState {
+    /// Whether each threshold trigger's reading is past its threshold, by webhook id.
+    crossed: Mutex<HashMap<String, bool>>,
+    /// Recent deliveries, by webhook id.
+    sent: Mutex<HashMap<String, VecDeque<Instant>>>,
+    /// Rate-limited deliveries not logged yet, by webhook id.
+    dropped: Mutex<HashMap<String, Dropped>>,
+}
+
+#[derive(Default)]
+struct Dropped {
+    logged: Option<Instant>,
+    count: u32,
+}
+
+fn for_device(devices: &[String], event: &Event) -> bool {
+    devices.is_empty() || event.device.as_ref().is_some_and(|d| devices.contains(d))
+}
+
+impl OutgoingWebhook {
+    fn fires(&self, event: &Event, state: &OutgoingState) -> bool {
+        if !event.payload.is_change() {
+            return false;
+        }
+
+        match (&self.trigger, &event.payload) {
+            (Trigger::Power { devices, on }, Payload::State { changes, .. }) => {
+                let power = changes
+                    .get("powerSwitch")
+                    .and_then(|v| v.as_i64())
+                    .map(|v| v == 1);
+
+                for_device(devices, event)
+                    && power.is_some_and(|power| on.is_none_or(|on| on == power))
+            }
+            (Trigger::State { devices, instances }, Payload::State { changes, .. }) => {
+                for_device(devices, event)
+                    && (instances.is_empty() || instances.iter().any(|i| changes.contains_key(i)))
+            }
+            (
+                Trigger::Threshold {
+                    device,
+                    instance,
+                    comparison,
+                    threshold,
+                },
+                Payload::Sensor {
+                    temperature,
+                    humidity,
+                },
+            ) => {
+                let reading = match instance.as_str() {
+                    "sensorTemperature" => *temperature,
+                    _ => *humidity,
+                };
+                let Some(reading) = reading.filter(|_| event.device.as_ref() == Some(device))
+                else {
+                    return false;
+                };
+
+                let crossed = match comparison {
+                    Comparison::Above => reading > *threshold,
+                    Comparison::Below => reading < *threshold,
+                };
+                let before = state
+                    .crossed
+                    .lock()
+                    .unwrap()
+                    .insert(self.id.clone(), crossed);
+
+                // The first reading only sets where the sensor stands.
+                crossed && before == Some(false)
+            }
+            (Trigger::Online { devices, online }, Payload::Online { online: now }) => {
+                for_device(devices, event) && online.is_none_or(|online| online == *now)
+            }
+            _ => false,
+        }
+    }
+
+    /// Takes a slot in the rate limit window, or returns false when it is full.
+    fn take_slot(&self, state: &OutgoingState) -> bool {
+        let now = Instant::now();
+        let mut sent = state.sent.lock().unwrap();
+        let sent = sent.entry(self.id.clone()).or_default();
+
+        while sent
+            .front()
+            .is_some_and(|at| now.duration_since(*at) >= RATE_WINDOW)
+        {
+            sent.pop_front();
+        }
+
+        if sent.len() >= self.max_per_minute as usize {
+            return false;
+        }
+
+        sent.push_back(now);
+        true
+    }
+
+    /// Counts a delivery dropped by the rate limit. Once a minute it returns how many were
+    /// dropped since the last log entry, so a busy trigger doesn't rewrite the settings file
+    /// on every event.
+    fn note_dropped(&self, state: &OutgoingState) -> Option<u32> {
+        let now = Instant::now();
+        let mut dropped = state.dropped.lock().unwrap();
+        let dropped = dropped.entry(self.id.clone()).or_default();
+
+        dropped.count += 1;
+        if dropped
+            .logged
+            .is_some_and(|at| now.duration_since(at) < DROP_LOG_INTERVAL)
+        {
+            return None;
+        }
+
+        dropped.logged = Some(now);
+        Some(std::mem::take(&mut dropped.count))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("Webhook name cannot be empty".to_string());
+        }
+
+        let url = reqwest::Url::parse(&self.url).map_err(|e| format!("Invalid URL: {}", e))?;
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Err("Webhook URLs must use http or https".to_string());
+        }
+
+        if !(1..=600).contains(&self.max_per_minute) {
+            return Err("The rate limit must be between 1 and 600 per minute".to_string());
+        }
+
+        if let Trigger::Threshold {
+            instance,
+            threshold,
+            ..
+        } = &self.trigger
+        {
+            if !RECORDED_INSTANCES.contains(&instance.as_str()) {
+                return Err("Thresholds are only available for temperature and humidity".into());
+            }
+            if !threshold.is_finite() {
+                return Err("Invalid threshold".to_string());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Fills the `{{path}}` placeholders in `template` from `context`.
+fn render(template: &Value, context: &Value) -> Value {
+    match template {
+        Value::String(s) => {
+            let whole = s
+                .strip_prefix("{{")
+                .and_then(|s| s.strip_suffix("}}"))
+                .filter(|path| !path.contains("{{"));
+            if let Some(path) = whole {
+                return lookup(context, path.trim()).cloned().unwrap_or(Value::Null);
+            }
+
+            let mut out = String::new();
+            let mut rest = s.as_str();
+            while let Some((before, after)) = rest.split_once("{{") {
+                let Some((path, after)) = after.split_once("}}") else {
+                    break;
+                };
+                out.push_str(before);
+                out.push_str(&lookup(context, path.trim()).map(text).unwrap_or_default());
+                rest = after;
+            }
+            out.push_str(rest);
+
+            Value::String(out)
+        }
+        Value::Array(items) => Value::Array(items.iter().map(|v| render(v, context)).collect()),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(key, value)| (key.clone(), render(value, context)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+/// The event with the device's name and the webhook's name added.
+fn context<R: Runtime>(app: &AppHandle<R>, webhook: &OutgoingWebhook, event: &Event) -> Value {
+    let mut context = serde_json::to_value(event).unwrap_or_default();
+
+    let name = event.device.as_ref().and_then(|device| {
+        cache::devices(app)
+            .into_iter()
+            .find(|d| d.device == *device)
+            .map(|d| d.display_name().to_string())
+    });
+
+    if let Value::Object(fields) = &mut context {
+        fields.insert("name".to_string(), name.into());
+        fields.insert("webhook".to_string(), webhook.name.clone().into());
+    }
+
+    context
+}
+
+/// Posts `body`, retrying network errors, rate limiting and server errors. Returns the
+/// last status, the number of attempts and the error if every attempt failed.
+async fn post(url: &str, body: &Value) -> (Option<u16>, u32, Option<String>) {
+    let client = match reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(e) => return (None, 0, Some(e.to_string())),
+    };
+
+    let mut delay = RETRY_DELAY;
+    let mut attempt = 1;
+
+    loop {
+        let (status, error) = match client.post(url).json(body).send().await {
+            Ok(response) if response.status().is_success() => {
+                return (Some(response.status().as_u16()), attempt, None);
+            }
+            Ok(response) => {
+                let status = response.status();
+                let retry = status.is_server_error() || status.as_u16() == 429;
+                if !retry {
+                    return (Some(status.as_u16()), attempt, Some(status.to_string()));
+                }
+                (Some(status.as_u16()), status.to_string())
+            }
+            Err(e) => (None, e.to_string()),
+        };
+
+        if attempt == MAX_ATTEMPTS {
+            return (status, attempt, Some(error));
+        }
+
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+async fn deliver<R: Runtime>(app: AppHandle<R>, webhook: OutgoingWebhook, event: Event) {
+    let context = context(&app, &webhook, &event);
+    let body = match &webhook.body {
+        Some(template) => render(template, &context),
+        None => context,
+    };
+
+    let (status, attempts, error) = post(&webhook.url, &body).await;
+    if let Some(e) = &error {
+        eprintln!("Webhook \"{}\" failed: {}", webhook.name, e);
+    }
+
+    record(
+        &app,
+        OutgoingDelivery {
+            webhook: webhook.name,
+            at: Utc::now(),
+            event: event.payload.kind().to_string(),
+            device: event.device,
+            status,
+            attempts,
+            error,
+        },
+    );
+}
+
+fn handle<R: Runtime>(app: &AppHandle<R>, event: &Event) {
+    let state = app.state::<OutgoingState>();
+
+    for webhook in load_webhooks(app).into_iter().filter(|w| w.enabled) {
+        if !webhook.fires(event, &state) {
+            continue;
+        }
+
+        if !webhook.take_slot(&state) {
+            let Some(count) = webhook.note_dropped(&state) else {
+                continue;
+            };
+            let error = match count {
+                1 => "Rate limited".to_string(),
+                n => format!("Rate limited, {} deliveries dropped", n),
+            };
+            record(
+                app,
+                OutgoingDelivery {
+                    webhook: webhook.name,
+                    at: Utc::now(),
+                    event: event.payload.kind().to_string(),
+                    device: event.device.clone(),
+                    status: None,
+                    attempts: 0,
+                    error: Some(error),
+                },
+            );
+            continue;
+        }
+
+        tauri::async_runtime::spawn(deliver(app.clone(), webhook, event.clone()));
+    }
+}
+
+pub fn load_webhooks<R: Runtime>(app: &AppHandle<R>) -> Vec<OutgoingWebhook> {
+    settings::get(app).outgoing_webhooks
+}
+
+fn save_webhooks<R: Runtime>(
+    app: &AppHandle<R>,
+    webhooks: Vec<OutgoingWebhook>,
+) -> Result<(), String> {
+    settings::update(app, |s| {
+        s.outgoing_webhooks = webhooks;
+        Ok(())
+    })
+}
+
+fn load_history<R: Runtime>(app: &AppHandle<R>) -> Vec<OutgoingDelivery> {
+    app.store(SETTINGS_FILE)
+        .ok()
+        .and_then(|store| store.get(HISTORY_KEY))
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default()
+}
+
+fn save_history<R: Runtime>(
+    app: &AppHandle<R>,
+    history: &[OutgoingDelivery],
+) -> Result<(), String> {
+    let store = app.store(SETTINGS_FILE).map_err(|e| e.to_string())?;
+    let value = serde_json::to_value(history).map_err(|e| e.to_string())?;
+
+    store.set(HISTORY_KEY, value);
+    store
+        .save()
+        .map_err(|_| "Failed to save settings".to_string())
+}
+
+fn record<R: Runtime>(app: &AppHandle<R>, delivery: OutgoingDelivery) {
+    let _lock = HISTORY_LOCK.lock().unwrap();
+    let mut history = load_history(app);
+
+    history.push(delivery);
+    let excess = history.len().saturating_sub(MAX_HISTORY_ENTRIES);
+    history.drain(..excess);
+
+    if let Err(e) = save_history(app, &history) {
+        eprintln!("Failed to save webhook history: {}", e);
+    }
+}
+
+pub fn start<R: Runtime>(app_handle: &AppHandle<R>) {
+    app_handle.manage(OutgoingState::default());
+
+    let app = app_handle.clone();
+    tauri::async_runtime::spawn(async move {
+        let mut events = events::subscribe(&app);
+
+        loop {
+            match events.recv().await {
+                Ok(event) => handle(&app, &event),
+                Err(RecvError::Lagged(missed)) => {
+                    eprintln!("Outgoing webhooks missed {} events", missed);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+#[tauri::command]
+pub fn get_outgoing_webhooks<R: Runtime>(app: AppHandle<R>) -> Vec<OutgoingWebhook> {
+    load_webhooks(&app)
+}
+
+#[tauri::command]
+pub fn save_outgoing_webhook<R: Runtime>(
+    app: AppHandle<R>,
+    mut webhook: OutgoingWebhook,
+) -> Result<OutgoingWebhook, String> {
+    webhook.validate()?;
+
+    let mut webhooks = load_webhooks(&app);
+
+    if webhook.id.is_empty() {
+        webhook.id = Uuid::new_v4().to_string();
+    }
+
+    match webhooks.iter_mut().find(|w| w.id == webhook.id) {
+        Some(existing) => *existing = webhook.clone(),
+        None => webhooks.push(webhook.clone()),
+    }
+
+    save_webhooks(&app, webhooks)?;
+    poller::wake(&app);
+
+    // An edited threshold starts over from the next reading.
+    let state = app.state::<OutgoingState>();
+    state.crossed.lock().unwrap().remove(&webhook.id);
+
+    Ok(webhook)
+}
+
+#[tauri::command]
+pub fn delete_outgoing_webhook<R: Runtime>(app: AppHandle<R>, id: String) -> Result<(), String> {
+    let mut webhooks = load_webhooks(&app);
+    let count = webhooks.len();
+
+    webhooks.retain(|w| w.id != id);
+
+    if webhooks.len() == count {
+        return Err("Webhook not found".to_string());
+    }
+
+    let state = app.state::<OutgoingState>();
+    state.crossed.lock().unwrap().remove(&id);
+    state.sent.lock().unwrap().remove(&id);
+
+    save_webhooks(&app, webhooks)?;
+    poller::wake(&app);
+
+    Ok(())
+}
+
+/// Most recent deliveries first.
+#[tauri::command]
+pub fn get_outgoing_webhook_history<R: Runtime>(app: AppHandle<R>) -> Vec<OutgoingDelivery> {
+    let mut history = load_history(&app);
+    history.reverse();
+    history
+}
+
+#[tauri::command]
+pub fn clear_outgoing_webhook_history<R: Runtime>(app: AppHandle<R>) -> Result<(), String> {
+    let _lock = HISTORY_LOCK.lock().unwrap();
+
+    save_history(&app, &[])
+}
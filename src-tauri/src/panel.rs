@@ -0,0 +1,162 @@
+//! The panel that opens from the tray icon. On macOS it is an NSPanel that floats above
+//! full-screen apps (see `fns`). Elsewhere it is the frameless main window, placed next to
+//! the tray icon and hidden when it loses focus.
+
+use tauri::{AppHandle, PhysicalPosition};
+
+pub use imp::{hide, init, is_visible, show};
+
+/// Shows the panel if it is hidden and hides it otherwise. `anchor` is where the tray icon
+/// was clicked, if the platform reports it.
+pub fn toggle(app_handle: &AppHandle, anchor: Option<PhysicalPosition<f64>>) {
+    if is_visible(app_handle) {
+        hide(app_handle);
+    } else {
+        show(app_handle, anchor);
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod imp {
+    use tauri::{AppHandle, PhysicalPosition};
+    use tauri_nspanel::ManagerExt;
+
+    use crate::fns::{
+        position_menubar_panel, setup_menubar_panel_listeners, swizzle_to_menubar_panel,
+        update_menubar_appearance,
+    };
+
+    pub fn init(app_handle: &AppHandle) {
+        swizzle_to_menubar_panel(app_handle);
+
+        update_menubar_appearance(app_handle);
+
+        setup_menubar_panel_listeners(app_handle);
+    }
+
+    /// The panel always opens under the menubar at the mouse, so `anchor` isn't needed.
+    pub fn show(app_handle: &AppHandle, _anchor: Option<PhysicalPosition<f64>>) {
+        let panel = app_handle.get_webview_panel("main").unwrap();
+
+        position_menubar_panel(app_handle, 0.0);
+
+        panel.show();
+    }
+
+    pub fn hide(app_handle: &AppHandle) {
+        let panel = app_handle.get_webview_panel("main").unwrap();
+
+        panel.order_out(None);
+    }
+
+    pub fn is_visible(app_handle: &AppHandle) -> bool {
+        let panel = app_handle.get_webview_panel("main").unwrap();
+
+        panel.is_visible()
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+mod imp {
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    use tauri::{AppHandle, Manager, PhysicalPosition, WebviewWindow, WindowEvent};
+
+    // Gap between the panel and the tray icon, in physical pixels.
+    const MARGIN: i32 = 8;
+
+    // Clicking the tray icon while the panel is open takes focus away from it, which hides
+    // it before the click arrives. A click this soon after is treated as that same click so
+    // it doesn't open the panel again straight away.
+    const REOPEN_DELAY: Duration = Duration::from_millis(250);
+
+    static LAST_HIDDEN: Mutex<Option<Instant>> = Mutex::new(None);
+
+    fn window(app_handle: &AppHandle) -> WebviewWindow {
+        app_handle.get_webview_window("main").unwrap()
+    }
+
+    pub fn init(app_handle: &AppHandle) {
+        let window = window(app_handle);
+
+        let _ = window.set_skip_taskbar(true);
+        let _ = window.set_always_on_top(true);
+
+        let handle = app_handle.clone();
+
+        window.on_window_event(move |event| {
+            if let WindowEvent::Focused(false) = event {
+                hide(&handle);
+            }
+        });
+    }
+
+    /// Places the window next to `anchor`, or the mouse when the platform doesn't report
+    /// where the tray icon was clicked (Linux).
+    fn position(window: &WebviewWindow, anchor: Option<PhysicalPosition<f64>>) {
+        let Some(anchor) = anchor.or_else(|| window.cursor_position().ok()) else {
+            return;
+        };
+        let Ok(size) = window.outer_size() else {
+            return;
+        };
+        let Some(monitor) = window
+            .monitor_from_point(anchor.x, anchor.y)
+            .ok()
+            .flatten()
+            .or_else(|| window.primary_monitor().ok().flatten())
+        else {
+            return;
+        };
+
+        let (width, height) = (size.width as i32, size.height as i32);
+        let (left, top) = (monitor.position().x, monitor.position().y);
+        let right = left + monitor.size().width as i32;
+        let bottom = top + monitor.size().height as i32;
+        let (x, y) = (anchor.x as i32, anchor.y as i32);
+
+        // Below a tray at the top of the screen, above a taskbar at the bottom.
+        let y = if y < top + (bottom - top) / 2 {
+            y + MARGIN
+        } else {
+            y - height - MARGIN
+        };
+
+        let _ = window.set_position(PhysicalPosition::new(
+            (x - width / 2).clamp(left, (right - width).max(left)),
+            y.clamp(top, (bottom - height).max(top)),
+        ));
+    }
+
+    pub fn show(app_handle: &AppHandle, anchor: Option<PhysicalPosition<f64>>) {
+        let just_hidden = LAST_HIDDEN
+            .lock()
+            .unwrap()
+            .is_some_and(|at| at.elapsed() < REOPEN_DELAY);
+        if just_hidden && anchor.is_some() {
+            return;
+        }
+
+        let window = window(app_handle);
+
+        position(&window, anchor);
+
+        let _ = window.show();
+        let _ = window.set_focus();
+    }
+
+    pub fn hide(app_handle: &AppHandle) {
+        let window = window(app_handle);
+
+        if window.is_visible().unwrap_or(false) {
+            *LAST_HIDDEN.lock().unwrap() = Some(Instant::now());
+        }
+
+        let _ = window.hide();
+    }
+
+    pub fn is_visible(app_handle: &AppHandle) -> bool {
+        window(app_handle).is_visible().unwrap_or(false)
+    }
+}
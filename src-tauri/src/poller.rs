@@ -0,0 +1,136 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tauri::{AppHandle, Manager, Runtime};
+use tokio::sync::Notify;
+
+use crate::alerts;
+use crate::cache;
+use crate::command::{get_device_state, get_devices};
+use crate::history;
+use crate::homekit;
+use crate::mqtt;
+use crate::outgoing;
+use crate::presence;
+use crate::profiles;
+use crate::scheduler::DeviceTarget;
+use crate::sensors;
+use crate::server;
+use crate::tray;
+
+// Thermo-hygrometers report every few minutes at most, so polling faster only costs quota.
+const POLL_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const QUOTA_RESERVE: u32 = 500;
+// Picks up devices added, renamed or removed in the Govee app.
+const DEVICE_LIST_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Keeps the devices the backend relies on fresh while the panel is closed.
+#[derive(Default)]
+pub struct Poller {
+    wake: Notify,
+    devices_fetched: Mutex<Option<Instant>>,
+}
+
+/// Devices to poll, each once.
+fn watched<R: Runtime>(app: &AppHandle<R>) -> Vec<DeviceTarget> {
+    let mut targets = sensors::load_tray_sensors(app).devices;
+
+    for rule in alerts::load_rules(app) {
+        if rule.enabled {
+            targets.push(DeviceTarget {
+                device: rule.device,
+                sku: rule.sku,
+            });
+        }
+    }
+
+    let pinned = tray::load_pinned_devices(app);
+    let recording = history::load_config(app).enabled;
+    let tracking = presence::load_offline_notifications(app).enabled;
+    let bridged = mqtt::load_config(app).enabled || homekit::load_config(app).enabled;
+    let api = server::load_config(app);
+    let exported = api.enabled && api.metrics;
+    let triggers: Vec<_> = outgoing::load_webhooks(app)
+        .into_iter()
+        .filter(|w| w.enabled)
+        .map(|w| w.trigger)
+        .collect();
+
+    for device in cache::devices(app) {
+        if tracking
+            || bridged
+            || exported
+            || triggers.iter().any(|t| t.watches(&device.device))
+            || pinned.contains(&device.device)
+            || (recording && history::is_sensor(&device))
+        {
+            targets.push(DeviceTarget {
+                device: device.device,
+                sku: device.sku,
+            });
+        }
+    }
+
+    let mut seen = std::collections::HashSet::new();
+    targets.retain(|t| seen.insert(t.device.clone()));
+    targets
+}
+
+async fn poll<R: Runtime>(app: &AppHandle<R>) {
+    // Pinned devices and sensor names come from the device list.
+    let poller = app.state::<Poller>();
+    let stale = poller
+        .devices_fetched
+        .lock()
+        .unwrap()
+        .is_none_or(|at| at.elapsed() >= DEVICE_LIST_INTERVAL);
+    if stale || cache::devices(app).is_empty() {
+        match get_devices(app.clone()).await {
+            Ok(_) => *poller.devices_fetched.lock().unwrap() = Some(Instant::now()),
+            Err(e) => {
+                eprintln!("Failed to refresh devices: {}", e);
+                // The cached list is still good enough to poll.
+                if cache::devices(app).is_empty() {
+                    return;
+                }
+            }
+        }
+    }
+
+    for target in watched(app) {
+        if profiles::remaining_for(app, &target.device) < QUOTA_RESERVE {
+            continue;
+        }
+
+        // get_device_state updates the cache, which redraws the tray.
+        if let Err(e) = get_device_state(app.clone(), target.device.clone(), target.sku).await {
+            eprintln!("Failed to refresh {}: {}", target.device, e);
+        }
+    }
+}
+
+/// Polls right away instead of waiting for the next interval, e.g. after the watched
+/// devices changed.
+pub fn wake<R: Runtime>(app: &AppHandle<R>) {
+    if let Some(poller) = app.try_state::<Poller>() {
+        poller.wake.notify_one();
+    }
+}
+
+pub fn start<R: Runtime>(app_handle: &AppHandle<R>) {
+    app_handle.manage(Poller::default());
+
+    let app = app_handle.clone();
+
+    tauri::async_runtime::spawn(async move {
+        loop {
+            poll(&app).await;
+
+            let poller = app.state::<Poller>();
+            tokio::select! {
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                _ = poller.wake.notified() => {}
+            }
+        }
+    });
+}
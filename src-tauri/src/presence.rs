@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration as StdDuration;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter, Manager, Runtime};
+use tauri_plugin_notification::NotificationExt;
+use tauri_plugin_store::StoreExt;
+
+use crate::cache;
+use crate::command::DeviceState;
+use crate::events::{self, Payload};
+use crate::settings::{self, SETTINGS_FILE};
+use crate::tray;
+
+const LAST_SEEN_KEY: &str = "device_last_seen";
+const TICK_INTERVAL: StdDuration = StdDuration::from_secs(60);
+// Last-seen times are written on every online/offline transition and otherwise at most this
+// often, since every state fetch updates them.
+const SAVE_INTERVAL_MINUTES: i64 = 10;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OfflineNotifications {
+    pub enabled: bool,
+    /// How long a device must stay offline before notifying.
+    pub after_minutes: u32,
+}
+
+impl Default for OfflineNotifications {
+    fn default() -> Self {
+        OfflineNotifications {
+            enabled: false,
+            after_minutes: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Presence {
+    /// `None` until the device's state has been fetched this session.
+    pub online: Option<bool>,
+    pub last_seen: Option<DateTime<Utc>>,
+    pub offline_since: Option<DateTime<Utc>>,
+    #[serde(skip)]
+    notified: bool,
+}
+
+#[derive(Default)]
+pub struct PresenceState {
+    devices: Mutex<HashMap<String, Presence>>,
+    last_saved: Mutex<Option<DateTime<Utc>>>,
+}
+
+fn load_last_seen<R: Runtime>(app: &AppHandle<R>) -> HashMap<String, DateTime<Utc>> {
+    app.store(SETTINGS_FILE)
+        .ok()
+        .and_then(|store| store.get(LAST_SEEN_KEY))
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default()
+}
+
+fn save_last_seen<R: Runtime>(app: &AppHandle<R>, devices: &HashMap<String, Presence>) {
+    let last_seen: HashMap<&String, DateTime<Utc>> = devices
+        .iter()
+        .filter_map(|(id, p)| p.last_seen.map(|at| (id, at)))
+        .collect();
+
+    let Ok(store) = app.store(SETTINGS_FILE) else {
+        return;
+    };
+    store.set(LAST_SEEN_KEY, serde_json::json!(last_seen));
+    if store.save().is_err() {
+        eprintln!("Failed to save last-seen times");
+    }
+}
+
+pub fn load_offline_notifications<R: Runtime>(app: &AppHandle<R>) -> OfflineNotifications {
+    settings::get(app).offline_notifications
+}
+
+pub fn get<R: Runtime>(app: &AppHandle<R>, device: &str) -> Presence {
+    app.try_state::<PresenceState>()
+        .and_then(|state| state.devices.lock().unwrap().get(device).cloned())
+        .unwrap_or_default()
+}
+
+pub fn is_offline<R: Runtime>(app: &AppHandle<R>, device: &str) -> bool {
+    get(app, device).online == Some(false)
+}
+
+/// Records whether a device answered as online. Devices that don't report the `online`
+/// capability count as online whenever their state can be fetched.
+pub fn observe<R: Runtime>(app: &AppHandle<R>, state: &DeviceState) {
+    let online = state
+        .value("online")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(true);
+
+    set_online(app, &state.device, online);
+}
+
+pub fn set_online<R: Runtime>(app: &AppHandle<R>, device: &str, online: bool) {
+    let Some(presence) = app.try_state::<PresenceState>() else {
+        return;
+    };
+    let now = Utc::now();
+
+    let changed = {
+        let mut devices = presence.devices.lock().unwrap();
+        let entry = devices.entry(device.to_string()).or_default();
+        let changed = entry.online != Some(online);
+
+        entry.online = Some(online);
+        if online {
+            entry.last_seen = Some(now);
+            entry.offline_since = None;
+            entry.notified = false;
+        } else if entry.offline_since.is_none() {
+            entry.offline_since = Some(now);
+        }
+
+        let entry = entry.clone();
+
+        let mut last_saved = presence.last_saved.lock().unwrap();
+        if changed
+            || last_saved.is_none_or(|at| now - at >= Duration::minutes(SAVE_INTERVAL_MINUTES))
+        {
+            save_last_seen(app, &devices);
+            *last_saved = Some(now);
+        }
+
+        changed.then_some(entry)
+    };
+
+    if let Some(entry) = changed {
+        events::publish(app, Some(device), Payload::Online { online });
+
+        if let Err(e) = tray::refresh_menu(app) {
+            eprintln!("Failed to update tray menu: {}", e);
+        }
+
+        let _ = app.emit(
+            "device_presence_changed",
+            serde_json::json!({ "device": device, "presence": entry }),
+        );
+    }
+}
+
+fn notify_offline<R: Runtime>(app: &AppHandle<R>, device: &str, since: DateTime<Utc>) {
+    let name = cache::devices(app)
+        .into_iter()
+        .find(|d| d.device == device)
+        .map(|d| d.display_name().to_string())
+        .unwrap_or_else(|| device.to_string());
+    let minutes = (Utc::now() - since).num_minutes();
+
+    if let Err(e) = app
+        .notification()
+        .builder()
+        .title(format!("{} is offline", name))
+        .body(format!("Unreachable for {} minutes", minutes))
+        .show()
+    {
+        eprintln!("Failed to show offline notification: {}", e);
+    }
+}
+
+fn tick<R: Runtime>(app: &AppHandle<R>) {
+    let config = load_offline_notifications(app);
+    if !config.enabled {
+        return;
+    }
+
+    let threshold = Duration::minutes(config.after_minutes.into());
+    let now = Utc::now();
+
+    let due: Vec<(String, DateTime<Utc>)> = {
+        let state = app.state::<PresenceState>();
+        let mut devices = state.devices.lock().unwrap();
+
+        devices
+            .iter_mut()
+            .filter_map(|(id, p)| {
+                let since = p.offline_since?;
+                if p.notified || now - since < threshold {
+                    return None;
+                }
+                p.notified = true;
+                Some((id.clone(), since))
+            })
+            .collect()
+    };
+
+    for (device, since) in due {
+        notify_offline(app, &device, since);
+    }
+}
+
+pub fn start<R: Runtime>(app_handle: &AppHandle<R>) {
+    let devices = load_last_seen(app_handle)
+        .into_iter()
+        .map(|(id, at)| {
+            (
+                id,
+                Presence {
+                    last_seen: Some(at),
+                    ..Default::default()
+                },
+            )
+        })
+        .collect();
+
+    app_handle.manage(PresenceState {
+        devices: Mutex::new(devices),
+        last_saved: Mutex::new(None),
+    });
+
+    let app = app_handle.clone();
+
+    tauri::async_runtime::spawn(async move {
+        loop {
+            tick(&app);
+
+            tokio::time::sleep(TICK_INTERVAL).await;
+        }
+    });
+}
+
+#[tauri::command]
+pub fn get_device_presence<R: Runtime>(app: AppHandle<R>) -> HashMap<String, Presence> {
+    app.state::<PresenceState>().devices.lock().unwrap().clone()
+}
+
+#[tauri::command]
+pub fn get_offline_notifications<R: Runtime>(app: AppHandle<R>) -> OfflineNotifications {
+    load_offline_notifications(&app)
+}
+
+#[tauri::command]
+pub fn set_offline_notifications<R: Runtime>(
+    app: AppHandle<R>,
+    config: OfflineNotifications,
+) -> Result<(), String> {
+    if !(1..=7 * 24 * 60).contains(&config.after_minutes) {
+        return Err("Offline time must be between 1 minute and a week".to_string());
+    }
+
+    settings::update(&app, |s| {
+        s.offline_notifications = config;
+        Ok(())
+    })
+}
@@ -0,0 +1,87 @@
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Runtime};
+use uuid::Uuid;
+
+use crate::scheduler::{apply_changes, CapabilityChange};
+use crate::settings;
+use crate::tray;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Preset {
+    #[serde(default)]
+    pub id: String,
+    pub name: String,
+    pub changes: Vec<CapabilityChange>,
+}
+
+pub fn load_presets<R: Runtime>(app: &AppHandle<R>) -> Vec<Preset> {
+    settings::get(app).presets
+}
+
+fn save_presets<R: Runtime>(app: &AppHandle<R>, presets: Vec<Preset>) -> Result<(), String> {
+    settings::update(app, |s| {
+        s.presets = presets;
+        Ok(())
+    })?;
+
+    tray::refresh_menu(app).map_err(|e| e.to_string())
+}
+
+pub async fn apply<R: Runtime>(app: &AppHandle<R>, id: &str) -> Result<(), String> {
+    let preset = load_presets(app)
+        .into_iter()
+        .find(|p| p.id == id)
+        .ok_or("Preset not found")?;
+
+    apply_changes(app, preset.changes).await
+}
+
+#[tauri::command]
+pub fn get_presets<R: Runtime>(app: AppHandle<R>) -> Vec<Preset> {
+    load_presets(&app)
+}
+
+#[tauri::command]
+pub fn save_preset<R: Runtime>(app: AppHandle<R>, mut preset: Preset) -> Result<Preset, String> {
+    if preset.name.trim().is_empty() {
+        return Err("Preset name cannot be empty".to_string());
+    }
+
+    if preset.changes.is_empty() {
+        return Err("Preset needs at least one change".to_string());
+    }
+
+    let mut presets = load_presets(&app);
+
+    if preset.id.is_empty() {
+        preset.id = Uuid::new_v4().to_string();
+    }
+
+    match presets.iter_mut().find(|p| p.id == preset.id) {
+        Some(existing) => *existing = preset.clone(),
+        None => presets.push(preset.clone()),
+    }
+
+    save_presets(&app, presets)?;
+
+    Ok(preset)
+}
+
+#[tauri::command]
+pub fn delete_preset<R: Runtime>(app: AppHandle<R>, id: String) -> Result<(), String> {
+    let mut presets = load_presets(&app);
+    let count = presets.len();
+
+    presets.retain(|p| p.id != id);
+
+    if presets.len() == count {
+        return Err("Preset not found".to_string());
+    }
+
+    save_presets(&app, presets)
+}
+
+#[tauri::command]
+pub async fn apply_preset<R: Runtime>(app: AppHandle<R>, id: String) -> Result<(), String> {
+    apply(&app, &id).await
+}
@@ -0,0 +1,193 @@
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Runtime};
+use uuid::Uuid;
+
+use crate::cache;
+use crate::command::{get_devices, verify_api_key};
+use crate::ratelimit;
+use crate::secrets;
+use crate::settings;
+
+/// Id of the profile created from the single `api_key` of older versions, and used by
+/// `get_api_key`/`set_api_key`.
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// One Govee account. The key is kept in the keyring, not in the settings file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Profile {
+    pub id: String,
+    pub name: String,
+    #[serde(default, skip_serializing)]
+    pub api_key: String,
+}
+
+/// A profile as shown to the panel, without its key.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProfileInfo {
+    pub id: String,
+    pub name: String,
+    pub requests_remaining: u32,
+}
+
+impl From<&Profile> for ProfileInfo {
+    fn from(profile: &Profile) -> Self {
+        ProfileInfo {
+            id: profile.id.clone(),
+            name: profile.name.clone(),
+            requests_remaining: ratelimit::remaining(&profile.id),
+        }
+    }
+}
+
+/// All profiles with their keys.
+pub fn load_profiles<R: Runtime>(app: &AppHandle<R>) -> Vec<Profile> {
+    settings::get(app).profiles
+}
+
+pub fn save_profiles<R: Runtime>(app: &AppHandle<R>, profiles: Vec<Profile>) -> Result<(), String> {
+    for profile in profiles.iter().filter(|p| !p.api_key.is_empty()) {
+        secrets::set(app, &profile.id, &profile.api_key)?;
+    }
+
+    settings::update(app, |s| {
+        s.profiles = profiles;
+        Ok(())
+    })
+}
+
+fn no_profiles() -> String {
+    "API key not set. Please set your Govee API key first.".to_string()
+}
+
+fn cached_profile<R: Runtime>(
+    app: &AppHandle<R>,
+    profiles: &[Profile],
+    device: &str,
+) -> Option<Profile> {
+    let id = cache::devices(app)
+        .into_iter()
+        .find(|d| d.device == device)?
+        .profile;
+
+    profiles.iter().find(|p| p.id == id).cloned()
+}
+
+/// The profile whose account owns `device`. Reloads the device list once if the device
+/// hasn't been seen yet and there is more than one profile to choose from.
+pub async fn for_device<R: Runtime>(app: &AppHandle<R>, device: &str) -> Result<Profile, String> {
+    let profiles = load_profiles(app);
+
+    match profiles.as_slice() {
+        [] => return Err(no_profiles()),
+        [only] => return Ok(only.clone()),
+        _ => {}
+    }
+
+    if let Some(profile) = cached_profile(app, &profiles, device) {
+        return Ok(profile);
+    }
+
+    get_devices(app.clone()).await?;
+
+    cached_profile(app, &profiles, device)
+        .ok_or_else(|| format!("Device {} not found in any profile", device))
+}
+
+/// Requests left today on the account that owns `device`, as far as the cache knows.
+pub fn remaining_for<R: Runtime>(app: &AppHandle<R>, device: &str) -> u32 {
+    let profiles = load_profiles(app);
+    let profile = match profiles.as_slice() {
+        [only] => Some(only.clone()),
+        _ => cached_profile(app, &profiles, device),
+    };
+
+    match profile {
+        Some(profile) => ratelimit::remaining(&profile.id),
+        None => ratelimit::DAILY_LIMIT,
+    }
+}
+
+#[tauri::command]
+pub fn get_profiles<R: Runtime>(app: AppHandle<R>) -> Vec<ProfileInfo> {
+    load_profiles(&app).iter().map(ProfileInfo::from).collect()
+}
+
+#[tauri::command]
+pub async fn add_profile<R: Runtime>(
+    app: AppHandle<R>,
+    name: String,
+    api_key: String,
+) -> Result<ProfileInfo, String> {
+    if name.trim().is_empty() {
+        return Err("Profile name cannot be empty".to_string());
+    }
+
+    let mut profiles = load_profiles(&app);
+    if profiles.iter().any(|p| p.api_key == api_key) {
+        return Err("A profile with this API key already exists".to_string());
+    }
+
+    let id = if profiles.is_empty() {
+        DEFAULT_PROFILE.to_string()
+    } else {
+        Uuid::new_v4().to_string()
+    };
+
+    verify_api_key(&app, &id, &api_key).await?;
+
+    let profile = Profile {
+        id,
+        name: name.trim().to_string(),
+        api_key,
+    };
+    profiles.push(profile.clone());
+    save_profiles(&app, profiles)?;
+
+    get_devices(app.clone()).await?;
+
+    Ok(ProfileInfo::from(&profile))
+}
+
+#[tauri::command]
+pub fn rename_profile<R: Runtime>(
+    app: AppHandle<R>,
+    id: String,
+    name: String,
+) -> Result<(), String> {
+    if name.trim().is_empty() {
+        return Err("Profile name cannot be empty".to_string());
+    }
+
+    let mut profiles = load_profiles(&app);
+    let profile = profiles
+        .iter_mut()
+        .find(|p| p.id == id)
+        .ok_or("Profile not found")?;
+
+    profile.name = name.trim().to_string();
+
+    save_profiles(&app, profiles)
+}
+
+#[tauri::command]
+pub async fn delete_profile<R: Runtime>(app: AppHandle<R>, id: String) -> Result<(), String> {
+    let mut profiles = load_profiles(&app);
+    let count = profiles.len();
+
+    profiles.retain(|p| p.id != id);
+
+    if profiles.len() == count {
+        return Err("Profile not found".to_string());
+    }
+
+    let empty = profiles.is_empty();
+    save_profiles(&app, profiles)?;
+    secrets::delete(&app, &id)?;
+
+    if empty {
+        cache::set_devices(&app, &[]);
+        Ok(())
+    } else {
+        get_devices(app).await.map(|_| ())
+    }
+}
@@ -0,0 +1,43 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use chrono::{NaiveDate, Utc};
+
+/// Requests the Govee OpenAPI allows per account per day.
+pub const DAILY_LIMIT: u32 = 10_000;
+
+#[derive(Default)]
+struct Usage {
+    day: Option<NaiveDate>,
+    used: u32,
+}
+
+/// Usage per profile, since each account has its own quota.
+static USAGE: Mutex<BTreeMap<String, Usage>> = Mutex::new(BTreeMap::new());
+
+impl Usage {
+    // The quota resets at midnight UTC.
+    fn roll_over(&mut self) {
+        let today = Utc::now().date_naive();
+
+        if self.day != Some(today) {
+            self.day = Some(today);
+            self.used = 0;
+        }
+    }
+}
+
+/// Counts one request against today's quota of `profile`.
+pub fn record(profile: &str) {
+    let mut usage = USAGE.lock().unwrap();
+    let usage = usage.entry(profile.to_string()).or_default();
+    usage.roll_over();
+    usage.used += 1;
+}
+
+pub fn remaining(profile: &str) -> u32 {
+    let mut usage = USAGE.lock().unwrap();
+    let usage = usage.entry(profile.to_string()).or_default();
+    usage.roll_over();
+    DAILY_LIMIT.saturating_sub(usage.used)
+}
@@ -0,0 +1,40 @@
+//! Rooms group devices for integrations, e.g. to follow one room on the WebSocket stream.
+//! A device is in at most one room.
+
+use std::collections::BTreeMap;
+
+use tauri::{AppHandle, Runtime};
+
+use crate::settings;
+
+/// Rooms by device id.
+pub fn load_rooms<R: Runtime>(app: &AppHandle<R>) -> BTreeMap<String, String> {
+    settings::get(app).rooms
+}
+
+pub fn room_of<R: Runtime>(app: &AppHandle<R>, device: &str) -> Option<String> {
+    load_rooms(app).remove(device)
+}
+
+#[tauri::command]
+pub fn get_device_rooms<R: Runtime>(app: AppHandle<R>) -> BTreeMap<String, String> {
+    load_rooms(&app)
+}
+
+/// Puts `device` in `room`, or takes it out of its room when `room` is empty.
+#[tauri::command]
+pub fn set_device_room<R: Runtime>(
+    app: AppHandle<R>,
+    device: String,
+    room: Option<String>,
+) -> Result<(), String> {
+    let room = room.map(|r| r.trim().to_string()).filter(|r| !r.is_empty());
+
+    settings::update(&app, |s| {
+        match room {
+            Some(room) => s.rooms.insert(device, room),
+            None => s.rooms.remove(&device),
+        };
+        Ok(())
+    })
+}
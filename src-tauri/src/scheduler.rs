@@ -0,0 +1,775 @@
+use std::collections::HashMap;
+use std::time::Duration as StdDuration;
+
+use chrono::{DateTime, Datelike, Duration, Local, LocalResult, NaiveDate, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Runtime};
+use tauri_plugin_store::StoreExt;
+use uuid::Uuid;
+
+use crate::circadian;
+use crate::command::send_capability;
+use crate::events::{self, AutomationKind};
+use crate::settings::{self, SETTINGS_FILE};
+use crate::solar::{self, Location, SolarEvent};
+
+const LAST_TICK_KEY: &str = "scheduler_last_tick";
+const TICK_INTERVAL: StdDuration = StdDuration::from_secs(30);
+// The last tick is only read after a restart, and `last_run` already keeps schedules from
+// firing twice, so it is saved every few minutes rather than on every tick.
+const LAST_TICK_SAVE_MINUTES: i64 = 10;
+
+// Runs missed by more than this (e.g. a laptop closed over the weekend) are dropped
+// instead of being replayed long after they stopped making sense.
+const CATCH_UP_WINDOW_HOURS: i64 = 12;
+
+/// Weekday bitmask: bit 0 is Monday, bit 6 is Sunday.
+pub const EVERY_DAY: u8 = 0b111_1111;
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Schedule {
+    #[serde(default)]
+    pub id: String,
+    pub name: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    pub trigger: Trigger,
+    #[serde(default = "default_weekdays")]
+    pub weekdays: u8,
+    pub action: ScheduleAction,
+    #[serde(default)]
+    pub last_run: Option<DateTime<Utc>>,
+    #[serde(default, skip_deserializing)]
+    pub next_run: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Trigger {
+    /// Fires at a fixed local wall-clock time.
+    At { hour: u32, minute: u32 },
+    /// Fires relative to a solar event at the configured location, recomputed each day.
+    Solar {
+        event: SolarEvent,
+        #[serde(default)]
+        offset_minutes: i32,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceTarget {
+    pub device: String,
+    pub sku: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CapabilityChange {
+    pub device: String,
+    pub sku: String,
+    pub capability_type: String,
+    pub instance: String,
+    pub value: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ScheduleAction {
+    /// A single capability change on one device.
+    Capability(CapabilityChange),
+    /// The same capability change applied to every device in `targets`.
+    Group {
+        targets: Vec<DeviceTarget>,
+        capability_type: String,
+        instance: String,
+        value: serde_json::Value,
+    },
+    /// A named set of changes applied in order.
+    Preset {
+        name: String,
+        changes: Vec<CapabilityChange>,
+    },
+}
+
+impl ScheduleAction {
+    pub fn changes(&self) -> Vec<CapabilityChange> {
+        match self {
+            ScheduleAction::Capability(change) => vec![change.clone()],
+            ScheduleAction::Group {
+                targets,
+                capability_type,
+                instance,
+                value,
+            } => targets
+                .iter()
+                .map(|target| CapabilityChange {
+                    device: target.device.clone(),
+                    sku: target.sku.clone(),
+                    capability_type: capability_type.clone(),
+                    instance: instance.clone(),
+                    value: value.clone(),
+                })
+                .collect(),
+            ScheduleAction::Preset { changes, .. } => changes.clone(),
+        }
+    }
+}
+
+impl Trigger {
+    /// The instant this trigger refers to on the given local date.
+    pub fn resolve_on<Tz: TimeZone>(
+        &self,
+        tz: &Tz,
+        location: Option<Location>,
+        date: NaiveDate,
+    ) -> Option<DateTime<Utc>> {
+        match *self {
+            Trigger::At { hour, minute } => {
+                resolve_local(tz, date, hour, minute).map(|dt| dt.with_timezone(&Utc))
+            }
+            Trigger::Solar {
+                event,
+                offset_minutes,
+            } => solar::event_time(location?, date, event)
+                .map(|at| at + Duration::minutes(offset_minutes.into())),
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        match self {
+            Trigger::At { hour, minute } if *hour > 23 || *minute > 59 => {
+                Err("Invalid schedule time".to_string())
+            }
+            Trigger::Solar { offset_minutes, .. } if offset_minutes.abs() > 12 * 60 => {
+                Err("Solar offset must be within 12 hours".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_weekdays() -> u8 {
+    EVERY_DAY
+}
+
+pub(crate) fn runs_on(weekdays: u8, date: NaiveDate) -> bool {
+    weekdays & (1 << date.weekday().num_days_from_monday()) != 0
+}
+
+/// Resolves a local wall-clock time on `date` to an instant in `tz`.
+///
+/// When the time falls in a DST gap it fires at the first valid minute after the gap, and
+/// when it occurs twice (clocks going back) only the earlier instant is used.
+fn resolve_local<Tz: TimeZone>(
+    tz: &Tz,
+    date: NaiveDate,
+    hour: u32,
+    minute: u32,
+) -> Option<DateTime<Tz>> {
+    let naive = date.and_hms_opt(hour, minute, 0)?;
+
+    match tz.from_local_datetime(&naive) {
+        LocalResult::Single(dt) => Some(dt),
+        LocalResult::Ambiguous(earliest, _) => Some(earliest),
+        LocalResult::None => (1..=180).find_map(|m| {
+            tz.from_local_datetime(&(naive + Duration::minutes(m)))
+                .earliest()
+        }),
+    }
+}
+
+impl Schedule {
+    /// The instant this schedule fires on the given local date, ignoring the weekday mask.
+    fn occurrence_on<Tz: TimeZone>(
+        &self,
+        tz: &Tz,
+        location: Option<Location>,
+        date: NaiveDate,
+    ) -> Option<DateTime<Utc>> {
+        self.trigger.resolve_on(tz, location, date)
+    }
+
+    /// The latest occurrence in `(after, until]`, if any.
+    pub fn latest_between<Tz: TimeZone>(
+        &self,
+        tz: &Tz,
+        location: Option<Location>,
+        after: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        if !self.enabled || until <= after {
+            return None;
+        }
+
+        let first = after.with_timezone(tz).date_naive().pred_opt()?;
+        let mut date = until.with_timezone(tz).date_naive().succ_opt()?;
+
+        while date >= first {
+            if runs_on(self.weekdays, date) {
+                if let Some(at) = self.occurrence_on(tz, location, date) {
+                    if at > after && at <= until {
+                        return Some(at);
+                    }
+                }
+            }
+            date = date.pred_opt()?;
+        }
+
+        None
+    }
+
+    /// The first occurrence strictly after `after`, searching up to a week ahead.
+    pub fn next_after<Tz: TimeZone>(
+        &self,
+        tz: &Tz,
+        location: Option<Location>,
+        after: DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        if !self.enabled || self.weekdays & EVERY_DAY == 0 {
+            return None;
+        }
+
+        let mut date = after.with_timezone(tz).date_naive().pred_opt()?;
+
+        for _ in 0..9 {
+            if runs_on(self.weekdays, date) {
+                if let Some(at) = self.occurrence_on(tz, location, date) {
+                    if at > after {
+                        return Some(at);
+                    }
+                }
+            }
+            date = date.succ_opt()?;
+        }
+
+        None
+    }
+}
+
+pub struct Scheduler<C: Clock, Tz: TimeZone> {
+    clock: C,
+    tz: Tz,
+    location: Option<Location>,
+}
+
+impl Scheduler<SystemClock, Local> {
+    pub fn system() -> Self {
+        Scheduler::new(SystemClock, Local)
+    }
+}
+
+impl<C: Clock, Tz: TimeZone> Scheduler<C, Tz> {
+    pub fn new(clock: C, tz: Tz) -> Self {
+        Scheduler {
+            clock,
+            tz,
+            location: None,
+        }
+    }
+
+    pub fn with_location(mut self, location: Option<Location>) -> Self {
+        self.location = location;
+        self
+    }
+
+    pub fn now(&self) -> DateTime<Utc> {
+        self.clock.now()
+    }
+
+    /// Returns the indices of schedules that came due since `last_tick`, together with the
+    /// occurrence each one is firing for. Each schedule fires at most once per call, so
+    /// several runs missed during sleep collapse into a single catch-up run.
+    pub fn due(
+        &self,
+        schedules: &[Schedule],
+        last_tick: DateTime<Utc>,
+    ) -> Vec<(usize, DateTime<Utc>)> {
+        let now = self.clock.now();
+        let window_start = now - Duration::hours(CATCH_UP_WINDOW_HOURS);
+
+        schedules
+            .iter()
+            .enumerate()
+            .filter_map(|(index, schedule)| {
+                let mut after = last_tick.max(window_start);
+                if let Some(last_run) = schedule.last_run {
+                    after = after.max(last_run);
+                }
+
+                schedule
+                    .latest_between(&self.tz, self.location, after, now)
+                    .map(|at| (index, at))
+            })
+            .collect()
+    }
+
+    pub fn with_next_runs(&self, mut schedules: Vec<Schedule>) -> Vec<Schedule> {
+        let now = self.clock.now();
+        for schedule in &mut schedules {
+            schedule.next_run = schedule.next_after(&self.tz, self.location, now);
+        }
+        schedules
+    }
+}
+
+pub fn load_schedules<R: Runtime>(app: &AppHandle<R>) -> Vec<Schedule> {
+    settings::get(app).schedules
+}
+
+fn save_schedules<R: Runtime>(app: &AppHandle<R>, schedules: Vec<Schedule>) -> Result<(), String> {
+    settings::update(app, |s| {
+        s.schedules = schedules;
+        Ok(())
+    })
+}
+
+fn load_last_tick<R: Runtime>(app: &AppHandle<R>) -> Option<DateTime<Utc>> {
+    let store = app.store(SETTINGS_FILE).ok()?;
+    store
+        .get(LAST_TICK_KEY)
+        .and_then(|v| serde_json::from_value(v).ok())
+}
+
+fn save_last_tick<R: Runtime>(app: &AppHandle<R>, at: DateTime<Utc>) -> Result<(), String> {
+    let store = app.store(SETTINGS_FILE).map_err(|e| e.to_string())?;
+
+    store.set(LAST_TICK_KEY, serde_json::json!(at));
+    store
+        .save()
+        .map_err(|_| "Failed to save settings".to_string())
+}
+
+pub async fn run_action<R: Runtime>(
+    app: &AppHandle<R>,
+    action: &ScheduleAction,
+) -> Result<(), String> {
+    send_changes(app, action.changes()).await
+}
+
+/// Applies changes someone asked for, through the panel, a preset or an integration. Like
+/// any manual change they pause circadian mode on the device.
+pub async fn apply_changes<R: Runtime>(
+    app: &AppHandle<R>,
+    changes: Vec<CapabilityChange>,
+) -> Result<(), String> {
+    send_each(app, changes, true).await
+}
+
+/// Applies changes for an automation, leaving circadian mode alone.
+pub async fn send_changes<R: Runtime>(
+    app: &AppHandle<R>,
+    changes: Vec<CapabilityChange>,
+) -> Result<(), String> {
+    send_each(app, changes, false).await
+}
+
+/// Sends each change in order, carrying on past failures so one offline device doesn't
+/// block the rest.
+async fn send_each<R: Runtime>(
+    app: &AppHandle<R>,
+    changes: Vec<CapabilityChange>,
+    manual: bool,
+) -> Result<(), String> {
+    let mut errors = Vec::new();
+
+    for change in changes {
+        let device = change.device.clone();
+        let sent = send_capability(
+            app,
+            change.device,
+            change.sku,
+            change.capability_type,
+            change.instance,
+            change.value,
+        )
+        .await;
+
+        match sent {
+            Ok(()) if manual => circadian::note_manual_change(app, &device),
+            Ok(()) => {}
+            Err(e) => errors.push(format!("{}: {}", device, e)),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("; "))
+    }
+}
+
+/// What the scheduler has done so far. It is kept in memory so runs that couldn't be saved
+/// still don't fire again.
+#[derive(Debug, Default)]
+struct Progress {
+    last_tick: Option<DateTime<Utc>>,
+    last_runs: HashMap<String, DateTime<Utc>>,
+}
+
+impl Progress {
+    /// Copies the runs recorded here onto `schedules` where they are newer.
+    fn apply(&self, schedules: &mut [Schedule]) {
+        for schedule in schedules {
+            if let Some(at) = self.last_runs.get(&schedule.id) {
+                schedule.last_run = schedule.last_run.max(Some(*at));
+            }
+        }
+    }
+
+    /// Returns the indices of the schedules that came due since the last tick, recording
+    /// them as run.
+    fn advance<C: Clock, Tz: TimeZone>(
+        &mut self,
+        scheduler: &Scheduler<C, Tz>,
+        schedules: &mut [Schedule],
+    ) -> Vec<usize> {
+        let now = scheduler.now();
+        self.apply(schedules);
+
+        let due = scheduler.due(schedules, self.last_tick.unwrap_or(now));
+        for (index, at) in &due {
+            schedules[*index].last_run = Some(*at);
+            self.last_runs.insert(schedules[*index].id.clone(), *at);
+        }
+        self.last_tick = Some(now);
+
+        due.into_iter().map(|(index, _)| index).collect()
+    }
+
+    /// Saves the recorded runs into `latest` with `save`, logging a failure.
+    fn save(
+        &self,
+        mut latest: Vec<Schedule>,
+        save: impl FnOnce(Vec<Schedule>) -> Result<(), String>,
+    ) {
+        self.apply(&mut latest);
+        if let Err(e) = save(latest) {
+            eprintln!("Failed to save the schedules' last runs: {}", e);
+        }
+    }
+}
+
+/// Runs the schedules that came due since the last tick.
+async fn tick<R: Runtime, C: Clock, Tz: TimeZone>(
+    app: &AppHandle<R>,
+    scheduler: &Scheduler<C, Tz>,
+    progress: &mut Progress,
+) {
+    let mut schedules = load_schedules(app);
+    let due = progress.advance(scheduler, &mut schedules);
+
+    for index in &due {
+        let schedule = &schedules[*index];
+        let result = run_action(app, &schedule.action).await;
+        if let Err(e) = &result {
+            eprintln!("Schedule \"{}\" failed: {}", schedule.name, e);
+        }
+        events::publish_automation(
+            app,
+            AutomationKind::Schedule,
+            &schedule.name,
+            None,
+            result.err(),
+        );
+    }
+
+    if !due.is_empty() {
+        // Re-read so edits made while the actions were running are not overwritten.
+        progress.save(load_schedules(app), |latest| save_schedules(app, latest));
+    }
+}
+
+pub fn start<R: Runtime>(app_handle: &AppHandle<R>) {
+    let app = app_handle.clone();
+
+    tauri::async_runtime::spawn(async move {
+        let mut progress = Progress {
+            last_tick: load_last_tick(&app),
+            ..Default::default()
+        };
+        let mut last_saved = progress.last_tick;
+
+        loop {
+            // The location is re-read every tick so edits apply without a restart.
+            let scheduler = Scheduler::system().with_location(solar::load_location(&app));
+
+            tick(&app, &scheduler, &mut progress).await;
+
+            if let Some(now) = progress.last_tick.filter(|now| {
+                last_saved.is_none_or(|at| *now - at >= Duration::minutes(LAST_TICK_SAVE_MINUTES))
+            }) {
+                if let Err(e) = save_last_tick(&app, now) {
+                    eprintln!("Failed to save the scheduler's last tick: {}", e);
+                }
+                last_saved = Some(now);
+            }
+
+            tokio::time::sleep(TICK_INTERVAL).await;
+        }
+    });
+}
+
+#[tauri::command]
+pub fn get_schedules<R: Runtime>(app: AppHandle<R>) -> Vec<Schedule> {
+    Scheduler::system()
+        .with_location(solar::load_location(&app))
+        .with_next_runs(load_schedules(&app))
+}
+
+#[tauri::command]
+pub fn save_schedule<R: Runtime>(
+    app: AppHandle<R>,
+    mut schedule: Schedule,
+) -> Result<Schedule, String> {
+    schedule.trigger.validate()?;
+
+    let location = solar::load_location(&app);
+    if matches!(schedule.trigger, Trigger::Solar { .. }) && location.is_none() {
+        return Err("Location not set. Please set your location first.".to_string());
+    }
+
+    if schedule.weekdays & EVERY_DAY == 0 {
+        return Err("Schedule must run on at least one weekday".to_string());
+    }
+
+    let mut schedules = load_schedules(&app);
+
+    if schedule.id.is_empty() {
+        schedule.id = Uuid::new_v4().to_string();
+    }
+
+    match schedules.iter_mut().find(|s| s.id == schedule.id) {
+        Some(existing) => {
+            // Editing a rule must not make it fire again for an occurrence it already ran.
+            schedule.last_run = existing.last_run;
+            *existing = schedule.clone();
+        }
+        None => {
+            schedule.last_run = Some(Utc::now());
+            schedules.push(schedule.clone());
+        }
+    }
+
+    save_schedules(&app, schedules)?;
+
+    Ok(Scheduler::system()
+        .with_location(location)
+        .with_next_runs(vec![schedule])
+        .remove(0))
+}
+
+#[tauri::command]
+pub fn delete_schedule<R: Runtime>(app: AppHandle<R>, id: String) -> Result<(), String> {
+    let mut schedules = load_schedules(&app);
+    let count = schedules.len();
+
+    schedules.retain(|s| s.id != id);
+
+    if schedules.len() == count {
+        return Err("Schedule not found".to_string());
+    }
+
+    save_schedules(&app, schedules)
+}
+
+#[tauri::command]
+pub async fn run_schedule_now<R: Runtime>(app: AppHandle<R>, id: String) -> Result<(), String> {
+    let schedule = load_schedules(&app)
+        .into_iter()
+        .find(|s| s.id == id)
+        .ok_or("Schedule not found")?;
+
+    run_action(&app, &schedule.action).await
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{FixedOffset, NaiveDateTime};
+
+    use super::*;
+
+    struct FakeClock(DateTime<Utc>);
+
+    impl Clock for FakeClock {
+        fn now(&self) -> DateTime<Utc> {
+            self.0
+        }
+    }
+
+    /// US Eastern time in 2026: EDT from 8 March 07:00 UTC until 1 November 06:00 UTC.
+    #[derive(Debug, Clone, Copy)]
+    struct Eastern;
+
+    const EST: i32 = -5 * 3600;
+    const EDT: i32 = -4 * 3600;
+
+    fn utc(text: &str) -> DateTime<Utc> {
+        text.parse().unwrap()
+    }
+
+    impl TimeZone for Eastern {
+        type Offset = FixedOffset;
+
+        fn from_offset(_: &FixedOffset) -> Self {
+            Eastern
+        }
+
+        fn offset_from_local_date(&self, local: &NaiveDate) -> LocalResult<FixedOffset> {
+            self.offset_from_local_datetime(&local.and_hms_opt(0, 0, 0).unwrap())
+        }
+
+        fn offset_from_local_datetime(&self, local: &NaiveDateTime) -> LocalResult<FixedOffset> {
+            // Earliest instant first, as chrono orders them.
+            let offsets: Vec<FixedOffset> = [EDT, EST]
+                .into_iter()
+                .filter(|offset| {
+                    let at = *local - Duration::seconds((*offset).into());
+                    self.offset_from_utc_datetime(&at).local_minus_utc() == *offset
+                })
+                .map(|offset| FixedOffset::east_opt(offset).unwrap())
+                .collect();
+
+            match offsets[..] {
+                [] => LocalResult::None,
+                [offset] => LocalResult::Single(offset),
+                [earliest, latest, ..] => LocalResult::Ambiguous(earliest, latest),
+            }
+        }
+
+        fn offset_from_utc_date(&self, utc: &NaiveDate) -> FixedOffset {
+            self.offset_from_utc_datetime(&utc.and_hms_opt(0, 0, 0).unwrap())
+        }
+
+        fn offset_from_utc_datetime(&self, at: &NaiveDateTime) -> FixedOffset {
+            let summer =
+                utc("2026-03-08T07:00:00Z").naive_utc()..utc("2026-11-01T06:00:00Z").naive_utc();
+            let offset = if summer.contains(at) { EDT } else { EST };
+
+            FixedOffset::east_opt(offset).unwrap()
+        }
+    }
+
+    fn schedule(hour: u32, minute: u32, weekdays: u8) -> Schedule {
+        Schedule {
+            id: "test".to_string(),
+            name: "Test".to_string(),
+            enabled: true,
+            trigger: Trigger::At { hour, minute },
+            weekdays,
+            action: ScheduleAction::Preset {
+                name: "Test".to_string(),
+                changes: Vec::new(),
+            },
+            last_run: None,
+            next_run: None,
+        }
+    }
+
+    fn scheduler(now: &str) -> Scheduler<FakeClock, Eastern> {
+        Scheduler::new(FakeClock(utc(now)), Eastern)
+    }
+
+    #[test]
+    fn due_fires_once_the_time_has_passed() {
+        let schedules = [schedule(7, 0, EVERY_DAY), schedule(8, 0, EVERY_DAY)];
+
+        // 07:00 EDT is 11:00 UTC.
+        let due = scheduler("2026-06-10T11:00:30Z").due(&schedules, utc("2026-06-10T10:59:30Z"));
+        assert_eq!(due, vec![(0, utc("2026-06-10T11:00:00Z"))]);
+
+        let due = scheduler("2026-06-10T11:01:00Z").due(&schedules, utc("2026-06-10T11:00:30Z"));
+        assert!(due.is_empty());
+    }
+
+    #[test]
+    fn due_respects_weekdays_and_last_run() {
+        // 10 June 2026 is a Wednesday.
+        let monday_only = schedule(7, 0, 0b000_0001);
+        let due =
+            scheduler("2026-06-10T11:00:30Z").due(&[monday_only], utc("2026-06-10T10:59:30Z"));
+        assert!(due.is_empty());
+
+        let mut ran = schedule(7, 0, EVERY_DAY);
+        ran.last_run = Some(utc("2026-06-10T11:00:00Z"));
+        let due = scheduler("2026-06-10T11:00:30Z").due(&[ran], utc("2026-06-10T10:59:30Z"));
+        assert!(due.is_empty());
+    }
+
+    #[test]
+    fn missed_runs_catch_up_once_within_the_window() {
+        let schedules = [schedule(7, 0, EVERY_DAY)];
+
+        // Asleep from 06:00 to 09:00 local time: the 07:00 run happens on waking.
+        let due = scheduler("2026-06-10T13:00:00Z").due(&schedules, utc("2026-06-10T10:00:00Z"));
+        assert_eq!(due, vec![(0, utc("2026-06-10T11:00:00Z"))]);
+
+        // Asleep for three days: only the latest run happens.
+        let due = scheduler("2026-06-10T13:00:00Z").due(&schedules, utc("2026-06-07T10:00:00Z"));
+        assert_eq!(due, vec![(0, utc("2026-06-10T11:00:00Z"))]);
+
+        // A run more than 12 hours ago is dropped.
+        let due = scheduler("2026-06-11T00:00:00Z").due(&schedules, utc("2026-06-10T10:00:00Z"));
+        assert!(due.is_empty());
+    }
+
+    #[test]
+    fn time_in_dst_gap_moves_forward() {
+        // 02:30 doesn't exist on 8 March; it fires at 03:00 EDT, 07:00 UTC.
+        let schedules = [schedule(2, 30, EVERY_DAY)];
+
+        let due = scheduler("2026-03-08T08:00:00Z").due(&schedules, utc("2026-03-08T05:00:00Z"));
+        assert_eq!(due, vec![(0, utc("2026-03-08T07:00:00Z"))]);
+    }
+
+    #[test]
+    fn ambiguous_time_fires_at_the_earliest_instant() {
+        // 01:30 happens twice on 1 November: 05:30 UTC (EDT) and 06:30 UTC (EST).
+        let mut schedules = [schedule(1, 30, EVERY_DAY)];
+
+        let due = scheduler("2026-11-01T05:31:00Z").due(&schedules, utc("2026-11-01T05:00:00Z"));
+        assert_eq!(due, vec![(0, utc("2026-11-01T05:30:00Z"))]);
+
+        schedules[0].last_run = Some(due[0].1);
+        let due = scheduler("2026-11-01T06:31:00Z").due(&schedules, utc("2026-11-01T06:00:00Z"));
+        assert!(due.is_empty());
+    }
+
+    #[test]
+    fn runs_are_remembered_when_saving_fails() {
+        let saved = vec![schedule(7, 0, EVERY_DAY)];
+        let mut progress = Progress {
+            last_tick: Some(utc("2026-06-10T10:59:30Z")),
+            ..Default::default()
+        };
+
+        let mut schedules = saved.clone();
+        let due = progress.advance(&scheduler("2026-06-10T11:00:00Z"), &mut schedules);
+        assert_eq!(due, vec![0]);
+        progress.save(
+            saved.clone(),
+            |_| Err("Failed to save settings".to_string()),
+        );
+        assert_eq!(progress.last_tick, Some(utc("2026-06-10T11:00:00Z")));
+
+        // The settings still have no last run, but the next tick doesn't fire it again.
+        let mut schedules = saved.clone();
+        let due = progress.advance(&scheduler("2026-06-10T11:00:30Z"), &mut schedules);
+        assert!(due.is_empty());
+        assert_eq!(schedules[0].last_run, Some(utc("2026-06-10T11:00:00Z")));
+    }
+}
@@ -0,0 +1,204 @@
+//! API keys live in the platform credential store (Keychain on macOS, Secret Service on
+//! Linux, Credential Manager on Windows). When none is available they go to a file in the
+//! app data directory, readable only by the user.
+//!
+//! The file is scrambled with a key derived from the machine id, which any program on the
+//! machine can derive too. That keeps keys out of plain sight in backups and searches; it is
+//! obfuscation, not encryption, and the file permissions are what protect it. The user is
+//! warned through the settings issues while it is in use.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use sha2::{Digest, Sha256};
+use tauri::{AppHandle, Manager, Runtime};
+
+use crate::settings;
+
+const SERVICE: &str = "com.pum.govee-statusbar";
+const FALLBACK_FILE: &str = "secrets.bin";
+const NONCE_LEN: usize = 12;
+
+// Reading the keychain can be slow and, on macOS, may ask the user for permission, so each
+// secret is read once per session.
+static CACHE: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+/// Set once a secret has been read from or written to the secrets file.
+static FILE_IN_USE: AtomicBool = AtomicBool::new(false);
+
+pub const FILE_WARNING: &str = "No keyring is available, so API keys are kept in a file that \
+     only its permissions protect. Set up a keyring such as GNOME Keyring or KWallet to store \
+     them securely.";
+
+/// Whether any secret this session came from or went to the secrets file.
+pub fn file_in_use() -> bool {
+    FILE_IN_USE.load(Ordering::Relaxed)
+}
+
+fn data_dir<R: Runtime>(app: &AppHandle<R>) -> Result<PathBuf, String> {
+    app.path().app_data_dir().map_err(|e| e.to_string())
+}
+
+/// Only obfuscates: anyone who can read the file can also derive this key.
+fn scrambler() -> Result<ChaCha20Poly1305, String> {
+    let machine = machine_uid::get().map_err(|e| format!("Failed to read machine id: {}", e))?;
+    let digest = Sha256::digest(format!("{}:{}", SERVICE, machine));
+
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&digest)))
+}
+
+fn read_fallback(dir: &Path) -> Result<BTreeMap<String, String>, String> {
+    let path = dir.join(FALLBACK_FILE);
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+
+    let data = std::fs::read(&path).map_err(|e| e.to_string())?;
+    if data.len() < NONCE_LEN {
+        return Err("Secrets file is corrupt".to_string());
+    }
+
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+    let plaintext = scrambler()?
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| "Secrets file was scrambled on another machine or is corrupt".to_string())?;
+
+    serde_json::from_slice(&plaintext).map_err(|e| e.to_string())
+}
+
+fn write_fallback(dir: &Path, secrets: &BTreeMap<String, String>) -> Result<(), String> {
+    let plaintext = serde_json::to_vec(secrets).map_err(|e| e.to_string())?;
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = scrambler()?
+        .encrypt(&nonce, plaintext.as_slice())
+        .map_err(|_| "Failed to scramble secrets".to_string())?;
+
+    let mut data = nonce.to_vec();
+    data.extend(ciphertext);
+
+    std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    let path = dir.join(FALLBACK_FILE);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+        options.mode(0o600);
+        // The mode only applies to new files; older versions created it world-readable.
+        if path.exists() {
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))
+                .map_err(|e| e.to_string())?;
+        }
+    }
+
+    options
+        .open(&path)
+        .and_then(|mut file| file.write_all(&data))
+        .map_err(|e| e.to_string())
+}
+
+fn entry(account: &str) -> keyring::Result<keyring::Entry> {
+    keyring::Entry::new(SERVICE, account)
+}
+
+/// Reads a secret from the keyring, or from the secrets file in `dir`, without the app or
+/// its cache. The `govee` CLI uses it.
+pub fn read(dir: &Path, account: &str) -> Result<Option<String>, String> {
+    match entry(account).and_then(|e| e.get_password()) {
+        Ok(secret) => Ok(Some(secret)),
+        Err(e) => {
+            let fallback = read_fallback(dir)
+                .map_err(|e| eprintln!("Failed to read secrets file: {}", e))
+                .ok()
+                .and_then(|mut secrets| secrets.remove(account));
+
+            match (fallback, e) {
+                (Some(secret), _) => {
+                    FILE_IN_USE.store(true, Ordering::Relaxed);
+                    Ok(Some(secret))
+                }
+                (None, keyring::Error::NoEntry) => Ok(None),
+                (None, e) => Err(format!(
+                    "Couldn't read {} because the keyring is locked or unavailable: {}",
+                    account, e
+                )),
+            }
+        }
+    }
+}
+
+/// The secret, or `None` if it was never stored. Fails when the keyring is locked or
+/// unavailable and the secrets file doesn't have it either, since it may well be in the
+/// keyring.
+pub fn try_get<R: Runtime>(app: &AppHandle<R>, account: &str) -> Result<Option<String>, String> {
+    if let Some(secret) = CACHE.lock().unwrap().get(account) {
+        return Ok(Some(secret.clone()));
+    }
+
+    let secret = read(&data_dir(app)?, account)?;
+    if let Some(secret) = &secret {
+        CACHE
+            .lock()
+            .unwrap()
+            .insert(account.to_string(), secret.clone());
+    }
+
+    Ok(secret)
+}
+
+/// Like `try_get`, treating an unreadable secret as missing.
+pub fn get<R: Runtime>(app: &AppHandle<R>, account: &str) -> Option<String> {
+    try_get(app, account).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        None
+    })
+}
+
+pub fn set<R: Runtime>(app: &AppHandle<R>, account: &str, secret: &str) -> Result<(), String> {
+    if CACHE.lock().unwrap().get(account).map(String::as_str) == Some(secret) {
+        return Ok(());
+    }
+
+    if let Err(e) = entry(account).and_then(|e| e.set_password(secret)) {
+        eprintln!("Keyring unavailable, using the secrets file: {}", e);
+
+        let dir = data_dir(app)?;
+        let mut secrets = read_fallback(&dir)?;
+        secrets.insert(account.to_string(), secret.to_string());
+        write_fallback(&dir, &secrets)?;
+
+        if !FILE_IN_USE.swap(true, Ordering::Relaxed) {
+            settings::report_issue(app, "profiles", FILE_WARNING);
+        }
+    }
+
+    CACHE
+        .lock()
+        .unwrap()
+        .insert(account.to_string(), secret.to_string());
+
+    Ok(())
+}
+
+pub fn delete<R: Runtime>(app: &AppHandle<R>, account: &str) -> Result<(), String> {
+    CACHE.lock().unwrap().remove(account);
+
+    match entry(account).and_then(|e| e.delete_credential()) {
+        Ok(()) | Err(keyring::Error::NoEntry) => {}
+        Err(e) => eprintln!("Failed to delete {} from the keyring: {}", account, e),
+    }
+
+    // The secret may have been written to the file while the keyring was unavailable.
+    let dir = data_dir(app)?;
+    let mut secrets = read_fallback(&dir)?;
+    if secrets.remove(account).is_some() {
+        write_fallback(&dir, &secrets)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,123 @@
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Runtime};
+
+use crate::cache;
+use crate::poller;
+use crate::scheduler::DeviceTarget;
+use crate::settings;
+use crate::tray;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TemperatureUnit {
+    #[default]
+    Celsius,
+    Fahrenheit,
+}
+
+impl TemperatureUnit {
+    /// Formats a temperature given in Celsius, which is what `get_device_state` returns.
+    pub fn format(self, celsius: f64) -> String {
+        match self {
+            TemperatureUnit::Celsius => format!("{:.0}°C", celsius),
+            TemperatureUnit::Fahrenheit => format!("{:.0}°F", celsius * 9.0 / 5.0 + 32.0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SensorPlacement {
+    /// Next to the icon, as short as possible.
+    Title,
+    /// In the tooltip, one line per sensor.
+    #[default]
+    Tooltip,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TraySensors {
+    pub devices: Vec<DeviceTarget>,
+    #[serde(default)]
+    pub placement: SensorPlacement,
+    #[serde(default)]
+    pub unit: TemperatureUnit,
+}
+
+pub fn load_tray_sensors<R: Runtime>(app: &AppHandle<R>) -> TraySensors {
+    settings::get(app).tray_sensors
+}
+
+/// Temperature and humidity of a sensor from the cache, such as "21°C 45%". `None` until
+/// its state has been fetched.
+fn reading<R: Runtime>(app: &AppHandle<R>, device: &str, unit: TemperatureUnit) -> Option<String> {
+    let state = cache::state(app, device)?;
+
+    let parts: Vec<String> = [
+        state.reading("sensorTemperature").map(|c| unit.format(c)),
+        state
+            .reading("sensorHumidity")
+            .map(|h| format!("{:.0}%", h)),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+
+    (!parts.is_empty()).then(|| parts.join(" "))
+}
+
+fn device_name<R: Runtime>(app: &AppHandle<R>, device: &str) -> String {
+    cache::devices(app)
+        .iter()
+        .find(|d| d.device == device)
+        .map(|d| d.display_name().to_string())
+        .unwrap_or_else(|| device.to_string())
+}
+
+/// Readings of the chosen sensors for the tray title, if they go there.
+pub fn tray_title<R: Runtime>(app: &AppHandle<R>) -> Option<String> {
+    let config = load_tray_sensors(app);
+    if config.placement != SensorPlacement::Title {
+        return None;
+    }
+
+    let readings: Vec<String> = config
+        .devices
+        .iter()
+        .filter_map(|d| reading(app, &d.device, config.unit))
+        .collect();
+
+    (!readings.is_empty()).then(|| readings.join(" · "))
+}
+
+/// One line per chosen sensor for the tray tooltip. The tooltip always lists them, since the
+/// title has no room for names.
+pub fn tray_tooltip<R: Runtime>(app: &AppHandle<R>) -> Vec<String> {
+    let config = load_tray_sensors(app);
+
+    config
+        .devices
+        .iter()
+        .filter_map(|d| {
+            reading(app, &d.device, config.unit)
+                .map(|r| format!("{}: {}", device_name(app, &d.device), r))
+        })
+        .collect()
+}
+
+#[tauri::command]
+pub fn get_tray_sensors<R: Runtime>(app: AppHandle<R>) -> TraySensors {
+    load_tray_sensors(&app)
+}
+
+#[tauri::command]
+pub fn set_tray_sensors<R: Runtime>(app: AppHandle<R>, sensors: TraySensors) -> Result<(), String> {
+    settings::update(&app, |s| {
+        s.tray_sensors = sensors;
+        Ok(())
+    })?;
+
+    poller::wake(&app);
+
+    tray::refresh_title(&app).map_err(|e| e.to_string())
+}
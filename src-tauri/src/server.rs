@@ -0,0 +1,498 @@
+//! Optional HTTP API for automation tools such as Stream Deck, scripts and Node-RED. The
+//! endpoints mirror the device commands, and `stream` adds a WebSocket feed of backend
+//! events. Every request needs the bearer token shown by `get_api_server_status`, except
+//! the OpenAPI description at `/api/v1/openapi.json` and the webhooks at `/hooks`, which
+//! are signed instead. Prometheus metrics are served at `/metrics` when turned on.
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use axum::extract::{Path, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tauri::{AppHandle, Manager, Runtime};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+use crate::cache;
+use crate::command::{
+    change_capability_value, get_device_state, get_devices, get_diy_scenes, get_light_scenes,
+    DeviceState, GoveeDevice, SceneOption,
+};
+use crate::metrics;
+use crate::poller;
+use crate::presets::{self, Preset};
+use crate::secrets;
+use crate::settings;
+use crate::stream;
+use crate::webhooks;
+
+const TOKEN_ACCOUNT: &str = "api-server-token";
+const OPENAPI: &str = include_str!("../openapi.json");
+const RESTART_DELAY: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Anything other than a loopback address makes the API reachable from the network.
+    #[serde(default = "default_bind_address")]
+    pub bind_address: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// Serves Prometheus metrics at `/metrics`.
+    #[serde(default)]
+    pub metrics: bool,
+    /// A fixed token, set in the headless config. Replaces the generated one when not empty.
+    #[serde(default, skip_serializing)]
+    pub token: String,
+}
+
+fn default_bind_address() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_port() -> u16 {
+    7585
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            enabled: false,
+            bind_address: default_bind_address(),
+            port: default_port(),
+            metrics: false,
+            token: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerStatus {
+    pub running: bool,
+    pub address: Option<String>,
+    /// Why the server last failed to start, if it did.
+    pub error: Option<String>,
+    pub token: String,
+    pub config: ServerConfig,
+}
+
+struct Running {
+    address: SocketAddr,
+    shutdown: oneshot::Sender<()>,
+}
+
+#[derive(Default)]
+pub struct ServerState {
+    running: Mutex<Option<Running>>,
+    error: Mutex<Option<String>>,
+}
+
+pub fn load_config<R: Runtime>(app: &AppHandle<R>) -> ServerConfig {
+    settings::get(app).api_server
+}
+
+/// The bearer token, created the first time it is needed.
+fn token<R: Runtime>(app: &AppHandle<R>) -> Result<String, String> {
+    let config = load_config(app);
+    if !config.token.is_empty() {
+        return Ok(config.token);
+    }
+    if let Some(token) = secrets::get(app, TOKEN_ACCOUNT) {
+        return Ok(token);
+    }
+
+    let token = Uuid::new_v4().simple().to_string();
+    secrets::set(app, TOKEN_ACCOUNT, &token)?;
+
+    Ok(token)
+}
+
+/// Prints the token when headless mode creates it, since there is no panel to show it in.
+pub fn announce_new_token<R: Runtime>(app: &AppHandle<R>) -> Result<(), String> {
+    let config = load_config(app);
+    if !config.enabled || !config.token.is_empty() || secrets::get(app, TOKEN_ACCOUNT).is_some() {
+        return Ok(());
+    }
+
+    println!("API server token: {}", token(app)?);
+
+    Ok(())
+}
+
+struct ApiError(StatusCode, String);
+
+impl ApiError {
+    fn not_found(message: impl Into<String>) -> Self {
+        ApiError(StatusCode::NOT_FOUND, message.into())
+    }
+
+    fn bad_request(message: impl Into<String>) -> Self {
+        ApiError(StatusCode::BAD_REQUEST, message.into())
+    }
+}
+
+/// Handlers check for unknown devices and bad arguments first, so the command errors left
+/// come from the Govee API or an unreachable device.
+impl From<String> for ApiError {
+    fn from(message: String) -> Self {
+        ApiError(StatusCode::BAD_GATEWAY, message)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(serde_json::json!({ "error": self.1 }))).into_response()
+    }
+}
+
+// Compares in constant time so the token can't be guessed from response times.
+fn same_token(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+async fn authorize<R: Runtime>(
+    State(app): State<AppHandle<R>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    // Browsers can't set headers on WebSocket requests, so the stream also takes the token
+    // as a `token` query parameter. Elsewhere it would only end up in logs and history.
+    let given = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .or_else(|| {
+            if !request.uri().path().ends_with("/stream") {
+                return None;
+            }
+            request
+                .uri()
+                .query()?
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("token="))
+        });
+
+    match (token(&app).ok(), given) {
+        (Some(token), Some(given)) if same_token(token.as_bytes(), given.as_bytes()) => {
+            next.run(request).await
+        }
+        _ => ApiError(
+            StatusCode::UNAUTHORIZED,
+            "Missing or invalid bearer token".to_string(),
+        )
+        .into_response(),
+    }
+}
+
+async fn find_device<R: Runtime>(
+    app: &AppHandle<R>,
+    device: &str,
+) -> Result<GoveeDevice, ApiError> {
+    if let Some(found) = cache::devices(app).into_iter().find(|d| d.device == device) {
+        return Ok(found);
+    }
+
+    get_devices(app.clone())
+        .await?
+        .into_iter()
+        .find(|d| d.device == device)
+        .ok_or_else(|| ApiError::not_found(format!("Device {} not found", device)))
+}
+
+async fn devices<R: Runtime>(
+    State(app): State<AppHandle<R>>,
+) -> Result<Json<Vec<GoveeDevice>>, ApiError> {
+    Ok(Json(get_devices(app).await?))
+}
+
+async fn device_state<R: Runtime>(
+    State(app): State<AppHandle<R>>,
+    Path(device): Path<String>,
+) -> Result<Json<DeviceState>, ApiError> {
+    let device = find_device(&app, &device).await?;
+
+    Ok(Json(
+        get_device_state(app, device.device, device.sku).await?,
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+struct ControlRequest {
+    capability_type: String,
+    instance: String,
+    value: Value,
+}
+
+async fn control<R: Runtime>(
+    State(app): State<AppHandle<R>>,
+    Path(device): Path<String>,
+    Json(request): Json<ControlRequest>,
+) -> Result<StatusCode, ApiError> {
+    let device = find_device(&app, &device).await?;
+    device
+        .check_control(&request.capability_type, &request.instance, &request.value)
+        .map_err(ApiError::bad_request)?;
+
+    change_capability_value(
+        app,
+        device.device,
+        device.sku,
+        request.capability_type,
+        request.instance,
+        request.value,
+    )
+    .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn light_scenes<R: Runtime>(
+    State(app): State<AppHandle<R>>,
+    Path(device): Path<String>,
+) -> Result<Json<Vec<SceneOption>>, ApiError> {
+    let device = find_device(&app, &device).await?;
+
+    Ok(Json(
+        get_light_scenes(app, device.device, device.sku).await?,
+    ))
+}
+
+async fn diy_scenes<R: Runtime>(
+    State(app): State<AppHandle<R>>,
+    Path(device): Path<String>,
+) -> Result<Json<Vec<SceneOption>>, ApiError> {
+    let device = find_device(&app, &device).await?;
+
+    Ok(Json(get_diy_scenes(app, device.device, device.sku).await?))
+}
+
+async fn list_presets<R: Runtime>(State(app): State<AppHandle<R>>) -> Json<Vec<Preset>> {
+    Json(presets::load_presets(&app))
+}
+
+async fn apply_preset<R: Runtime>(
+    State(app): State<AppHandle<R>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    if !presets::load_presets(&app).iter().any(|p| p.id == id) {
+        return Err(ApiError::not_found("Preset not found"));
+    }
+
+    presets::apply(&app, &id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn metrics<R: Runtime>(State(app): State<AppHandle<R>>) -> Result<Response, ApiError> {
+    if !load_config(&app).metrics {
+        return Err(ApiError::not_found("Metrics are turned off"));
+    }
+
+    let body = metrics::render(&app);
+
+    Ok(([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response())
+}
+
+async fn openapi() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "application/json")], OPENAPI)
+}
+
+fn router<R: Runtime>(app: AppHandle<R>) -> Router {
+    let api = Router::new()
+        .route("/devices", get(devices::<R>))
+        .route("/devices/{device}/state", get(device_state::<R>))
+        .route("/devices/{device}/control", post(control::<R>))
+        .route("/devices/{device}/scenes", get(light_scenes::<R>))
+        .route("/devices/{device}/diy-scenes", get(diy_scenes::<R>))
+        .route("/presets", get(list_presets::<R>))
+        .route("/presets/{id}/apply", post(apply_preset::<R>))
+        .route("/stream", get(stream::stream::<R>))
+        .route_layer(middleware::from_fn_with_state(app.clone(), authorize::<R>))
+        .route("/openapi.json", get(openapi));
+
+    Router::new()
+        .nest("/api/v1", api)
+        .route("/hooks/{name}", post(webhooks::receive::<R>))
+        .route(
+            "/metrics",
+            get(metrics::<R>)
+                .route_layer(middleware::from_fn_with_state(app.clone(), authorize::<R>)),
+        )
+        .with_state(app)
+}
+
+/// Starts listening unless the server is already running.
+async fn listen<R: Runtime>(app: &AppHandle<R>) -> Result<(), String> {
+    if app.state::<ServerState>().running.lock().unwrap().is_some() {
+        return Ok(());
+    }
+
+    let config = load_config(app);
+    token(app)?;
+
+    let listener = TcpListener::bind((config.bind_address.as_str(), config.port))
+        .await
+        .map_err(|e| {
+            format!(
+                "Failed to listen on {}:{}: {}",
+                config.bind_address, config.port, e
+            )
+        })?;
+    let address = listener.local_addr().map_err(|e| e.to_string())?;
+
+    let (shutdown, stopped) = oneshot::channel::<()>();
+    let router = router(app.clone());
+
+    tauri::async_runtime::spawn(async move {
+        let served = axum::serve(listener, router)
+            .with_graceful_shutdown(async {
+                let _ = stopped.await;
+            })
+            .await;
+
+        if let Err(e) = served {
+            eprintln!("API server stopped: {}", e);
+        }
+    });
+
+    *app.state::<ServerState>().running.lock().unwrap() = Some(Running { address, shutdown });
+
+    Ok(())
+}
+
+async fn listen_and_record<R: Runtime>(app: &AppHandle<R>) -> Result<(), String> {
+    let result = listen(app).await;
+    *app.state::<ServerState>().error.lock().unwrap() = result.as_ref().err().cloned();
+
+    result
+}
+
+fn shutdown<R: Runtime>(app: &AppHandle<R>) {
+    if let Some(running) = app.state::<ServerState>().running.lock().unwrap().take() {
+        let _ = running.shutdown.send(());
+    }
+}
+
+fn status<R: Runtime>(app: &AppHandle<R>) -> Result<ServerStatus, String> {
+    let state = app.state::<ServerState>();
+    let address = state
+        .running
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|r| r.address.to_string());
+    let error = state.error.lock().unwrap().clone();
+
+    Ok(ServerStatus {
+        running: address.is_some(),
+        address,
+        error,
+        token: token(app)?,
+        config: load_config(app),
+    })
+}
+
+pub fn start<R: Runtime>(app_handle: &AppHandle<R>) {
+    app_handle.manage(ServerState::default());
+
+    if !load_config(app_handle).enabled {
+        return;
+    }
+
+    let app = app_handle.clone();
+
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = listen_and_record(&app).await {
+            eprintln!("{}", e);
+        }
+    });
+}
+
+#[tauri::command]
+pub fn get_api_server_status<R: Runtime>(app: AppHandle<R>) -> Result<ServerStatus, String> {
+    status(&app)
+}
+
+#[tauri::command]
+pub async fn start_api_server<R: Runtime>(app: AppHandle<R>) -> Result<ServerStatus, String> {
+    listen_and_record(&app).await?;
+
+    settings::update(&app, |s| {
+        s.api_server.enabled = true;
+        Ok(())
+    })?;
+    poller::wake(&app);
+
+    status(&app)
+}
+
+#[tauri::command]
+pub fn stop_api_server<R: Runtime>(app: AppHandle<R>) -> Result<ServerStatus, String> {
+    shutdown(&app);
+
+    settings::update(&app, |s| {
+        s.api_server.enabled = false;
+        Ok(())
+    })?;
+    poller::wake(&app);
+
+    status(&app)
+}
+
+/// Saves the address and port, restarting the server if it is running.
+#[tauri::command]
+pub async fn set_api_server_config<R: Runtime>(
+    app: AppHandle<R>,
+    config: ServerConfig,
+) -> Result<ServerStatus, String> {
+    config
+        .bind_address
+        .parse::<IpAddr>()
+        .map_err(|_| format!("{} is not an IP address", config.bind_address))?;
+    if config.port == 0 {
+        return Err("Port must be between 1 and 65535".to_string());
+    }
+
+    let was_running = app.state::<ServerState>().running.lock().unwrap().is_some();
+
+    settings::update(&app, |s| {
+        s.api_server = ServerConfig {
+            enabled: s.api_server.enabled,
+            token: s.api_server.token.clone(),
+            ..config
+        };
+        Ok(())
+    })?;
+    poller::wake(&app);
+
+    if was_running {
+        shutdown(&app);
+        // Give the old listener a moment to release the port.
+        tokio::time::sleep(RESTART_DELAY).await;
+        listen_and_record(&app).await?;
+    }
+
+    status(&app)
+}
+
+/// Replaces the token. Clients using the old one are rejected from then on.
+#[tauri::command]
+pub fn regenerate_api_server_token<R: Runtime>(app: AppHandle<R>) -> Result<ServerStatus, String> {
+    if !load_config(&app).token.is_empty() {
+        return Err("The token is set in the config file".to_string());
+    }
+
+    secrets::set(&app, TOKEN_ACCOUNT, &Uuid::new_v4().simple().to_string())?;
+
+    status(&app)
+}
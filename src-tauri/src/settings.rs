@@ -0,0 +1,395 @@
+//! User preferences, kept as one typed `Settings` value in `settings.json`.
+//!
+//! Each field is stored under its own key so the file stays readable and older versions
+//! keep finding the keys they know. A `version` key records the schema version; at startup
+//! the migrations between the stored and the current version run in order before the file
+//! is parsed. A field that fails to parse falls back to its default, is reported through
+//! `get_settings_issues` and is kept in the file under `<key>_invalid` so it isn't lost.
+//!
+//! Runtime state such as sleep timers and the alert log shares the file but isn't part of
+//! `Settings`.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tauri::{AppHandle, Manager, Runtime};
+use tauri_plugin_store::StoreExt;
+
+use crate::alarm::Alarm;
+use crate::alerts::{AlertRule, QuietHours};
+use crate::autostart::Autostart;
+use crate::circadian::CircadianConfig;
+use crate::history::HistoryConfig;
+use crate::homekit::HomekitConfig;
+use crate::mqtt::{self, MqttConfig};
+use crate::outgoing::OutgoingWebhook;
+use crate::presence::OfflineNotifications;
+use crate::presets::Preset;
+use crate::profiles::{Profile, DEFAULT_PROFILE};
+use crate::scheduler::Schedule;
+use crate::secrets;
+use crate::sensors::TraySensors;
+use crate::server::ServerConfig;
+use crate::solar::Location;
+use crate::tray::TrayIconMode;
+use crate::webhooks::{self, Webhook};
+
+pub const SETTINGS_FILE: &str = "settings.json";
+const VERSION_KEY: &str = "version";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub profiles: Vec<Profile>,
+    pub pinned_devices: Vec<String>,
+    pub tray_icon_mode: TrayIconMode,
+    pub tray_sensors: TraySensors,
+    pub location: Option<Location>,
+    pub schedules: Vec<Schedule>,
+    pub circadian: CircadianConfig,
+    pub alarms: Vec<Alarm>,
+    pub presets: Vec<Preset>,
+    pub sensor_history: HistoryConfig,
+    pub alert_rules: Vec<AlertRule>,
+    pub alert_quiet_hours: QuietHours,
+    pub offline_notifications: OfflineNotifications,
+    /// `None` until the login item has been set up on the first run.
+    pub autostart: Option<Autostart>,
+    pub api_server: ServerConfig,
+    /// Room of each device, by device id.
+    pub rooms: BTreeMap<String, String>,
+    pub mqtt: MqttConfig,
+    pub homekit: HomekitConfig,
+    pub webhooks: Vec<Webhook>,
+    pub outgoing_webhooks: Vec<OutgoingWebhook>,
+}
+
+type Migration<R> = fn(&AppHandle<R>, &mut Map<String, Value>) -> Result<(), String>;
+
+pub const CURRENT_VERSION: u64 = 2;
+
+/// Migration `i` upgrades the stored settings from version `i` to `i + 1`. New migrations
+/// go at the end, with `CURRENT_VERSION` raised to match; existing ones must never change.
+fn migrations<R: Runtime>() -> [Migration<R>; CURRENT_VERSION as usize] {
+    [single_api_key_to_profiles, api_keys_to_keyring]
+}
+
+/// Version 1: the single `api_key` becomes the default profile.
+fn single_api_key_to_profiles<R: Runtime>(
+    _: &AppHandle<R>,
+    raw: &mut Map<String, Value>,
+) -> Result<(), String> {
+    let Some(api_key) = raw.remove("api_key") else {
+        return Ok(());
+    };
+
+    if !raw.contains_key("profiles") {
+        raw.insert(
+            "profiles".to_string(),
+            serde_json::json!([{ "id": DEFAULT_PROFILE, "name": "Default", "api_key": api_key }]),
+        );
+    }
+
+    Ok(())
+}
+
+/// Version 2: API keys move from the file to the keyring.
+fn api_keys_to_keyring<R: Runtime>(
+    app: &AppHandle<R>,
+    raw: &mut Map<String, Value>,
+) -> Result<(), String> {
+    let Some(profiles) = raw.get_mut("profiles").and_then(|v| v.as_array_mut()) else {
+        return Ok(());
+    };
+
+    for profile in profiles.iter_mut().filter_map(|p| p.as_object_mut()) {
+        let id = profile
+            .get("id")
+            .and_then(|v| v.as_str())
+            .ok_or("Profile without an id")?
+            .to_string();
+
+        if let Some(api_key) = profile.get("api_key").and_then(|v| v.as_str()) {
+            secrets::set(app, &id, api_key)?;
+        }
+        profile.remove("api_key");
+    }
+
+    Ok(())
+}
+
+/// A setting that couldn't be loaded.
+#[derive(Debug, Clone, Serialize)]
+pub struct SettingsIssue {
+    pub key: String,
+    pub message: String,
+}
+
+pub struct SettingsState {
+    settings: Mutex<Settings>,
+    issues: Mutex<Vec<SettingsIssue>>,
+    /// False when the settings come from the headless config file, which is never written.
+    persist: bool,
+}
+
+fn to_map(settings: &Settings) -> Map<String, Value> {
+    match serde_json::to_value(settings) {
+        Ok(Value::Object(map)) => map,
+        _ => unreachable!("Settings serializes to an object"),
+    }
+}
+
+/// Parses each field on its own so one bad value doesn't throw away the rest.
+fn parse(raw: &Map<String, Value>) -> (Settings, Vec<SettingsIssue>) {
+    let mut merged = to_map(&Settings::default());
+    let mut issues = Vec::new();
+
+    for key in merged.keys().cloned().collect::<Vec<_>>() {
+        let Some(value) = raw.get(&key) else {
+            continue;
+        };
+
+        let mut candidate = merged.clone();
+        candidate.insert(key.clone(), value.clone());
+
+        match serde_json::from_value::<Settings>(Value::Object(candidate.clone())) {
+            Ok(_) => merged = candidate,
+            Err(e) => issues.push(SettingsIssue {
+                key,
+                message: e.to_string(),
+            }),
+        }
+    }
+
+    let settings = serde_json::from_value(Value::Object(merged)).unwrap_or_default();
+
+    (settings, issues)
+}
+
+fn run_migrations<R: Runtime>(
+    app: &AppHandle<R>,
+    raw: &mut Map<String, Value>,
+    issues: &mut Vec<SettingsIssue>,
+) -> u64 {
+    let mut version = raw.get(VERSION_KEY).and_then(|v| v.as_u64()).unwrap_or(0);
+
+    if version > CURRENT_VERSION {
+        issues.push(SettingsIssue {
+            key: VERSION_KEY.to_string(),
+            message: format!(
+                "Settings were saved by a newer version of the app (schema {}, this version \
+                 understands {})",
+                version, CURRENT_VERSION
+            ),
+        });
+        return version;
+    }
+
+    for migration in &migrations()[version as usize..] {
+        if let Err(e) = migration(app, raw) {
+            issues.push(SettingsIssue {
+                key: VERSION_KEY.to_string(),
+                message: format!(
+                    "Migrating settings to version {} failed: {}",
+                    version + 1,
+                    e
+                ),
+            });
+            break;
+        }
+        version += 1;
+    }
+
+    version
+}
+
+/// Migrates and loads the settings. Call once during setup, before anything reads them.
+pub fn init<R: Runtime>(app: &AppHandle<R>) -> Result<(), String> {
+    let store = app.store(SETTINGS_FILE).map_err(|e| e.to_string())?;
+
+    let original: Map<String, Value> = store.entries().into_iter().collect();
+    let mut raw = original.clone();
+    let mut issues = Vec::new();
+
+    let version = run_migrations(app, &mut raw, &mut issues);
+    let (mut settings, parse_issues) = parse(&raw);
+
+    for issue in &parse_issues {
+        if let Some(value) = raw.get(&issue.key).cloned() {
+            raw.insert(format!("{}_invalid", issue.key), value);
+        }
+    }
+    issues.extend(parse_issues);
+
+    raw.insert(VERSION_KEY.to_string(), version.into());
+
+    for key in original.keys().filter(|k| !raw.contains_key(*k)) {
+        store.delete(key);
+    }
+    for (key, value) in raw {
+        if original.get(&key) != Some(&value) {
+            store.set(key, value);
+        }
+    }
+    store
+        .save()
+        .map_err(|e| format!("Failed to save settings: {}", e))?;
+
+    for profile in &mut settings.profiles {
+        match secrets::try_get(app, &profile.id) {
+            Ok(key) => profile.api_key = key.unwrap_or_default(),
+            Err(e) => issues.push(SettingsIssue {
+                key: "profiles".to_string(),
+                message: format!("No API key for {}: {}", profile.name, e),
+            }),
+        }
+    }
+    settings.mqtt.password = secrets::get(app, mqtt::PASSWORD_ACCOUNT).unwrap_or_default();
+    for webhook in &mut settings.webhooks {
+        webhook.secret =
+            secrets::get(app, &webhooks::secret_account(&webhook.id)).unwrap_or_default();
+    }
+    if secrets::file_in_use() {
+        issues.push(SettingsIssue {
+            key: "profiles".to_string(),
+            message: secrets::FILE_WARNING.to_string(),
+        });
+    }
+
+    for issue in &issues {
+        eprintln!("Settings problem with `{}`: {}", issue.key, issue.message);
+    }
+
+    app.manage(SettingsState {
+        settings: Mutex::new(settings),
+        issues: Mutex::new(issues),
+        persist: true,
+    });
+
+    Ok(())
+}
+
+/// Reads the settings file in the app data directory `dir` without the app, for the `govee`
+/// CLI. API keys come from the keyring or the secrets file next to it.
+pub fn load_file(dir: &Path) -> Result<Settings, String> {
+    let path = dir.join(SETTINGS_FILE);
+    let text = std::fs::read_to_string(&path).map_err(|e| {
+        format!(
+            "Failed to read {}: {}. Set up the app first.",
+            path.display(),
+            e
+        )
+    })?;
+    let raw: Map<String, Value> = serde_json::from_str(&text)
+        .map_err(|e| format!("Invalid settings {}: {}", path.display(), e))?;
+
+    // Migrations may need the app, e.g. to move keys to the keyring.
+    let version = raw.get(VERSION_KEY).and_then(|v| v.as_u64()).unwrap_or(0);
+    if version < CURRENT_VERSION {
+        return Err(
+            "The settings are from an older version. Start the app once to upgrade them."
+                .to_string(),
+        );
+    }
+
+    let (mut settings, issues) = parse(&raw);
+    for issue in &issues {
+        eprintln!("Settings problem with `{}`: {}", issue.key, issue.message);
+    }
+
+    for profile in &mut settings.profiles {
+        profile.api_key = secrets::read(dir, &profile.id)?.unwrap_or_default();
+    }
+
+    Ok(settings)
+}
+
+/// Uses `settings` instead of the settings file. Changes made while running are kept in
+/// memory only.
+pub fn init_with<R: Runtime>(app: &AppHandle<R>, settings: Settings) {
+    app.manage(SettingsState {
+        settings: Mutex::new(settings),
+        issues: Mutex::new(Vec::new()),
+        persist: false,
+    });
+}
+
+/// The current settings. Defaults before `init` has run.
+pub fn get<R: Runtime>(app: &AppHandle<R>) -> Settings {
+    app.try_state::<SettingsState>()
+        .map(|state| state.settings.lock().unwrap().clone())
+        .unwrap_or_default()
+}
+
+/// Changes the settings and saves them. Nothing is changed if `change` or saving fails.
+pub fn update<R: Runtime, T>(
+    app: &AppHandle<R>,
+    change: impl FnOnce(&mut Settings) -> Result<T, String>,
+) -> Result<T, String> {
+    let state = app.state::<SettingsState>();
+    let mut settings = state.settings.lock().unwrap();
+
+    let mut updated = settings.clone();
+    let result = change(&mut updated)?;
+
+    if !state.persist {
+        *settings = updated;
+        return Ok(result);
+    }
+
+    let store = app.store(SETTINGS_FILE).map_err(|e| e.to_string())?;
+    let previous = to_map(&settings);
+    let mut replaced = Vec::new();
+    for (key, value) in to_map(&updated) {
+        if previous.get(&key) != Some(&value) {
+            // A value replaced by the user no longer needs its invalid backup.
+            let invalid = format!("{}_invalid", key);
+            replaced.push((invalid.clone(), store.get(&invalid)));
+            replaced.push((key.clone(), store.get(&key)));
+            store.delete(invalid);
+            store.set(key, value);
+        }
+    }
+
+    if store.save().is_err() {
+        // Puts the store back so a later save doesn't write this change after all.
+        for (key, value) in replaced {
+            match value {
+                Some(value) => store.set(key, value),
+                None => {
+                    store.delete(key);
+                }
+            }
+        }
+        return Err("Failed to save settings".to_string());
+    }
+
+    *settings = updated;
+
+    Ok(result)
+}
+
+/// Reports a problem found after startup, once. Does nothing before `init`, which reports
+/// its own.
+pub fn report_issue<R: Runtime>(app: &AppHandle<R>, key: &str, message: &str) {
+    let Some(state) = app.try_state::<SettingsState>() else {
+        return;
+    };
+
+    eprintln!("Settings problem with `{}`: {}", key, message);
+    let mut issues = state.issues.lock().unwrap();
+    if !issues.iter().any(|i| i.key == key && i.message == message) {
+        issues.push(SettingsIssue {
+            key: key.to_string(),
+            message: message.to_string(),
+        });
+    }
+}
+
+#[tauri::command]
+pub fn get_settings_issues<R: Runtime>(app: AppHandle<R>) -> Vec<SettingsIssue> {
+    app.state::<SettingsState>().issues.lock().unwrap().clone()
+}
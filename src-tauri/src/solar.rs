@@ -0,0 +1,240 @@
+use std::f64::consts::PI;
+
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Runtime};
+
+use crate::settings;
+
+// Julian date of 2000-01-01 12:00 UTC and of the Unix epoch.
+const J2000: f64 = 2_451_545.0;
+const UNIX_EPOCH_JD: f64 = 2_440_587.5;
+
+// Solar altitudes (degrees) that define each event. Sunrise and sunset include atmospheric
+// refraction and the radius of the solar disc.
+const SUNRISE_ALTITUDE: f64 = -0.833;
+const CIVIL_TWILIGHT_ALTITUDE: f64 = -6.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SolarEvent {
+    CivilDawn,
+    Sunrise,
+    Sunset,
+    CivilDusk,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SolarTimes {
+    pub civil_dawn: Option<DateTime<Utc>>,
+    pub sunrise: Option<DateTime<Utc>>,
+    pub sunset: Option<DateTime<Utc>>,
+    pub civil_dusk: Option<DateTime<Utc>>,
+}
+
+fn to_radians(degrees: f64) -> f64 {
+    degrees * PI / 180.0
+}
+
+fn to_degrees(radians: f64) -> f64 {
+    radians * 180.0 / PI
+}
+
+fn julian_to_utc(julian: f64) -> Option<DateTime<Utc>> {
+    let seconds = (julian - UNIX_EPOCH_JD) * 86_400.0;
+
+    DateTime::from_timestamp(seconds.round() as i64, 0)
+}
+
+/// Computes when the sun crosses `event`'s altitude on `date` at `location`.
+///
+/// Follows the NOAA sunrise equation, which is accurate to about a minute away from the
+/// poles. Returns `None` when the sun never reaches that altitude on the day (polar day
+/// or night).
+pub fn event_time(location: Location, date: NaiveDate, event: SolarEvent) -> Option<DateTime<Utc>> {
+    let epoch = NaiveDate::from_ymd_opt(2000, 1, 1)?;
+    let day = (date - epoch).num_days() as f64;
+
+    let mean_solar_time = day - location.longitude / 360.0;
+    let mean_anomaly = (357.5291 + 0.985_600_28 * mean_solar_time).rem_euclid(360.0);
+    let m = to_radians(mean_anomaly);
+
+    let center = 1.9148 * m.sin() + 0.02 * (2.0 * m).sin() + 0.0003 * (3.0 * m).sin();
+    let ecliptic_longitude =
+        to_radians((mean_anomaly + center + 180.0 + 102.9372).rem_euclid(360.0));
+
+    let transit =
+        J2000 + mean_solar_time + 0.0053 * m.sin() - 0.0069 * (2.0 * ecliptic_longitude).sin();
+
+    let declination = (ecliptic_longitude.sin() * to_radians(23.4397).sin()).asin();
+    let latitude = to_radians(location.latitude);
+
+    let altitude = match event {
+        SolarEvent::Sunrise | SolarEvent::Sunset => SUNRISE_ALTITUDE,
+        SolarEvent::CivilDawn | SolarEvent::CivilDusk => CIVIL_TWILIGHT_ALTITUDE,
+    };
+
+    let cos_hour_angle = (to_radians(altitude).sin() - latitude.sin() * declination.sin())
+        / (latitude.cos() * declination.cos());
+
+    if !(-1.0..=1.0).contains(&cos_hour_angle) {
+        return None;
+    }
+
+    let hour_angle = to_degrees(cos_hour_angle.acos()) / 360.0;
+
+    match event {
+        SolarEvent::CivilDawn | SolarEvent::Sunrise => julian_to_utc(transit - hour_angle),
+        SolarEvent::Sunset | SolarEvent::CivilDusk => julian_to_utc(transit + hour_angle),
+    }
+}
+
+pub fn solar_times(location: Location, date: NaiveDate) -> SolarTimes {
+    SolarTimes {
+        civil_dawn: event_time(location, date, SolarEvent::CivilDawn),
+        sunrise: event_time(location, date, SolarEvent::Sunrise),
+        sunset: event_time(location, date, SolarEvent::Sunset),
+        civil_dusk: event_time(location, date, SolarEvent::CivilDusk),
+    }
+}
+
+pub fn load_location<R: Runtime>(app: &AppHandle<R>) -> Option<Location> {
+    settings::get(app).location
+}
+
+#[tauri::command]
+pub fn get_location<R: Runtime>(app: AppHandle<R>) -> Option<Location> {
+    load_location(&app)
+}
+
+#[tauri::command]
+pub fn set_location<R: Runtime>(
+    app: AppHandle<R>,
+    latitude: f64,
+    longitude: f64,
+) -> Result<(), String> {
+    if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+        return Err("Latitude must be within ±90° and longitude within ±180°".to_string());
+    }
+
+    settings::update(&app, |s| {
+        s.location = Some(Location {
+            latitude,
+            longitude,
+        });
+        Ok(())
+    })
+}
+
+#[tauri::command]
+pub fn get_solar_times<R: Runtime>(
+    app: AppHandle<R>,
+    date: Option<NaiveDate>,
+) -> Result<SolarTimes, String> {
+    let location =
+        load_location(&app).ok_or("Location not set. Please set your location first.")?;
+    let date = date.unwrap_or_else(|| Local::now().date_naive());
+
+    Ok(solar_times(location, date))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LONDON: Location = Location {
+        latitude: 51.5074,
+        longitude: -0.1278,
+    };
+    const NEW_YORK: Location = Location {
+        latitude: 40.7128,
+        longitude: -74.006,
+    };
+    const SYDNEY: Location = Location {
+        latitude: -33.8688,
+        longitude: 151.2093,
+    };
+    const TROMSO: Location = Location {
+        latitude: 69.6492,
+        longitude: 18.9553,
+    };
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    /// Within two minutes of the almanac, which rounds to the minute.
+    fn assert_near(actual: Option<DateTime<Utc>>, expected: &str) {
+        let expected: DateTime<Utc> = expected.parse().unwrap();
+        let actual = actual.unwrap_or_else(|| panic!("expected {}, got none", expected));
+
+        assert!(
+            (actual - expected).num_seconds().abs() <= 120,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn london_midsummer_matches_the_almanac() {
+        // 04:43 and 21:21 BST.
+        let times = solar_times(LONDON, date(2024, 6, 20));
+
+        assert_near(times.civil_dawn, "2024-06-20T02:55:00Z");
+        assert_near(times.sunrise, "2024-06-20T03:43:00Z");
+        assert_near(times.sunset, "2024-06-20T20:21:00Z");
+        assert_near(times.civil_dusk, "2024-06-20T21:09:00Z");
+    }
+
+    #[test]
+    fn days_when_the_clocks_change_match_the_almanac() {
+        // New York moves to EDT on 8 March 2026: sunrise goes from 06:20 EST to 07:19 EDT,
+        // though in UTC it keeps creeping earlier.
+        assert_near(
+            event_time(NEW_YORK, date(2026, 3, 7), SolarEvent::Sunrise),
+            "2026-03-07T11:20:00Z",
+        );
+        assert_near(
+            event_time(NEW_YORK, date(2026, 3, 8), SolarEvent::Sunrise),
+            "2026-03-08T11:19:00Z",
+        );
+        assert_near(
+            event_time(NEW_YORK, date(2026, 3, 8), SolarEvent::Sunset),
+            "2026-03-08T22:55:00Z",
+        );
+
+        // Sydney leaves daylight time on 5 April 2026. Its 06:10 AEST sunrise is still on
+        // the 4th in UTC.
+        assert_near(
+            event_time(SYDNEY, date(2026, 4, 5), SolarEvent::Sunrise),
+            "2026-04-04T20:10:00Z",
+        );
+        assert_near(
+            event_time(SYDNEY, date(2026, 4, 5), SolarEvent::Sunset),
+            "2026-04-05T07:45:00Z",
+        );
+    }
+
+    #[test]
+    fn polar_night_and_day_have_no_sunrise_or_sunset() {
+        let midwinter = solar_times(TROMSO, date(2026, 12, 21));
+        assert!(midwinter.sunrise.is_none());
+        assert!(midwinter.sunset.is_none());
+        // The sun stays above civil twilight for a few hours around noon.
+        assert_near(midwinter.civil_dawn, "2026-12-21T08:31:00Z");
+        assert_near(midwinter.civil_dusk, "2026-12-21T12:53:00Z");
+
+        let midsummer = solar_times(TROMSO, date(2026, 6, 21));
+        assert!(midsummer.civil_dawn.is_none());
+        assert!(midsummer.sunrise.is_none());
+        assert!(midsummer.sunset.is_none());
+        assert!(midsummer.civil_dusk.is_none());
+    }
+}
@@ -0,0 +1,148 @@
+//! WebSocket stream of backend events at `/api/v1/stream`, served by the local API.
+//!
+//! Each event is sent as one JSON message. Clients pick what they receive with the
+//! `devices`, `rooms` and `events` query parameters (comma-separated) or by sending
+//! `{"type": "subscribe", "devices": [...], "rooms": [...], "events": [...]}` at any time.
+//! Empty lists mean everything; devices and rooms add up, event types narrow them down.
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Query, State};
+use axum::response::Response;
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Runtime};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::events::{self, Event};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Subscription {
+    #[serde(default)]
+    pub devices: Vec<String>,
+    #[serde(default)]
+    pub rooms: Vec<String>,
+    /// `state`, `sensor`, `online` or `automation`.
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+impl Subscription {
+    fn matches(&self, event: &Event) -> bool {
+        let kind = self.events.is_empty() || self.events.iter().any(|e| e == event.payload.kind());
+
+        let place = (self.devices.is_empty() && self.rooms.is_empty())
+            || event
+                .device
+                .as_ref()
+                .is_some_and(|d| self.devices.contains(d))
+            || event
+                .room
+                .as_ref()
+                .is_some_and(|r| self.rooms.iter().any(|room| room.eq_ignore_ascii_case(r)));
+
+        kind && place
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct StreamQuery {
+    devices: Option<String>,
+    rooms: Option<String>,
+    events: Option<String>,
+}
+
+fn split(list: Option<String>) -> Vec<String> {
+    list.unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+impl From<StreamQuery> for Subscription {
+    fn from(query: StreamQuery) -> Self {
+        Subscription {
+            devices: split(query.devices),
+            rooms: split(query.rooms),
+            events: split(query.events),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Subscribe(Subscription),
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    Subscribed(Subscription),
+    /// Events were dropped because the client read too slowly.
+    Lagged {
+        missed: u64,
+    },
+    Error {
+        error: String,
+    },
+}
+
+async fn send(socket: &mut WebSocket, message: &impl Serialize) -> Result<(), String> {
+    let text = serde_json::to_string(message).map_err(|e| e.to_string())?;
+
+    socket
+        .send(Message::Text(text.into()))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+async fn serve<R: Runtime>(
+    app: AppHandle<R>,
+    mut socket: WebSocket,
+    mut subscription: Subscription,
+) {
+    let mut events = events::subscribe(&app);
+
+    loop {
+        let sent = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) if event.payload.is_change() && subscription.matches(&event) => send(&mut socket, &event).await,
+                Ok(_) => Ok(()),
+                Err(RecvError::Lagged(missed)) => {
+                    send(&mut socket, &ServerMessage::Lagged { missed }).await
+                }
+                Err(RecvError::Closed) => break,
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    let reply = match serde_json::from_str::<ClientMessage>(text.as_str()) {
+                        Ok(ClientMessage::Subscribe(update)) => {
+                            subscription = update;
+                            ServerMessage::Subscribed(subscription.clone())
+                        }
+                        Err(e) => ServerMessage::Error { error: e.to_string() },
+                    };
+                    send(&mut socket, &reply).await
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                // Pings are answered by axum.
+                Some(Ok(_)) => Ok(()),
+            },
+        };
+
+        if sent.is_err() {
+            break;
+        }
+    }
+}
+
+pub async fn stream<R: Runtime>(
+    State(app): State<AppHandle<R>>,
+    Query(query): Query<StreamQuery>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let subscription = Subscription::from(query);
+
+    upgrade.on_upgrade(move |socket| serve(app, socket, subscription))
+}
@@ -0,0 +1,258 @@
+use std::sync::Mutex;
+use std::time::Duration as StdDuration;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Manager, Runtime};
+use tauri_plugin_store::StoreExt;
+use uuid::Uuid;
+
+use crate::command::send_capability;
+use crate::events::{self, AutomationKind};
+use crate::scheduler::DeviceTarget;
+use crate::settings::SETTINGS_FILE;
+use crate::tray;
+
+const TIMERS_KEY: &str = "timers";
+const TICK_INTERVAL: StdDuration = StdDuration::from_secs(5);
+const MAX_MINUTES: i64 = 24 * 60;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SleepTimer {
+    pub id: String,
+    pub label: String,
+    pub targets: Vec<DeviceTarget>,
+    pub ends_at: DateTime<Utc>,
+}
+
+#[derive(Default)]
+pub struct TimerState {
+    timers: Mutex<Vec<SleepTimer>>,
+}
+
+fn load_timers<R: Runtime>(app: &AppHandle<R>) -> Vec<SleepTimer> {
+    app.store(SETTINGS_FILE)
+        .ok()
+        .and_then(|store| store.get(TIMERS_KEY))
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default()
+}
+
+fn save_timers<R: Runtime>(app: &AppHandle<R>, timers: &[SleepTimer]) -> Result<(), String> {
+    let store = app.store(SETTINGS_FILE).map_err(|e| e.to_string())?;
+    let value = serde_json::to_value(timers).map_err(|e| e.to_string())?;
+
+    store.set(TIMERS_KEY, value);
+    store
+        .save()
+        .map_err(|_| "Failed to save settings".to_string())
+}
+
+/// Short remaining-time text such as "25m" or "1h 05m".
+pub fn format_remaining(remaining: Duration) -> String {
+    let minutes = (remaining.num_seconds() + 59) / 60;
+
+    if minutes >= 60 {
+        format!("{}h {:02}m", minutes / 60, minutes % 60)
+    } else if minutes >= 1 {
+        format!("{}m", minutes)
+    } else {
+        "<1m".to_string()
+    }
+}
+
+/// Remaining time of the timer that ends first, for the tray title.
+pub fn tray_title<R: Runtime>(app: &AppHandle<R>) -> Option<String> {
+    let state = app.try_state::<TimerState>()?;
+    let timers = state.timers.lock().unwrap();
+    let soonest = timers.iter().map(|t| t.ends_at).min()?;
+
+    Some(format!("⏾ {}", format_remaining(soonest - Utc::now())))
+}
+
+/// One line per active timer, for the tray tooltip.
+pub fn tray_tooltip<R: Runtime>(app: &AppHandle<R>) -> Vec<String> {
+    let Some(state) = app.try_state::<TimerState>() else {
+        return Vec::new();
+    };
+    let now = Utc::now();
+    let timers = state.timers.lock().unwrap();
+
+    timers
+        .iter()
+        .map(|t| format!("{} off in {}", t.label, format_remaining(t.ends_at - now)))
+        .collect()
+}
+
+async fn turn_off<R: Runtime>(app: &AppHandle<R>, timer: &SleepTimer) {
+    let mut errors = Vec::new();
+
+    for target in &timer.targets {
+        if let Err(e) = send_capability(
+            app,
+            target.device.clone(),
+            target.sku.clone(),
+            "devices.capabilities.on_off".to_string(),
+            "powerSwitch".to_string(),
+            serde_json::json!(0),
+        )
+        .await
+        {
+            eprintln!(
+                "Sleep timer \"{}\" failed for {}: {}",
+                timer.label, target.device, e
+            );
+            errors.push(format!("{}: {}", target.device, e));
+        }
+    }
+
+    let device = match timer.targets.as_slice() {
+        [only] => Some(only.device.as_str()),
+        _ => None,
+    };
+    let error = (!errors.is_empty()).then(|| errors.join("; "));
+    events::publish_automation(app, AutomationKind::Timer, &timer.label, device, error);
+}
+
+async fn tick<R: Runtime>(app: &AppHandle<R>) -> Result<(), String> {
+    let now = Utc::now();
+
+    let expired: Vec<SleepTimer> = {
+        let state = app.state::<TimerState>();
+        let mut timers = state.timers.lock().unwrap();
+        let (expired, remaining): (Vec<_>, Vec<_>) =
+            timers.drain(..).partition(|t| t.ends_at <= now);
+        *timers = remaining;
+
+        // The devices are switched off even if saving fails; a timer left in the file
+        // fires again on the next start, which is harmless.
+        if !expired.is_empty() {
+            if let Err(e) = save_timers(app, &timers) {
+                eprintln!("Failed to save sleep timers: {}", e);
+            }
+        }
+        expired
+    };
+
+    for timer in &expired {
+        turn_off(app, timer).await;
+    }
+
+    tray::refresh_title(app).map_err(|e| e.to_string())
+}
+
+/// Restores persisted timers and starts counting them down. Timers that ran out while the
+/// app was closed fire on the first tick.
+pub fn start<R: Runtime>(app_handle: &AppHandle<R>) {
+    app_handle.manage(TimerState {
+        timers: Mutex::new(load_timers(app_handle)),
+    });
+
+    let app = app_handle.clone();
+
+    tauri::async_runtime::spawn(async move {
+        loop {
+            if let Err(e) = tick(&app).await {
+                eprintln!("Sleep timer tick failed: {}", e);
+            }
+
+            tokio::time::sleep(TICK_INTERVAL).await;
+        }
+    });
+}
+
+fn validate_minutes(minutes: i64) -> Result<Duration, String> {
+    if !(1..=MAX_MINUTES).contains(&minutes) {
+        return Err(format!(
+            "Timer must be between 1 and {} minutes",
+            MAX_MINUTES
+        ));
+    }
+
+    Ok(Duration::minutes(minutes))
+}
+
+#[tauri::command]
+pub fn get_timers<R: Runtime>(app: AppHandle<R>) -> Vec<SleepTimer> {
+    let state = app.state::<TimerState>();
+    let mut timers = state.timers.lock().unwrap().clone();
+    timers.sort_by_key(|t| t.ends_at);
+    timers
+}
+
+#[tauri::command]
+pub fn start_timer<R: Runtime>(
+    app: AppHandle<R>,
+    label: String,
+    targets: Vec<DeviceTarget>,
+    minutes: i64,
+) -> Result<SleepTimer, String> {
+    if targets.is_empty() {
+        return Err("Timer needs at least one device".to_string());
+    }
+
+    let timer = SleepTimer {
+        id: Uuid::new_v4().to_string(),
+        label,
+        targets,
+        ends_at: Utc::now() + validate_minutes(minutes)?,
+    };
+
+    {
+        let state = app.state::<TimerState>();
+        let mut timers = state.timers.lock().unwrap();
+        timers.push(timer.clone());
+        save_timers(&app, &timers)?;
+    }
+
+    tray::refresh_title(&app).map_err(|e| e.to_string())?;
+
+    Ok(timer)
+}
+
+#[tauri::command]
+pub fn extend_timer<R: Runtime>(
+    app: AppHandle<R>,
+    id: String,
+    minutes: i64,
+) -> Result<SleepTimer, String> {
+    let extra = validate_minutes(minutes)?;
+
+    let timer = {
+        let state = app.state::<TimerState>();
+        let mut timers = state.timers.lock().unwrap();
+        let timer = timers
+            .iter_mut()
+            .find(|t| t.id == id)
+            .ok_or("Timer not found")?;
+
+        timer.ends_at += extra;
+        let timer = timer.clone();
+
+        save_timers(&app, &timers)?;
+        timer
+    };
+
+    tray::refresh_title(&app).map_err(|e| e.to_string())?;
+
+    Ok(timer)
+}
+
+#[tauri::command]
+pub fn cancel_timer<R: Runtime>(app: AppHandle<R>, id: String) -> Result<(), String> {
+    {
+        let state = app.state::<TimerState>();
+        let mut timers = state.timers.lock().unwrap();
+        let count = timers.len();
+
+        timers.retain(|t| t.id != id);
+
+        if timers.len() == count {
+            return Err("Timer not found".to_string());
+        }
+
+        save_timers(&app, &timers)?;
+    }
+
+    tray::refresh_title(&app).map_err(|e| e.to_string())
+}
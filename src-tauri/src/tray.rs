@@ -1,35 +1,465 @@
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
 use tauri::{
     image::Image,
+    menu::{CheckMenuItem, Menu, MenuItem, PredefinedMenuItem, Submenu},
     tray::{MouseButtonState, TrayIcon, TrayIconBuilder, TrayIconEvent},
-    AppHandle,
+    AppHandle, Emitter, Runtime,
 };
-use tauri_nspanel::ManagerExt;
 
-use crate::fns::position_menubar_panel;
+use crate::alarm;
+use crate::cache;
+use crate::command::{change_capability_value, get_device_state, get_devices, get_light_scenes};
+use crate::health::{self, ApiHealth};
+use crate::icon::{self, Badge, IconState};
+use crate::panel;
+use crate::presence;
+use crate::presets;
+use crate::sensors;
+use crate::settings;
+use crate::timer;
+
+const TRAY_ICON: &[u8] = include_bytes!("../icons/tray.png");
+
+const CANCEL_ALARM_ID: &str = "cancel_alarm";
+const OPEN_ID: &str = "open";
+const REFRESH_ID: &str = "refresh";
+const SETTINGS_ID: &str = "settings";
+const QUIT_ID: &str = "quit";
+
+// Prefixes for per-device and per-preset entries. Scene ids are `scene:<device>/<value>`,
+// split at the first slash since Govee device ids contain colons but no slashes.
+const POWER_PREFIX: &str = "power:";
+const SCENE_PREFIX: &str = "scene:";
+const PRESET_PREFIX: &str = "preset:";
 
 pub fn create(app_handle: &AppHandle) -> tauri::Result<TrayIcon> {
-    let icon = Image::from_bytes(include_bytes!("../icons/tray.png"))?;
+    let icon = Image::from_bytes(TRAY_ICON)?;
 
     TrayIconBuilder::with_id("tray")
         .icon(icon)
         .icon_as_template(true)
+        .menu(&build_menu(app_handle)?)
+        .show_menu_on_left_click(false)
+        .on_menu_event(|app_handle, event| {
+            handle_menu_event(app_handle, event.id.as_ref());
+        })
         .on_tray_icon_event(|tray, event| {
             let app_handle = tray.app_handle();
 
-            if let TrayIconEvent::Click { button_state, .. } = event {
+            if let TrayIconEvent::Click {
+                button_state,
+                position,
+                ..
+            } = event
+            {
                 if button_state == MouseButtonState::Up {
-                    let panel = app_handle.get_webview_panel("main").unwrap();
+                    panel::toggle(app_handle, Some(position));
+                }
+            }
+        })
+        .build(app_handle)
+}
 
-                    if panel.is_visible() {
-                        panel.order_out(None);
-                        return;
-                    }
+pub fn load_pinned_devices<R: Runtime>(app_handle: &AppHandle<R>) -> Vec<String> {
+    settings::get(app_handle).pinned_devices
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrayIconMode {
+    /// The plain template icon.
+    #[default]
+    Static,
+    /// A badge with the number of lights switched on.
+    LightsOn,
+    /// A swatch of the first pinned light's current colour.
+    Color,
+}
+
+fn load_icon_mode<R: Runtime>(app_handle: &AppHandle<R>) -> TrayIconMode {
+    settings::get(app_handle).tray_icon_mode
+}
+
+fn icon_state<R: Runtime>(app_handle: &AppHandle<R>) -> IconState {
+    let badge = match load_icon_mode(app_handle) {
+        TrayIconMode::Static => Badge::None,
+        TrayIconMode::LightsOn => Badge::Count(cache::lights_on(app_handle)),
+        TrayIconMode::Color => match load_pinned_devices(app_handle).first() {
+            Some(device) => Badge::Swatch(
+                cache::power(app_handle, device)
+                    .unwrap_or(false)
+                    .then(|| cache::color(app_handle, device))
+                    .flatten(),
+            ),
+            None => Badge::None,
+        },
+    };
+
+    IconState {
+        badge,
+        warning: matches!(
+            health::current(),
+            ApiHealth::InvalidKey | ApiHealth::Unreachable
+        ),
+    }
+}
+
+/// Redraws the tray icon from the cached device state and API health. Does nothing when
+/// the icon would look the same as last time.
+pub fn refresh_icon<R: Runtime>(app_handle: &AppHandle<R>) -> tauri::Result<()> {
+    static LAST: Mutex<Option<IconState>> = Mutex::new(None);
+
+    let Some(tray) = app_handle.tray_by_id("tray") else {
+        return Ok(());
+    };
+
+    let state = icon_state(app_handle);
+    {
+        let mut last = LAST.lock().unwrap();
+        if *last == Some(state) {
+            return Ok(());
+        }
+        *last = Some(state);
+    }
+
+    let template = Image::from_bytes(TRAY_ICON)?;
+    let (rgba, size) = icon::render(template.rgba(), template.width(), template.height(), state);
+
+    tray.set_icon(Some(Image::new_owned(rgba, size, size)))?;
+    tray.set_icon_as_template(state.is_template())
+}
+
+fn build_menu<R: Runtime>(app_handle: &AppHandle<R>) -> tauri::Result<Menu<R>> {
+    let menu = Menu::new(app_handle)?;
+
+    // Tray icon clicks aren't reported on Linux, so the panel opens from the menu instead.
+    if cfg!(target_os = "linux") {
+        menu.append(&MenuItem::with_id(
+            app_handle,
+            OPEN_ID,
+            "Open Govee Statusbar",
+            true,
+            None::<&str>,
+        )?)?;
+        menu.append(&PredefinedMenuItem::separator(app_handle)?)?;
+    }
+
+    let devices = cache::devices(app_handle);
+    let pinned: Vec<_> = load_pinned_devices(app_handle)
+        .iter()
+        .filter_map(|id| devices.iter().find(|d| &d.device == id))
+        .collect();
+
+    for device in &pinned {
+        let power = cache::power(app_handle, &device.device);
+        let offline = presence::is_offline(app_handle, &device.device);
+        let label = if offline {
+            format!("{} (offline)", device.display_name())
+        } else {
+            device.display_name().to_string()
+        };
+
+        menu.append(&CheckMenuItem::with_id(
+            app_handle,
+            format!("{}{}", POWER_PREFIX, device.device),
+            label,
+            power.is_some() && !offline,
+            power.unwrap_or(false),
+            None::<&str>,
+        )?)?;
+    }
+
+    if !pinned.is_empty() {
+        menu.append(&PredefinedMenuItem::separator(app_handle)?)?;
+    }
+
+    let scenes_menu = Submenu::new(app_handle, "Scenes", true)?;
+    let mut has_scenes = false;
+
+    for device in &pinned {
+        let scenes = cache::scenes(app_handle, &device.device);
+        if scenes.is_empty() {
+            continue;
+        }
+
+        let device_menu = Submenu::new(app_handle, device.display_name(), true)?;
+        // The scene's value goes in the id, so a click applies the scene that was shown
+        // even if the cached list changed since.
+        for scene in &scenes {
+            device_menu.append(&MenuItem::with_id(
+                app_handle,
+                format!("{}{}/{}", SCENE_PREFIX, device.device, scene.value),
+                &scene.name,
+                true,
+                None::<&str>,
+            )?)?;
+        }
+
+        scenes_menu.append(&device_menu)?;
+        has_scenes = true;
+    }
+
+    if has_scenes {
+        menu.append(&scenes_menu)?;
+    }
+
+    let presets = presets::load_presets(app_handle);
+    if !presets.is_empty() {
+        let presets_menu = Submenu::new(app_handle, "Presets", true)?;
+
+        for preset in &presets {
+            presets_menu.append(&MenuItem::with_id(
+                app_handle,
+                format!("{}{}", PRESET_PREFIX, preset.id),
+                &preset.name,
+                true,
+                None::<&str>,
+            )?)?;
+        }
+
+        menu.append(&presets_menu)?;
+    }
+
+    if has_scenes || !presets.is_empty() {
+        menu.append(&PredefinedMenuItem::separator(app_handle)?)?;
+    }
+
+    if alarm::is_ramping(app_handle) {
+        menu.append(&MenuItem::with_id(
+            app_handle,
+            CANCEL_ALARM_ID,
+            "Cancel Wake-Up Light",
+            true,
+            None::<&str>,
+        )?)?;
+        menu.append(&PredefinedMenuItem::separator(app_handle)?)?;
+    }
 
-                    position_menubar_panel(app_handle, 0.0);
+    menu.append(&MenuItem::with_id(
+        app_handle,
+        REFRESH_ID,
+        "Refresh",
+        true,
+        None::<&str>,
+    )?)?;
+    menu.append(&MenuItem::with_id(
+        app_handle,
+        SETTINGS_ID,
+        "Settings…",
+        true,
+        None::<&str>,
+    )?)?;
+    menu.append(&PredefinedMenuItem::separator(app_handle)?)?;
+    menu.append(&MenuItem::with_id(
+        app_handle,
+        QUIT_ID,
+        "Quit",
+        true,
+        Some("CmdOrCtrl+Q"),
+    )?)?;
 
-                    panel.show();
+    Ok(menu)
+}
+
+/// Rebuilds the right-click menu from the cached devices, pinned devices and presets.
+pub fn refresh_menu<R: Runtime>(app_handle: &AppHandle<R>) -> tauri::Result<()> {
+    let Some(tray) = app_handle.tray_by_id("tray") else {
+        return Ok(());
+    };
+
+    tray.set_menu(Some(build_menu(app_handle)?))
+}
+
+fn handle_menu_event(app_handle: &AppHandle, id: &str) {
+    match id {
+        QUIT_ID => app_handle.exit(0),
+        OPEN_ID => panel::show(app_handle, None),
+        CANCEL_ALARM_ID => alarm::cancel_active(app_handle),
+        SETTINGS_ID => {
+            panel::show(app_handle, None);
+            let _ = app_handle.emit("open_settings", ());
+        }
+        REFRESH_ID => {
+            let app = app_handle.clone();
+            tauri::async_runtime::spawn(async move {
+                if let Err(e) = refresh(&app).await {
+                    eprintln!("Refresh failed: {}", e);
                 }
+            });
+        }
+        _ => {
+            if let Some(device) = id.strip_prefix(POWER_PREFIX) {
+                toggle_power(app_handle, device.to_string());
+            } else if let Some((device, value)) = id
+                .strip_prefix(SCENE_PREFIX)
+                .and_then(|rest| rest.split_once('/'))
+            {
+                if let Ok(value) = serde_json::from_str(value) {
+                    apply_scene(app_handle, device.to_string(), value);
+                }
+            } else if let Some(preset) = id.strip_prefix(PRESET_PREFIX) {
+                let app = app_handle.clone();
+                let preset = preset.to_string();
+                tauri::async_runtime::spawn(async move {
+                    if let Err(e) = presets::apply(&app, &preset).await {
+                        eprintln!("Failed to apply preset: {}", e);
+                    }
+                });
             }
-        })
-        .build(app_handle)
+        }
+    }
+}
+
+fn toggle_power<R: Runtime>(app_handle: &AppHandle<R>, device: String) {
+    let Some(sku) = cache::devices(app_handle)
+        .into_iter()
+        .find(|d| d.device == device)
+        .map(|d| d.sku)
+    else {
+        return;
+    };
+    let turn_on = !cache::power(app_handle, &device).unwrap_or(false);
+
+    let app = app_handle.clone();
+    tauri::async_runtime::spawn(async move {
+        let result = change_capability_value(
+            app.clone(),
+            device,
+            sku,
+            "devices.capabilities.on_off".to_string(),
+            "powerSwitch".to_string(),
+            serde_json::json!(if turn_on { 1 } else { 0 }),
+        )
+        .await;
+
+        if let Err(e) = result {
+            eprintln!("Failed to toggle power: {}", e);
+            // The check item toggled itself on click, so put it back.
+            let _ = refresh_menu(&app);
+        }
+
+        let _ = app.emit("devices_refreshed", ());
+    });
+}
+
+fn apply_scene<R: Runtime>(app_handle: &AppHandle<R>, device: String, value: serde_json::Value) {
+    let Some(sku) = cache::devices(app_handle)
+        .into_iter()
+        .find(|d| d.device == device)
+        .map(|d| d.sku)
+    else {
+        return;
+    };
+
+    let app = app_handle.clone();
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = change_capability_value(
+            app.clone(),
+            device,
+            sku,
+            "devices.capabilities.dynamic_scene".to_string(),
+            "lightScene".to_string(),
+            value,
+        )
+        .await
+        {
+            eprintln!("Failed to apply scene: {}", e);
+        }
+    });
+}
+
+/// Reloads the device list and the state and scenes of pinned devices, then tells the
+/// panel to reload too.
+async fn refresh<R: Runtime>(app_handle: &AppHandle<R>) -> Result<(), String> {
+    let devices = get_devices(app_handle.clone()).await?;
+
+    for id in load_pinned_devices(app_handle) {
+        let Some(device) = devices.iter().find(|d| d.device == id) else {
+            continue;
+        };
+
+        // One unreachable device shouldn't keep the rest of the menu from updating.
+        if let Err(e) = get_device_state(
+            app_handle.clone(),
+            device.device.clone(),
+            device.sku.clone(),
+        )
+        .await
+        {
+            eprintln!("Failed to refresh {}: {}", device.display_name(), e);
+            continue;
+        }
+
+        if cache::scenes(app_handle, &id).is_empty() {
+            // Not every device supports scenes.
+            let _ = get_light_scenes(
+                app_handle.clone(),
+                device.device.clone(),
+                device.sku.clone(),
+            )
+            .await;
+        }
+    }
+
+    refresh_menu(app_handle).map_err(|e| e.to_string())?;
+    refresh_icon(app_handle).map_err(|e| e.to_string())?;
+
+    let _ = app_handle.emit("devices_refreshed", ());
+
+    Ok(())
+}
+
+/// Shows the sleep timer that ends first and the chosen sensor readings next to the icon,
+/// and lists every active timer and sensor in the tooltip.
+pub fn refresh_title<R: Runtime>(app_handle: &AppHandle<R>) -> tauri::Result<()> {
+    let Some(tray) = app_handle.tray_by_id("tray") else {
+        return Ok(());
+    };
+
+    let title: Vec<String> = [
+        timer::tray_title(app_handle),
+        sensors::tray_title(app_handle),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    tray.set_title((!title.is_empty()).then(|| title.join("  ")))?;
+
+    let mut lines = timer::tray_tooltip(app_handle);
+    lines.extend(sensors::tray_tooltip(app_handle));
+    tray.set_tooltip((!lines.is_empty()).then(|| lines.join("\n")))
+}
+
+#[tauri::command]
+pub fn get_pinned_devices<R: Runtime>(app: AppHandle<R>) -> Vec<String> {
+    load_pinned_devices(&app)
+}
+
+#[tauri::command]
+pub async fn set_pinned_devices<R: Runtime>(
+    app: AppHandle<R>,
+    devices: Vec<String>,
+) -> Result<(), String> {
+    settings::update(&app, |s| {
+        s.pinned_devices = devices;
+        Ok(())
+    })?;
+
+    refresh(&app).await
+}
+
+#[tauri::command]
+pub fn get_tray_icon_mode<R: Runtime>(app: AppHandle<R>) -> TrayIconMode {
+    load_icon_mode(&app)
+}
+
+#[tauri::command]
+pub fn set_tray_icon_mode<R: Runtime>(app: AppHandle<R>, mode: TrayIconMode) -> Result<(), String> {
+    settings::update(&app, |s| {
+        s.tray_icon_mode = mode;
+        Ok(())
+    })?;
+
+    refresh_icon(&app).map_err(|e| e.to_string())
 }
@@ -0,0 +1,481 @@
+//! Incoming webhooks, received by the local API at `POST /hooks/<name>`. Each webhook has
+//! routes that test fields of the JSON payload and run a preset, scene or capability
+//! change when all their conditions hold. Deliveries must be signed with the webhook's
+//! secret: an HMAC-SHA256 of the body, hex-encoded in `X-Signature-256` (or GitHub's
+//! `X-Hub-Signature-256`), optionally prefixed with `sha256=`.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use axum::body::Bytes;
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+use tauri::{AppHandle, Runtime};
+use tauri_plugin_store::StoreExt;
+use uuid::Uuid;
+
+use crate::command::find_scene;
+use crate::events::{self, AutomationKind};
+use crate::presets;
+use crate::scheduler::{apply_changes, CapabilityChange};
+use crate::secrets;
+use crate::settings::{self, SETTINGS_FILE};
+
+const DELIVERY_LOG_KEY: &str = "webhook_log";
+const MAX_LOG_ENTRIES: usize = 200;
+const SIGNATURE_HEADERS: [&str; 2] = ["x-signature-256", "x-hub-signature-256"];
+const REJECTION_LOG_INTERVAL_SECS: i64 = 60;
+
+/// Serializes writes to the delivery log, since deliveries finish concurrently.
+static LOG_LOCK: Mutex<()> = Mutex::new(());
+/// When each webhook last had a rejected delivery logged, by webhook id.
+static LAST_REJECTION: Mutex<BTreeMap<String, DateTime<Utc>>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Test {
+    /// Numbers and booleans also equal their text, so `500` matches `"500"`.
+    Equals {
+        value: Value,
+    },
+    NotEquals {
+        value: Value,
+    },
+    /// A string containing `value`, or an array with an element equal to it.
+    Contains {
+        value: Value,
+    },
+    Exists,
+    Above {
+        value: f64,
+    },
+    Below {
+        value: f64,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Condition {
+    /// Field names separated by dots, with `[n]` for array elements, e.g.
+    /// `alerts[0].labels.severity`.
+    pub path: String,
+    #[serde(flatten)]
+    pub test: Test,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum WebhookAction {
+    /// A preset, by id or name.
+    Preset {
+        preset: String,
+    },
+    /// A light or DIY scene, by name.
+    Scene {
+        device: String,
+        sku: String,
+        scene: String,
+    },
+    Capability(CapabilityChange),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookRoute {
+    pub name: String,
+    /// All of them must hold. A route without conditions runs on every delivery.
+    #[serde(default)]
+    pub conditions: Vec<Condition>,
+    pub action: WebhookAction,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Webhook {
+    #[serde(default)]
+    pub id: String,
+    /// The last part of the URL, `/hooks/<name>`.
+    pub name: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Kept in the keyring, not in the settings file.
+    #[serde(default, skip_serializing)]
+    pub secret: String,
+    /// Every route that matches runs, in order.
+    pub routes: Vec<WebhookRoute>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Delivery {
+    pub webhook: String,
+    pub at: DateTime<Utc>,
+    /// The HTTP status the sender got back.
+    pub status: u16,
+    /// Routes whose conditions matched.
+    pub routes: Vec<String>,
+    /// Why the delivery was rejected or which actions failed.
+    pub error: Option<String>,
+}
+
+pub fn secret_account(id: &str) -> String {
+    format!("webhook-{}", id)
+}
+
+/// Looks up `path` in `payload`. An empty path or `$` is the whole payload.
+pub fn lookup<'a>(payload: &'a Value, path: &str) -> Option<&'a Value> {
+    let path = path.strip_prefix('$').unwrap_or(path).replace('[', ".");
+
+    path.split('.')
+        .map(|segment| segment.trim_end_matches(']'))
+        .filter(|segment| !segment.is_empty())
+        .try_fold(payload, |value, segment| match value {
+            Value::Object(fields) => fields.get(segment),
+            Value::Array(items) => items.get(segment.parse::<usize>().ok()?),
+            _ => None,
+        })
+}
+
+/// Strings without their quotes, anything else as JSON.
+pub fn text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::String(s) => s.trim().parse().ok(),
+        other => other.as_f64(),
+    }
+}
+
+impl Condition {
+    fn holds(&self, payload: &Value) -> bool {
+        let found = lookup(payload, &self.path);
+
+        match (&self.test, found) {
+            (Test::Exists, found) => found.is_some(),
+            (Test::NotEquals { value }, found) => found.is_none_or(|f| text(f) != text(value)),
+            (_, None) => false,
+            (Test::Equals { value }, Some(found)) => text(found) == text(value),
+            (Test::Contains { value }, Some(Value::Array(items))) => items.contains(value),
+            (Test::Contains { value }, Some(found)) => text(found).contains(&text(value)),
+            (Test::Above { value }, Some(found)) => number(found).is_some_and(|n| n > *value),
+            (Test::Below { value }, Some(found)) => number(found).is_some_and(|n| n < *value),
+        }
+    }
+}
+
+impl WebhookRoute {
+    fn matches(&self, payload: &Value) -> bool {
+        self.conditions.iter().all(|c| c.holds(payload))
+    }
+}
+
+fn verify(secret: &str, headers: &HeaderMap, body: &[u8]) -> bool {
+    let Some(signature) = SIGNATURE_HEADERS
+        .iter()
+        .find_map(|name| headers.get(*name))
+        .and_then(|v| v.to_str().ok())
+    else {
+        return false;
+    };
+    let signature = signature.trim();
+    let Ok(signature) = hex::decode(signature.strip_prefix("sha256=").unwrap_or(signature)) else {
+        return false;
+    };
+    let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(secret.as_bytes()) else {
+        return false;
+    };
+
+    mac.update(body);
+    // Compares in constant time.
+    mac.verify_slice(&signature).is_ok()
+}
+
+async fn changes<R: Runtime>(
+    app: &AppHandle<R>,
+    action: &WebhookAction,
+) -> Result<Vec<CapabilityChange>, String> {
+    match action {
+        WebhookAction::Preset { preset } => presets::load_presets(app)
+            .into_iter()
+            .find(|p| p.id == *preset || p.name.eq_ignore_ascii_case(preset))
+            .map(|p| p.changes)
+            .ok_or_else(|| format!("No preset named {}", preset)),
+        WebhookAction::Scene { device, sku, scene } => {
+            let (instance, value) = find_scene(app, device, sku, scene)
+                .await
+                .ok_or_else(|| format!("{} has no scene named {}", device, scene))?;
+
+            Ok(vec![CapabilityChange {
+                device: device.clone(),
+                sku: sku.clone(),
+                capability_type: "devices.capabilities.dynamic_scene".to_string(),
+                instance: instance.to_string(),
+                value,
+            }])
+        }
+        WebhookAction::Capability(change) => Ok(vec![change.clone()]),
+    }
+}
+
+/// Runs the routes one after another. Returns the errors of the ones that failed.
+async fn run<R: Runtime>(app: &AppHandle<R>, routes: &[WebhookRoute]) -> Option<String> {
+    let mut errors = Vec::new();
+
+    for route in routes {
+        let result = match changes(app, &route.action).await {
+            Ok(changes) => apply_changes(app, changes).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = &result {
+            errors.push(format!("{}: {}", route.name, e));
+        }
+        events::publish_automation(
+            app,
+            AutomationKind::Webhook,
+            &route.name,
+            None,
+            result.err(),
+        );
+    }
+
+    (!errors.is_empty()).then(|| errors.join("; "))
+}
+
+pub fn load_webhooks<R: Runtime>(app: &AppHandle<R>) -> Vec<Webhook> {
+    settings::get(app).webhooks
+}
+
+fn save_webhooks<R: Runtime>(app: &AppHandle<R>, webhooks: Vec<Webhook>) -> Result<(), String> {
+    settings::update(app, |s| {
+        s.webhooks = webhooks;
+        Ok(())
+    })
+}
+
+fn load_log<R: Runtime>(app: &AppHandle<R>) -> Vec<Delivery> {
+    app.store(SETTINGS_FILE)
+        .ok()
+        .and_then(|store| store.get(DELIVERY_LOG_KEY))
+        .and_then(|v| serde_json::from_value(v).ok())
+        .unwrap_or_default()
+}
+
+fn save_log<R: Runtime>(app: &AppHandle<R>, log: &[Delivery]) -> Result<(), String> {
+    let store = app.store(SETTINGS_FILE).map_err(|e| e.to_string())?;
+    let value = serde_json::to_value(log).map_err(|e| e.to_string())?;
+
+    store.set(DELIVERY_LOG_KEY, value);
+    store
+        .save()
+        .map_err(|_| "Failed to save settings".to_string())
+}
+
+fn record<R: Runtime>(app: &AppHandle<R>, delivery: Delivery) {
+    let _lock = LOG_LOCK.lock().unwrap();
+    let mut log = load_log(app);
+
+    log.push(delivery);
+    let excess = log.len().saturating_sub(MAX_LOG_ENTRIES);
+    log.drain(..excess);
+
+    if let Err(e) = save_log(app, &log) {
+        eprintln!("Failed to save webhook log: {}", e);
+    }
+}
+
+/// Logs at most one rejected delivery per webhook a minute, so anyone who finds the URL
+/// can't flood the log and wear out the settings file. The rest only go to stderr.
+fn reject<R: Runtime>(
+    app: &AppHandle<R>,
+    webhook: &Webhook,
+    status: StatusCode,
+    error: &str,
+) -> Response {
+    let now = Utc::now();
+    let due = {
+        let mut last = LAST_REJECTION.lock().unwrap();
+        let due = last
+            .get(&webhook.id)
+            .is_none_or(|at| (now - *at).num_seconds() >= REJECTION_LOG_INTERVAL_SECS);
+        if due {
+            last.insert(webhook.id.clone(), now);
+        }
+        due
+    };
+
+    if due {
+        record(
+            app,
+            Delivery {
+                webhook: webhook.name.clone(),
+                at: now,
+                status: status.as_u16(),
+                routes: Vec::new(),
+                error: Some(error.to_string()),
+            },
+        );
+    } else {
+        eprintln!("Rejected delivery to webhook {}: {}", webhook.name, error);
+    }
+
+    (status, Json(serde_json::json!({ "error": error }))).into_response()
+}
+
+/// Answers once the delivery is verified and matched; the actions run afterwards and their
+/// outcome goes to the delivery log.
+pub async fn receive<R: Runtime>(
+    State(app): State<AppHandle<R>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    // Requests for unknown webhooks aren't logged, so they can't flood the log.
+    let Some(webhook) = load_webhooks(&app)
+        .into_iter()
+        .find(|w| w.enabled && w.name == name)
+    else {
+        let error = serde_json::json!({ "error": "Webhook not found" });
+        return (StatusCode::NOT_FOUND, Json(error)).into_response();
+    };
+
+    if webhook.secret.is_empty() || !verify(&webhook.secret, &headers, &body) {
+        return reject(
+            &app,
+            &webhook,
+            StatusCode::UNAUTHORIZED,
+            "Missing or invalid signature",
+        );
+    }
+
+    let payload: Value = match serde_json::from_slice(&body) {
+        Ok(payload) => payload,
+        Err(e) => {
+            let error = format!("Invalid JSON: {}", e);
+            return reject(&app, &webhook, StatusCode::BAD_REQUEST, &error);
+        }
+    };
+
+    let routes: Vec<WebhookRoute> = webhook
+        .routes
+        .into_iter()
+        .filter(|r| r.matches(&payload))
+        .collect();
+    let names: Vec<String> = routes.iter().map(|r| r.name.clone()).collect();
+
+    let status = StatusCode::ACCEPTED;
+    let delivery = Delivery {
+        webhook: webhook.name,
+        at: Utc::now(),
+        status: status.as_u16(),
+        routes: names.clone(),
+        error: None,
+    };
+    tauri::async_runtime::spawn(async move {
+        let error = run(&app, &routes).await;
+        record(&app, Delivery { error, ..delivery });
+    });
+
+    (status, Json(serde_json::json!({ "routes": names }))).into_response()
+}
+
+#[tauri::command]
+pub fn get_webhooks<R: Runtime>(app: AppHandle<R>) -> Vec<Webhook> {
+    load_webhooks(&app)
+}
+
+/// Saves a webhook. `secret` replaces the stored secret and `None` keeps it; a new webhook
+/// needs one.
+#[tauri::command]
+pub fn save_webhook<R: Runtime>(
+    app: AppHandle<R>,
+    mut webhook: Webhook,
+    secret: Option<String>,
+) -> Result<Webhook, String> {
+    let valid_name = webhook
+        .name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if webhook.name.is_empty() || !valid_name {
+        return Err("Webhook names may only use letters, digits, - and _".to_string());
+    }
+    if webhook.routes.iter().any(|r| r.name.trim().is_empty()) {
+        return Err("Route name cannot be empty".to_string());
+    }
+
+    let mut webhooks = load_webhooks(&app);
+
+    if webhook.id.is_empty() {
+        webhook.id = Uuid::new_v4().to_string();
+    }
+    if webhooks
+        .iter()
+        .any(|w| w.id != webhook.id && w.name == webhook.name)
+    {
+        return Err(format!("A webhook named {} already exists", webhook.name));
+    }
+
+    webhook.secret = match secret.filter(|s| !s.is_empty()) {
+        Some(secret) => {
+            secrets::set(&app, &secret_account(&webhook.id), &secret)?;
+            secret
+        }
+        None => webhooks
+            .iter()
+            .find(|w| w.id == webhook.id)
+            .map(|w| w.secret.clone())
+            .ok_or("Webhook needs a secret")?,
+    };
+
+    match webhooks.iter_mut().find(|w| w.id == webhook.id) {
+        Some(existing) => *existing = webhook.clone(),
+        None => webhooks.push(webhook.clone()),
+    }
+
+    save_webhooks(&app, webhooks)?;
+
+    Ok(webhook)
+}
+
+#[tauri::command]
+pub fn delete_webhook<R: Runtime>(app: AppHandle<R>, id: String) -> Result<(), String> {
+    let mut webhooks = load_webhooks(&app);
+    let count = webhooks.len();
+
+    webhooks.retain(|w| w.id != id);
+
+    if webhooks.len() == count {
+        return Err("Webhook not found".to_string());
+    }
+
+    save_webhooks(&app, webhooks)?;
+
+    secrets::delete(&app, &secret_account(&id))
+}
+
+/// Most recent deliveries first.
+#[tauri::command]
+pub fn get_webhook_log<R: Runtime>(app: AppHandle<R>) -> Vec<Delivery> {
+    let mut log = load_log(&app);
+    log.reverse();
+    log
+}
+
+#[tauri::command]
+pub fn clear_webhook_log<R: Runtime>(app: AppHandle<R>) -> Result<(), String> {
+    let _lock = LOG_LOCK.lock().unwrap();
+
+    save_log(&app, &[])
+}
@@ -3,6 +3,11 @@ export type Device = {
   deviceName?: string;
   sku: string;
   type: string;
+  online?: boolean | null;
+  lastSeen?: string | null;
+  profile?: string;
+  profileName?: string;
+  room?: string | null;
 };
 
 export type DeviceCapabilityType =
@@ -70,6 +75,7 @@ export type DeviceState = {
     instance: DeviceCapabilityInstance;
     state: { value: DeviceCapabilityValue };
   }>;
+  online?: boolean;
 };
 
 export type SceneOption = {
@@ -78,4 +84,8 @@ export type SceneOption = {
     id: number;
     paramId: number,
   }
-};
\ No newline at end of file
+};
+export type SettingsIssue = {
+  key: string;
+  message: string;
+};